@@ -1,9 +1,4 @@
-drop table if exists posts cascade;
-drop table if exists user_channel cascade ;
-drop table if exists users cascade ;
-drop table if exists channels cascade;
-
-CREATE TABLE posts (
+CREATE TABLE IF NOT EXISTS posts (
                        id serial primary key,
                        title text,
                        link text not null,
@@ -13,19 +8,19 @@ CREATE TABLE posts (
                        chat_id bigint not null
 );
 
-create table channels (
+create table if not exists channels (
                           id bigint not null primary key,
                           title text not null,
                           username text not null unique
 );
 
-create table users (
+create table if not exists users (
     id bigint not null primary key,
     enabled bool not null default true,
     chat_id bigint not null
 );
 
-create table user_channel (
+create table if not exists user_channel (
     id serial primary key,
     user_id bigint not null references users(id),
     channel_id bigint not null references channels(id),
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS banned bool NOT NULL DEFAULT false;
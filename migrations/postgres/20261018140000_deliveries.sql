@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS deliveries (
+    id bigserial primary key,
+    post_id integer not null references posts(id),
+    user_id bigint not null references users(id),
+    chat_id bigint not null,
+    attempts integer not null default 0,
+    next_attempt_at bigint not null default 0,
+    last_error text
+);
+
+CREATE INDEX IF NOT EXISTS deliveries_chat_id ON deliveries (chat_id, id);
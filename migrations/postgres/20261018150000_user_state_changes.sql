@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS user_state_changes (
+    id bigserial primary key,
+    user_id bigint not null references users(id),
+    enabled bool not null,
+    reason text not null,
+    changed_at bigint not null default extract(epoch FROM now())::bigint
+);
+
+CREATE INDEX IF NOT EXISTS user_state_changes_user_id ON user_state_changes (user_id, id);
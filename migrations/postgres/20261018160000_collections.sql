@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS collections (
+    id bigserial primary key,
+    user_id bigint not null references users(id),
+    name text not null,
+    token text not null unique,
+    unique (user_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS collection_channel (
+    id bigserial primary key,
+    collection_id bigint not null references collections(id),
+    channel_id bigint not null references channels(id),
+    unique (collection_id, channel_id)
+);
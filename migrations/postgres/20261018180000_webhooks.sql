@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS webhooks (
+    id bigserial primary key,
+    user_id bigint references users(id),
+    channel_id bigint references channels(id),
+    url text not null,
+    secret text not null,
+    enabled boolean not null default true,
+    failures integer not null default 0,
+    last_error text
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id bigserial primary key,
+    webhook_id bigint not null references webhooks(id),
+    post_id integer not null references posts(id),
+    attempts integer not null default 0,
+    next_attempt_at bigint not null default 0,
+    last_error text
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
@@ -0,0 +1,26 @@
+-- a post saved twice, e.g. by a backfill and by the live updates, is kept as first saved
+DELETE FROM deliveries
+WHERE post_id IN (
+    SELECT p.id FROM posts p
+    WHERE EXISTS (
+        SELECT 1 FROM posts o
+        WHERE o.chat_id = p.chat_id AND o.telegram_id = p.telegram_id AND o.id < p.id
+    )
+);
+
+DELETE FROM webhook_deliveries
+WHERE post_id IN (
+    SELECT p.id FROM posts p
+    WHERE EXISTS (
+        SELECT 1 FROM posts o
+        WHERE o.chat_id = p.chat_id AND o.telegram_id = p.telegram_id AND o.id < p.id
+    )
+);
+
+DELETE FROM posts
+WHERE EXISTS (
+    SELECT 1 FROM posts o
+    WHERE o.chat_id = posts.chat_id AND o.telegram_id = posts.telegram_id AND o.id < posts.id
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS posts_chat_id_telegram_id ON posts (chat_id, telegram_id);
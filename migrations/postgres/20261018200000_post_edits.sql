@@ -0,0 +1,5 @@
+-- photos and animations are not saved, they are viewed at the link of the post
+ALTER TABLE posts ADD COLUMN IF NOT EXISTS has_media boolean not null default false;
+
+-- webhooks are told whether a post is new or edited
+ALTER TABLE webhook_deliveries ADD COLUMN IF NOT EXISTS event text not null default 'post.created';
@@ -0,0 +1,10 @@
+-- messages of admins to all the users, queued like posts
+CREATE TABLE IF NOT EXISTS broadcasts (
+    id bigserial primary key,
+    text text not null,
+    created_at bigint not null default extract(epoch FROM now())::bigint
+);
+
+-- a delivery is either of a post or of a broadcast
+ALTER TABLE deliveries ALTER COLUMN post_id DROP NOT NULL;
+ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS broadcast_id bigint references broadcasts(id);
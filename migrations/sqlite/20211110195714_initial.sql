@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS posts (
+                       id integer primary key autoincrement,
+                       title text,
+                       link text not null,
+                       telegram_id bigint not null,
+                       pub_date integer not null,
+                       content text not null,
+                       chat_id bigint not null
+);
+
+create table if not exists channels (
+                          id bigint not null primary key,
+                          title text not null,
+                          username text not null unique
+);
+
+create table if not exists users (
+    id bigint not null primary key,
+    enabled bool not null default true,
+    chat_id bigint not null
+);
+
+create table if not exists user_channel (
+    id integer primary key autoincrement,
+    user_id bigint not null references users(id),
+    channel_id bigint not null references channels(id),
+    unique (user_id, channel_id)
+);
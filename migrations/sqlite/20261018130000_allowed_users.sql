@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN allowed bool NOT NULL DEFAULT false;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS user_state_changes (
+    id integer primary key autoincrement,
+    user_id bigint not null references users(id),
+    enabled bool not null,
+    reason text not null,
+    changed_at bigint not null default (CAST(strftime('%s', 'now') AS integer))
+);
+
+CREATE INDEX IF NOT EXISTS user_state_changes_user_id ON user_state_changes (user_id, id);
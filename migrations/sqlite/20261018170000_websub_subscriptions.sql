@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS websub_subscriptions (
+    id integer primary key autoincrement,
+    channel_id bigint not null references channels(id),
+    callback text not null,
+    secret text,
+    expires_at bigint not null,
+    unique (channel_id, callback)
+);
@@ -0,0 +1,5 @@
+-- photos and animations are not saved, they are viewed at the link of the post
+ALTER TABLE posts ADD COLUMN has_media boolean not null default false;
+
+-- webhooks are told whether a post is new or edited
+ALTER TABLE webhook_deliveries ADD COLUMN event text not null default 'post.created';
@@ -0,0 +1,27 @@
+-- messages of admins to all the users, queued like posts
+CREATE TABLE IF NOT EXISTS broadcasts (
+    id integer primary key autoincrement,
+    text text not null,
+    created_at bigint not null default (CAST(strftime('%s', 'now') AS integer))
+);
+
+-- a delivery is either of a post or of a broadcast, sqlite cannot drop not null in place
+CREATE TABLE IF NOT EXISTS deliveries_new (
+    id integer primary key autoincrement,
+    post_id integer references posts(id),
+    broadcast_id integer references broadcasts(id),
+    user_id bigint not null references users(id),
+    chat_id bigint not null,
+    attempts integer not null default 0,
+    next_attempt_at bigint not null default 0,
+    last_error text
+);
+
+INSERT INTO deliveries_new (id, post_id, user_id, chat_id, attempts, next_attempt_at, last_error)
+SELECT id, post_id, user_id, chat_id, attempts, next_attempt_at, last_error FROM deliveries;
+
+DROP TABLE deliveries;
+
+ALTER TABLE deliveries_new RENAME TO deliveries;
+
+CREATE INDEX IF NOT EXISTS deliveries_chat_id ON deliveries (chat_id, id);
@@ -0,0 +1,2 @@
+-- feeds are paged by publish date within a channel
+CREATE INDEX IF NOT EXISTS posts_chat_id_pub_date ON posts (chat_id, pub_date, telegram_id);
@@ -1,19 +1,27 @@
 use crate::db::DbService;
-use crate::models;
+use crate::outbox::{disable_user, Outbox};
+use crate::settings::DeliverySettings;
 use crate::telegram::{
-    BotRequests, BotResponseListChannels, BotResponses, NewUpdate, ServiceRequests,
-    ServiceResponses, TelegramService,
+    BotRequests, BotResponseChannelsAdded, BotResponseChannelsRemoved, BotResponseCollections,
+    BotResponseListChannels, BotResponseStats, BotResponseText, BotResponses, CollectionAction,
+    CollectionRequest, NewUpdate, ServiceRequests, ServiceResponses, TelegramService,
+    WebhookAction, WebhookRequest,
 };
+use crate::{metrics, models, webhooks};
 use anyhow::anyhow;
+use rand::distributions::{Alphanumeric, DistString};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
-
-const HISTORY_LIMIT: i32 = 100;
+use tracing::Instrument;
 
 struct Inner {
     tg: TelegramService,
     db: DbService,
+    delivery: DeliverySettings,
+    limits: models::SubscriptionLimits,
+    /// Webhooks a user may add, 0 if only admins add them.
+    max_webhooks: usize,
 }
 
 #[derive(Clone)]
@@ -22,84 +30,412 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(tg: TelegramService, db: DbService) -> Self {
+    pub fn new(
+        tg: TelegramService,
+        db: DbService,
+        delivery: DeliverySettings,
+        limits: models::SubscriptionLimits,
+        max_webhooks: usize,
+    ) -> Self {
         Self {
-            inner: Arc::new(Inner { tg, db }),
+            inner: Arc::new(Inner {
+                tg,
+                db,
+                delivery,
+                limits,
+                max_webhooks,
+            }),
         }
     }
 
-    pub async fn start(&self) -> anyhow::Result<JoinHandle<()>> {
+    /// Starts the application. The returned handle completes after `stop` is called
+    /// (or telegram service exits on its own) and all pending requests are processed.
+    pub async fn start(&self) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+        let access = self.inner.tg.access();
+        if access.is_private() {
+            let users = self.inner.db.get_users().await?;
+            access.allow(users.into_iter().filter(|u| u.allowed).map(|u| u.id));
+        }
+
         log::info!("starting telegram service");
-        let (fas, far) = mpsc::channel(10);
-        let (tas, mut tar) = mpsc::channel(10);
-        let h = self.inner.tg.start(far, tas).await?;
+        let (fas, far) = mpsc::channel(self.inner.delivery.queue_size);
+        let (tas, mut tar) = mpsc::channel(self.inner.delivery.queue_size);
+        metrics::watch_channel("app_responses", &fas);
+        metrics::watch_channel("app_requests", &tas);
+        // outcomes never wait behind bot requests, or the bot and the outbox could block each other
+        let (ds, dr) = mpsc::unbounded_channel();
+        let h = self
+            .inner
+            .tg
+            .start(self.inner.db.clone(), far, tas, ds)
+            .await?;
         log::info!("telegram service started");
 
+        let outbox =
+            Outbox::new(self.inner.db.clone(), self.inner.delivery.clone()).start(fas.clone(), dr);
+
         let db = self.inner.db.clone();
-        tokio::spawn(async move {
+        let limits = self.inner.limits;
+        let max_webhooks = self.inner.max_webhooks;
+        let processor = tokio::spawn(async move {
             while let Some(r) = tar.recv().await {
-                log::info!("new app request: {:?}", r);
-                let result = match &r {
-                    ServiceRequests::Bot(bot_request) => match bot_request {
-                        BotRequests::AddUser(add_user) => {
-                            db.save_user(models::NewUser {
-                                user_id: add_user.user_id,
-                                chat_id: add_user.chat_id,
-                                enabled: true,
-                            })
-                            .await
-                        }
-                        BotRequests::RemoveUser(remove_user) => {
-                            db.save_user(models::NewUser {
-                                user_id: remove_user.user_id,
-                                chat_id: remove_user.chat_id,
-                                enabled: false,
-                            })
-                            .await
-                        }
-                        BotRequests::AddUserChannel(add_channel) => {
-                            if let Err(err) = db
-                                .save_channel(models::NewChannel {
-                                    title: add_channel.title.clone(),
-                                    telegram_id: add_channel.channel_id,
-                                    username: add_channel.channel_name.clone(),
-                                })
+                // requests are logged without their fields, those may contain message texts
+                let span = tracing::info_span!("app_request", request = r.name());
+                let result = async {
+                    match &r {
+                        ServiceRequests::Bot(bot_request) => match bot_request {
+                            BotRequests::AddUser(add_user) => {
+                                // the bot lets only allowed users start a private instance,
+                                // the allowance is persisted for those who used an invite code
+                                let allowed = if access.is_private() {
+                                    db.allow_user(add_user.user_id, add_user.chat_id).await
+                                } else {
+                                    Ok(())
+                                };
+                                match allowed {
+                                    Err(e) => Err(e),
+                                    Ok(()) => {
+                                        db.save_user(models::NewUser {
+                                            user_id: add_user.user_id,
+                                            chat_id: add_user.chat_id,
+                                            enabled: true,
+                                            reason: models::StateReason::Started,
+                                        })
+                                        .await
+                                    }
+                                }
+                            }
+                            BotRequests::RemoveUser(user) | BotRequests::Blocked(user) => {
+                                let reason = match bot_request {
+                                    BotRequests::Blocked(_) => models::StateReason::Blocked,
+                                    _ => models::StateReason::Stopped,
+                                };
+                                disable_user(&db, user.user_id, user.chat_id, reason).await
+                            }
+                            BotRequests::AddUserChannels(add_channels) => {
+                                match db.get_user(add_channels.user_id).await {
+                                    Err(e) => Err(e),
+                                    Ok(Some(user)) if user.banned => fas
+                                        .send(ServiceResponses::Bot(BotResponses::Text(
+                                            BotResponseText {
+                                                chat_id: add_channels.chat_id,
+                                                text: "you are banned".to_string(),
+                                            },
+                                        )))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                    Ok(_) => match db
+                                        .add_user_channels(
+                                            add_channels.user_id,
+                                            &add_channels.channels,
+                                            limits,
+                                        )
+                                        .await
+                                    {
+                                        Err(e) => Err(e),
+                                        Ok(mut res) => {
+                                            res.over_limit
+                                                .extend(add_channels.over_limit.iter().cloned());
+                                            fas.send(ServiceResponses::Bot(
+                                                BotResponses::ChannelsAdded(
+                                                    BotResponseChannelsAdded {
+                                                        chat_id: add_channels.chat_id,
+                                                        added: res.added,
+                                                        already_present: res.already_present,
+                                                        unresolved: add_channels.unresolved.clone(),
+                                                        over_limit: res.over_limit,
+                                                    },
+                                                ),
+                                            ))
+                                            .await
+                                            .map_err(anyhow::Error::msg)
+                                        }
+                                    },
+                                }
+                            }
+                            BotRequests::ListChannels(user_id) => {
+                                match db.get_user_channels(*user_id).await {
+                                    Err(e) => Err(e),
+                                    Ok((chat_id, channels)) => fas
+                                        .send(ServiceResponses::Bot(BotResponses::ListChannels(
+                                            BotResponseListChannels { chat_id, channels },
+                                        )))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
+                            }
+                            BotRequests::ExportChannels(user_id) => {
+                                match db.get_user_channels(*user_id).await {
+                                    Err(e) => Err(e),
+                                    Ok((chat_id, channels)) => fas
+                                        .send(ServiceResponses::Bot(BotResponses::ExportChannels(
+                                            BotResponseListChannels { chat_id, channels },
+                                        )))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
+                            }
+                            BotRequests::RemoveUserChannels(remove_channels) => match db
+                                .remove_user_channels(
+                                    remove_channels.user_id,
+                                    &remove_channels.channel_names,
+                                )
                                 .await
                             {
-                                Err(err)
-                            } else {
-                                db.save_user_channel(models::NewUserChannel {
-                                    user_id: add_channel.user_id,
-                                    channel_id: add_channel.channel_id,
-                                })
-                                .await
-                            }
-                        }
-                        BotRequests::ListChannels(user_id) => {
-                            match db.get_user_channels(*user_id).await {
                                 Err(e) => Err(e),
-                                Ok((chat_id, channels)) => fas
-                                    .send(ServiceResponses::Bot(BotResponses::ListChannels(
-                                        BotResponseListChannels { chat_id, channels },
+                                Ok((removed, mut not_found)) => {
+                                    not_found.extend(remove_channels.invalid.iter().cloned());
+                                    fas.send(ServiceResponses::Bot(BotResponses::ChannelsRemoved(
+                                        BotResponseChannelsRemoved {
+                                            chat_id: remove_channels.chat_id,
+                                            removed,
+                                            not_found,
+                                        },
+                                    )))
+                                    .await
+                                    .map_err(anyhow::Error::msg)
+                                }
+                            },
+                            BotRequests::Stats(chat_id) => match db.get_stats().await {
+                                Err(e) => Err(e),
+                                Ok(stats) => fas
+                                    .send(ServiceResponses::Bot(BotResponses::Stats(
+                                        BotResponseStats {
+                                            chat_id: *chat_id,
+                                            stats,
+                                        },
                                     )))
                                     .await
                                     .map_err(anyhow::Error::msg),
+                            },
+                            // sent by the outbox within the limits of telegram, like posts
+                            BotRequests::Broadcast(broadcast) => {
+                                match db.queue_broadcast(&broadcast.text).await {
+                                    Err(e) => Err(e),
+                                    Ok(queued) => fas
+                                        .send(ServiceResponses::Bot(BotResponses::Text(
+                                            BotResponseText {
+                                                chat_id: broadcast.chat_id,
+                                                text: format!("queued for {queued} users"),
+                                            },
+                                        )))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
+                            }
+                            BotRequests::BanUser(ban) => match db.ban_user(ban.user_id).await {
+                                Err(e) => Err(e),
+                                Ok(()) => {
+                                    if let Err(err) = db.remove_user_deliveries(ban.user_id).await {
+                                        log::error!("cannot remove deliveries: {err:#}");
+                                    }
+                                    access.revoke(ban.user_id);
+                                    fas.send(ServiceResponses::Bot(BotResponses::Text(
+                                        BotResponseText {
+                                            chat_id: ban.chat_id,
+                                            text: format!("user {} is banned", ban.user_id),
+                                        },
+                                    )))
+                                    .await
+                                    .map_err(anyhow::Error::msg)
+                                }
+                            },
+                            BotRequests::RefetchChannel(refetch) => {
+                                match db.save_channel(refetch.channel.clone()).await {
+                                    Err(e) => Err(e),
+                                    Ok(()) => fas
+                                        .send(ServiceResponses::Bot(BotResponses::Text(
+                                            BotResponseText {
+                                                chat_id: refetch.chat_id,
+                                                text: format!(
+                                                    "channel {} is updated: {}",
+                                                    refetch.channel.username, refetch.channel.title
+                                                ),
+                                            },
+                                        )))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
                             }
-                        }
-                        BotRequests::RemoveUserChannel(remove_channel) => {
-                            db.remove_user_channel(models::RemoveUserChannel {
-                                user_id: remove_channel.user_id,
-                                channel_name: remove_channel.channel_name.clone(),
-                            })
-                            .await
-                        }
-                    },
-                };
+                            BotRequests::Collection(request) => {
+                                match handle_collection(&db, request).await {
+                                    Err(e) => Err(e),
+                                    Ok(response) => fas
+                                        .send(ServiceResponses::Bot(response))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
+                            }
+                            BotRequests::Webhook(request) => {
+                                match handle_webhook(&db, request, max_webhooks).await {
+                                    Err(e) => Err(e),
+                                    Ok(response) => fas
+                                        .send(ServiceResponses::Bot(response))
+                                        .await
+                                        .map_err(anyhow::Error::msg),
+                                }
+                            }
+                        },
+                    }
+                }
+                .instrument(span.clone())
+                .await;
                 if let Err(err) = result {
-                    log::error!("error {err} for request {r:?}");
+                    span.in_scope(|| log::error!("request failed: {err:#}"));
                 }
             }
         });
-        Ok(h)
+
+        let db = self.inner.db.clone();
+        Ok(tokio::spawn(async move {
+            let result = h.await?;
+            // telegram service has dropped its sender, so the processor
+            // finishes as soon as all the pending requests are handled
+            processor.await?;
+            // and the outbox once it has recorded the outcomes passed by the processor
+            outbox.await?;
+            db.close().await;
+            result
+        }))
+    }
+
+    pub async fn stop(&self) {
+        log::info!("stopping telegram service");
+        self.inner.tg.stop().await;
+    }
+}
+
+/// Length of the random part of collection feed URLs.
+const COLLECTION_TOKEN_LEN: usize = 16;
+
+async fn handle_collection(
+    db: &DbService,
+    request: &CollectionRequest,
+) -> anyhow::Result<BotResponses> {
+    let (user_id, chat_id) = (request.user_id, request.chat_id);
+    let text = |text: String| BotResponses::Text(BotResponseText { chat_id, text });
+    let response = match &request.action {
+        CollectionAction::List => BotResponses::Collections(BotResponseCollections {
+            chat_id,
+            note: None,
+            collections: db.get_user_collections(user_id).await?,
+        }),
+        CollectionAction::Create(name) => {
+            let token = Alphanumeric.sample_string(&mut rand::thread_rng(), COLLECTION_TOKEN_LEN);
+            if db.create_collection(user_id, name, &token).await? {
+                BotResponses::Collections(BotResponseCollections {
+                    chat_id,
+                    note: Some(format!("collection {name} is created")),
+                    collections: db
+                        .get_user_collections(user_id)
+                        .await?
+                        .into_iter()
+                        .filter(|c| &c.name == name)
+                        .collect(),
+                })
+            } else {
+                text(format!("collection {name} already exists"))
+            }
+        }
+        CollectionAction::Delete(name) => {
+            if db.delete_collection(user_id, name).await? {
+                text(format!("collection {name} is deleted"))
+            } else {
+                text(format!("collection {name} not found"))
+            }
+        }
+        CollectionAction::Add {
+            name,
+            channel_names,
+            invalid,
+        } => match db
+            .add_collection_channels(user_id, name, channel_names)
+            .await?
+        {
+            None => text(format!("collection {name} not found")),
+            Some((added, mut not_subscribed)) => {
+                not_subscribed.extend(invalid.iter().cloned());
+                let mut s = "".to_string();
+                append_channels(&mut s, &format!("added to {name}"), &added);
+                append_channels(&mut s, "not subscribed", &not_subscribed);
+                text(s)
+            }
+        },
+        CollectionAction::Remove {
+            name,
+            channel_names,
+            invalid,
+        } => match db
+            .remove_collection_channels(user_id, name, channel_names)
+            .await?
+        {
+            None => text(format!("collection {name} not found")),
+            Some((removed, mut not_found)) => {
+                not_found.extend(invalid.iter().cloned());
+                let mut s = "".to_string();
+                append_channels(&mut s, &format!("removed from {name}"), &removed);
+                append_channels(&mut s, "not found", &not_found);
+                text(s)
+            }
+        },
+    };
+    Ok(response)
+}
+
+async fn handle_webhook(
+    db: &DbService,
+    request: &WebhookRequest,
+    max_webhooks: usize,
+) -> anyhow::Result<BotResponses> {
+    let chat_id = request.chat_id;
+    let target = models::WebhookTarget::User(request.user_id);
+    let text = match &request.action {
+        WebhookAction::List => {
+            let webhooks = db.get_webhooks(Some(target)).await?;
+            if webhooks.is_empty() {
+                "no webhooks, add one with /webhook add <url>".to_string()
+            } else {
+                webhooks
+                    .iter()
+                    .map(|w| match (w.enabled, &w.last_error) {
+                        (true, _) => format!("{}\n", w.url),
+                        (false, Some(error)) => format!("{} (disabled: {error})\n", w.url),
+                        (false, None) => format!("{} (disabled)\n", w.url),
+                    })
+                    .collect()
+            }
+        }
+        WebhookAction::Add(url) => {
+            let webhooks = db.get_webhooks(Some(target)).await?;
+            if max_webhooks == 0 {
+                "webhooks are added by admins only".to_string()
+            } else if !webhooks.iter().any(|w| &w.url == url) && webhooks.len() >= max_webhooks {
+                format!("you may add up to {max_webhooks} webhooks")
+            } else {
+                let secret =
+                    Alphanumeric.sample_string(&mut rand::thread_rng(), webhooks::SECRET_LEN);
+                let webhook = db.save_webhook(target, url, &secret).await?;
+                format!(
+                    "posts of your channels are sent to {url}, \
+                    their signatures are made with the secret {}",
+                    webhook.secret
+                )
+            }
+        }
+        WebhookAction::Remove(url) => {
+            if db.remove_webhook(target, url).await? {
+                format!("webhook {url} is removed")
+            } else {
+                format!("webhook {url} not found")
+            }
+        }
+    };
+    Ok(BotResponses::Text(BotResponseText { chat_id, text }))
+}
+
+fn append_channels(s: &mut String, header: &str, channels: &[String]) {
+    if channels.is_empty() {
+        return;
     }
+    *s += format!("{}:\n{}\n", header, channels.join("\n")).as_str();
 }
@@ -0,0 +1,330 @@
+use crate::db::DbService;
+use crate::settings::Settings;
+use crate::telegram::TelegramService;
+use crate::{feed, models, opml, webhooks};
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand};
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashSet;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Debug, Parser)]
+#[command(name = "tgfeed", version, about = "RSS feeds of telegram channels")]
+pub struct Cli {
+    /// Config file, `config.*` in the working directory by default.
+    #[arg(long, global = true, env = "TGFEED_CONFIG")]
+    pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the bot and the reader account; the default command.
+    Serve,
+    #[command(flatten)]
+    Admin(AdminCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// Applies pending database migrations, even if they are disabled in the config.
+    Migrate,
+    /// Manages bot users.
+    #[command(subcommand)]
+    Users(UsersCommand),
+    /// Manages channels.
+    #[command(subcommand)]
+    Channels(ChannelsCommand),
+    /// Manages endpoints new posts are sent to.
+    #[command(subcommand)]
+    Webhooks(WebhooksCommand),
+    /// Saves the latest posts of the channel fetched by the reader account. Unless the channel
+    /// has no posts saved yet, new posts are queued for delivery to its subscribers.
+    /// Refuses to run while `serve` uses the reader account, TDLib cannot share its database.
+    Backfill {
+        /// Channel name or link.
+        channel: String,
+        /// How many posts to fetch, `ingestion.history_limit` by default.
+        #[arg(long)]
+        limit: Option<i32>,
+    },
+    /// Prints the RSS feed of the channel.
+    ExportFeed {
+        /// Channel name or link.
+        channel: String,
+        /// How many of the latest posts to include, `feeds.default_items` by default.
+        #[arg(long)]
+        limit: Option<i64>,
+    },
+    /// Prints the number of users, channels, subscriptions and posts.
+    Stats,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UsersCommand {
+    List,
+    /// Stops delivering posts to the user, as if the user sent /stop.
+    Disable {
+        user_id: i64,
+    },
+    /// Prints when the user was enabled and disabled and why.
+    History {
+        user_id: i64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ChannelsCommand {
+    /// Lists channels along with the number of their subscribers.
+    List,
+    /// Removes the channel with its posts and subscriptions.
+    Remove {
+        /// Channel name or link.
+        channel: String,
+    },
+    /// Updates the title and the id of the channel from telegram.
+    Refetch {
+        /// Channel name or link.
+        channel: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WebhooksCommand {
+    /// Lists webhooks of channels and users.
+    List,
+    /// Sends new posts to the URL and prints the secret their signatures are made with.
+    /// Adding a disabled webhook again enables it.
+    Add {
+        url: String,
+        #[command(flatten)]
+        target: WebhookTargetArgs,
+    },
+    Remove {
+        url: String,
+        #[command(flatten)]
+        target: WebhookTargetArgs,
+    },
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct WebhookTargetArgs {
+    /// Posts of the channel, a name or link.
+    #[arg(long)]
+    channel: Option<String>,
+    /// Posts of the channels the user is subscribed to.
+    #[arg(long)]
+    user: Option<i64>,
+}
+
+pub async fn run(command: AdminCommand, mut settings: Settings) -> Result<()> {
+    if let AdminCommand::Migrate = command {
+        settings.db.run_migrations = true;
+    }
+    let db = DbService::new(&settings.db).await?;
+    let result = execute(command, settings, &db).await;
+    db.close().await;
+    result
+}
+
+async fn execute(command: AdminCommand, settings: Settings, db: &DbService) -> Result<()> {
+    let base_url = settings.feeds.base_url.clone();
+    match command {
+        AdminCommand::Migrate => println!("database is up to date"),
+        AdminCommand::Users(UsersCommand::List) => {
+            println!("id\tchat_id\tenabled\tbanned");
+            for user in db.get_users().await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.id, user.chat_id, user.enabled, user.banned
+                );
+            }
+        }
+        AdminCommand::Users(UsersCommand::Disable { user_id }) => {
+            if !db
+                .set_user_enabled(user_id, false, models::StateReason::Disabled)
+                .await?
+            {
+                bail!("user {user_id} not found");
+            }
+            db.remove_user_deliveries(user_id).await?;
+            println!("user {user_id} disabled");
+        }
+        AdminCommand::Users(UsersCommand::History { user_id }) => {
+            println!("changed_at\tenabled\treason");
+            for change in db.get_user_history(user_id).await? {
+                let changed_at = OffsetDateTime::from_unix_timestamp(change.changed_at)?;
+                println!(
+                    "{}\t{}\t{}",
+                    changed_at.format(&Rfc3339)?,
+                    change.enabled,
+                    change.reason
+                );
+            }
+        }
+        AdminCommand::Channels(ChannelsCommand::List) => {
+            println!("username\tid\tsubscribers\ttitle");
+            for (channel, subscribers) in db.get_channels().await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    channel.username, channel.id, subscribers, channel.title
+                );
+            }
+        }
+        AdminCommand::Channels(ChannelsCommand::Remove { channel }) => {
+            let name = channel_name(&base_url, &channel)?;
+            if !db.remove_channel(&name).await? {
+                bail!("channel {name} not found");
+            }
+            println!("channel {name} removed");
+        }
+        AdminCommand::Channels(ChannelsCommand::Refetch { channel }) => {
+            let name = channel_name(&base_url, &channel)?;
+            let channel = telegram(settings)
+                .with_user_client(|user| async move {
+                    user.search_channel(&name)
+                        .await?
+                        .ok_or_else(|| anyhow!("{name} is not a public channel"))
+                })
+                .await?;
+            println!(
+                "channel {}: {} ({})",
+                channel.username, channel.title, channel.telegram_id
+            );
+            db.save_channel(channel).await?;
+        }
+        AdminCommand::Webhooks(WebhooksCommand::List) => {
+            println!("id\ttarget\turl\tenabled\tfailures\tlast_error");
+            for webhook in db.get_webhooks(None).await? {
+                let target = match (webhook.user_id, webhook.channel) {
+                    (Some(user_id), _) => format!("user {user_id}"),
+                    (None, channel) => format!("channel {}", channel.unwrap_or_default()),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    webhook.id,
+                    target,
+                    webhook.url,
+                    webhook.enabled,
+                    webhook.failures,
+                    webhook.last_error.unwrap_or_default()
+                );
+            }
+        }
+        AdminCommand::Webhooks(WebhooksCommand::Add { url, target }) => {
+            if !webhooks::is_valid_url(&url) {
+                bail!("{url} is not an http(s) url");
+            }
+            let target = webhook_target(&base_url, db, target).await?;
+            let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), webhooks::SECRET_LEN);
+            let webhook = db.save_webhook(target, &url, &secret).await?;
+            println!("webhook {} added, secret: {}", webhook.id, webhook.secret);
+        }
+        AdminCommand::Webhooks(WebhooksCommand::Remove { url, target }) => {
+            let target = webhook_target(&base_url, db, target).await?;
+            if !db.remove_webhook(target, &url).await? {
+                bail!("webhook {url} not found");
+            }
+            println!("webhook {url} removed");
+        }
+        AdminCommand::Backfill { channel, limit } => {
+            let name = channel_name(&base_url, &channel)?;
+            let limit = limit.unwrap_or(settings.ingestion.history_limit);
+            if !(1..=100).contains(&limit) {
+                bail!("--limit must be in 1..=100, telegram returns at most 100 messages at once");
+            }
+            let (channel, mut posts) = telegram(settings)
+                .with_user_client(|user| async move {
+                    let channel = user
+                        .search_channel(&name)
+                        .await?
+                        .ok_or_else(|| anyhow!("{name} is not a public channel"))?;
+                    let posts = user.get_channel_history(channel.telegram_id, limit).await?;
+                    Ok((channel, posts))
+                })
+                .await?;
+            let (chat_id, name) = (channel.telegram_id, channel.username.clone());
+            db.save_channel(channel).await?;
+
+            let known: HashSet<_> = db
+                .get_channel_post_ids(chat_id, i64::MAX)
+                .await?
+                .into_iter()
+                .map(|(_, telegram_id)| telegram_id)
+                .collect();
+            let fetched = posts.len();
+            posts.retain(|p| !known.contains(&p.telegram_id));
+            for post in posts.iter_mut() {
+                post.link = feed::post_link(&name, post.telegram_id);
+            }
+            // the first backfill of a channel only fills its feed, later ones catch up
+            // on missed posts, so those are delivered to the subscribers as well
+            db.save_channel_posts(&posts, !known.is_empty()).await?;
+            println!(
+                "saved {} new posts of {name}, {} are already saved",
+                posts.len(),
+                fetched - posts.len()
+            );
+        }
+        AdminCommand::ExportFeed { channel, limit } => {
+            let name = channel_name(&base_url, &channel)?;
+            let limit = limit.unwrap_or(settings.feeds.default_items);
+            let (channel, posts) = db
+                .get_channel_posts(&name, models::PostsPage::Latest, limit)
+                .await?
+                .ok_or_else(|| anyhow!("channel {name} not found"))?;
+            print!(
+                "{}",
+                feed::render(&channel, &posts, &feed::History::default())
+            );
+        }
+        AdminCommand::Stats => {
+            let stats = db.get_stats().await?;
+            println!("users: {} ({} enabled)", stats.users, stats.enabled_users);
+            println!("channels: {}", stats.channels);
+            println!("subscriptions: {}", stats.subscriptions);
+            println!(
+                "posts: {} ({} in the last 24 hours)",
+                stats.posts, stats.posts_last_day
+            );
+        }
+    }
+    Ok(())
+}
+
+fn telegram(settings: Settings) -> TelegramService {
+    TelegramService::new(
+        settings.telegram,
+        settings.ingestion,
+        settings.delivery,
+        settings.feeds.base_url,
+        settings.limits,
+    )
+}
+
+async fn webhook_target(
+    base_url: &str,
+    db: &DbService,
+    target: WebhookTargetArgs,
+) -> Result<models::WebhookTarget> {
+    match (target.user, target.channel) {
+        (Some(user_id), _) => Ok(models::WebhookTarget::User(user_id)),
+        (None, Some(channel)) => {
+            let name = channel_name(base_url, &channel)?;
+            let channel = db
+                .get_channel(&name)
+                .await?
+                .ok_or_else(|| anyhow!("channel {name} not found"))?;
+            Ok(models::WebhookTarget::Channel(channel.id))
+        }
+        (None, None) => bail!("either --channel or --user is required"),
+    }
+}
+
+fn channel_name(base_url: &str, channel: &str) -> Result<String> {
+    opml::channel_name_from_link(base_url, channel)
+        .ok_or_else(|| anyhow!("{channel} is not a channel name or link"))
+}
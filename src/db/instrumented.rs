@@ -0,0 +1,444 @@
+use super::Storage;
+use crate::{metrics, models};
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::Arc;
+use tracing::Instrument;
+
+/// Measures durations of the storage calls, runs them in spans and counts saved posts.
+pub struct Instrumented {
+    storage: Arc<dyn Storage>,
+}
+
+impl Instrumented {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+}
+
+async fn timed<T>(query: &str, call: impl Future<Output = T>) -> T {
+    let timer = metrics::DB_QUERY_SECONDS
+        .with_label_values(&[query])
+        .start_timer();
+    let result = call.instrument(tracing::debug_span!("db", query)).await;
+    timer.observe_duration();
+    result
+}
+
+#[async_trait]
+impl Storage for Instrumented {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.storage.migrate().await
+    }
+
+    async fn close(&self) {
+        self.storage.close().await
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        timed("ping", self.storage.ping()).await
+    }
+
+    async fn save_user(&self, user: models::NewUser) -> anyhow::Result<()> {
+        timed("save_user", self.storage.save_user(user)).await
+    }
+
+    async fn get_user(&self, user_id: i64) -> anyhow::Result<Option<models::User>> {
+        timed("get_user", self.storage.get_user(user_id)).await
+    }
+
+    async fn get_users(&self) -> anyhow::Result<Vec<models::User>> {
+        timed("get_users", self.storage.get_users()).await
+    }
+
+    async fn set_user_enabled(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        reason: models::StateReason,
+    ) -> anyhow::Result<bool> {
+        timed(
+            "set_user_enabled",
+            self.storage.set_user_enabled(user_id, enabled, reason),
+        )
+        .await
+    }
+
+    async fn ban_user(&self, user_id: i64) -> anyhow::Result<()> {
+        timed("ban_user", self.storage.ban_user(user_id)).await
+    }
+
+    async fn get_user_history(&self, user_id: i64) -> anyhow::Result<Vec<models::UserStateChange>> {
+        timed("get_user_history", self.storage.get_user_history(user_id)).await
+    }
+
+    async fn allow_user(&self, user_id: i64, chat_id: i64) -> anyhow::Result<()> {
+        timed("allow_user", self.storage.allow_user(user_id, chat_id)).await
+    }
+
+    async fn save_channel(&self, channel: models::NewChannel) -> anyhow::Result<()> {
+        timed("save_channel", self.storage.save_channel(channel)).await
+    }
+
+    async fn get_channels(&self) -> anyhow::Result<Vec<(models::Channel, i64)>> {
+        timed("get_channels", self.storage.get_channels()).await
+    }
+
+    async fn remove_channel(&self, channel_name: &str) -> anyhow::Result<bool> {
+        timed("remove_channel", self.storage.remove_channel(channel_name)).await
+    }
+
+    async fn add_user_channels(
+        &self,
+        user_id: i64,
+        channels: &[models::NewChannel],
+        limits: models::SubscriptionLimits,
+    ) -> anyhow::Result<models::AddedChannels> {
+        timed(
+            "add_user_channels",
+            self.storage.add_user_channels(user_id, channels, limits),
+        )
+        .await
+    }
+
+    async fn remove_user_channels(
+        &self,
+        user_id: i64,
+        channel_names: &[String],
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        timed(
+            "remove_user_channels",
+            self.storage.remove_user_channels(user_id, channel_names),
+        )
+        .await
+    }
+
+    async fn save_channel_posts(
+        &self,
+        posts: &[models::Post],
+        deliver: bool,
+    ) -> anyhow::Result<()> {
+        timed(
+            "save_channel_posts",
+            self.storage.save_channel_posts(posts, deliver),
+        )
+        .await?;
+        for post in posts {
+            metrics::POSTS_INGESTED
+                .with_label_values(&[&post.chat_id.to_string()])
+                .inc();
+        }
+        Ok(())
+    }
+
+    async fn edit_channel_post(
+        &self,
+        chat_id: models::TelegramChatId,
+        telegram_id: models::TelegramPostId,
+        content: &str,
+        has_media: bool,
+    ) -> anyhow::Result<bool> {
+        timed(
+            "edit_channel_post",
+            self.storage
+                .edit_channel_post(chat_id, telegram_id, content, has_media),
+        )
+        .await
+    }
+
+    async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<models::Channel>> {
+        timed("get_channel", self.storage.get_channel(channel_name)).await
+    }
+
+    async fn get_channel_by_id(
+        &self,
+        channel_id: models::TelegramChatId,
+    ) -> anyhow::Result<Option<models::Channel>> {
+        timed(
+            "get_channel_by_id",
+            self.storage.get_channel_by_id(channel_id),
+        )
+        .await
+    }
+
+    async fn get_user_channels(&self, user_id: i64) -> anyhow::Result<(i64, Vec<models::Channel>)> {
+        timed("get_user_channels", self.storage.get_user_channels(user_id)).await
+    }
+
+    async fn get_channel_post_ids(
+        &self,
+        chat_id: models::TelegramChatId,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::TelegramPostId)>> {
+        timed(
+            "get_channel_post_ids",
+            self.storage.get_channel_post_ids(chat_id, limit),
+        )
+        .await
+    }
+
+    async fn get_channel_posts(
+        &self,
+        channel_name: &str,
+        page: models::PostsPage,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Channel, Vec<models::Post>)>> {
+        timed(
+            "get_channel_posts",
+            self.storage.get_channel_posts(channel_name, page, limit),
+        )
+        .await
+    }
+
+    async fn get_stats(&self) -> anyhow::Result<models::Stats> {
+        timed("get_stats", self.storage.get_stats()).await
+    }
+
+    async fn create_collection(
+        &self,
+        user_id: i64,
+        name: &str,
+        token: &str,
+    ) -> anyhow::Result<bool> {
+        timed(
+            "create_collection",
+            self.storage.create_collection(user_id, name, token),
+        )
+        .await
+    }
+
+    async fn delete_collection(&self, user_id: i64, name: &str) -> anyhow::Result<bool> {
+        timed(
+            "delete_collection",
+            self.storage.delete_collection(user_id, name),
+        )
+        .await
+    }
+
+    async fn add_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        timed(
+            "add_collection_channels",
+            self.storage
+                .add_collection_channels(user_id, name, channel_names),
+        )
+        .await
+    }
+
+    async fn remove_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        timed(
+            "remove_collection_channels",
+            self.storage
+                .remove_collection_channels(user_id, name, channel_names),
+        )
+        .await
+    }
+
+    async fn get_user_collections(&self, user_id: i64) -> anyhow::Result<Vec<models::Collection>> {
+        timed(
+            "get_user_collections",
+            self.storage.get_user_collections(user_id),
+        )
+        .await
+    }
+
+    async fn get_collection_posts(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Collection, Vec<(models::Channel, models::Post)>)>> {
+        timed(
+            "get_collection_posts",
+            self.storage.get_collection_posts(token, limit),
+        )
+        .await
+    }
+
+    async fn get_last_post_id(&self) -> anyhow::Result<i32> {
+        timed("get_last_post_id", self.storage.get_last_post_id()).await
+    }
+
+    async fn get_new_posts(
+        &self,
+        channel_ids: &[models::TelegramChatId],
+        after_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::Channel, models::Post)>> {
+        timed(
+            "get_new_posts",
+            self.storage.get_new_posts(channel_ids, after_id, limit),
+        )
+        .await
+    }
+
+    async fn get_due_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::Delivery>> {
+        timed(
+            "get_due_deliveries",
+            self.storage.get_due_deliveries(now, limit),
+        )
+        .await
+    }
+
+    async fn remove_delivery(&self, delivery_id: i64) -> anyhow::Result<()> {
+        timed("remove_delivery", self.storage.remove_delivery(delivery_id)).await
+    }
+
+    async fn retry_delivery(
+        &self,
+        delivery_id: i64,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        timed(
+            "retry_delivery",
+            self.storage
+                .retry_delivery(delivery_id, next_attempt_at, error),
+        )
+        .await
+    }
+
+    async fn queue_broadcast(&self, text: &str) -> anyhow::Result<u64> {
+        timed("queue_broadcast", self.storage.queue_broadcast(text)).await
+    }
+
+    async fn remove_user_deliveries(&self, user_id: i64) -> anyhow::Result<u64> {
+        timed(
+            "remove_user_deliveries",
+            self.storage.remove_user_deliveries(user_id),
+        )
+        .await
+    }
+
+    async fn save_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+        secret: Option<&str>,
+        expires_at: i64,
+    ) -> anyhow::Result<()> {
+        timed(
+            "save_websub_subscription",
+            self.storage
+                .save_websub_subscription(channel_id, callback, secret, expires_at),
+        )
+        .await
+    }
+
+    async fn remove_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+    ) -> anyhow::Result<bool> {
+        timed(
+            "remove_websub_subscription",
+            self.storage
+                .remove_websub_subscription(channel_id, callback),
+        )
+        .await
+    }
+
+    async fn get_websub_subscriptions(
+        &self,
+        channel_id: i64,
+        now: i64,
+    ) -> anyhow::Result<Vec<models::WebSubSubscription>> {
+        timed(
+            "get_websub_subscriptions",
+            self.storage.get_websub_subscriptions(channel_id, now),
+        )
+        .await
+    }
+
+    async fn remove_expired_websub_subscriptions(&self, now: i64) -> anyhow::Result<u64> {
+        timed(
+            "remove_expired_websub_subscriptions",
+            self.storage.remove_expired_websub_subscriptions(now),
+        )
+        .await
+    }
+
+    async fn save_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+        secret: &str,
+    ) -> anyhow::Result<models::Webhook> {
+        timed(
+            "save_webhook",
+            self.storage.save_webhook(target, url, secret),
+        )
+        .await
+    }
+
+    async fn remove_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+    ) -> anyhow::Result<bool> {
+        timed("remove_webhook", self.storage.remove_webhook(target, url)).await
+    }
+
+    async fn get_webhooks(
+        &self,
+        target: Option<models::WebhookTarget>,
+    ) -> anyhow::Result<Vec<models::Webhook>> {
+        timed("get_webhooks", self.storage.get_webhooks(target)).await
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::WebhookDelivery>> {
+        timed(
+            "get_due_webhook_deliveries",
+            self.storage.get_due_webhook_deliveries(now, limit),
+        )
+        .await
+    }
+
+    async fn complete_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+    ) -> anyhow::Result<()> {
+        timed(
+            "complete_webhook_delivery",
+            self.storage
+                .complete_webhook_delivery(delivery_id, webhook_id),
+        )
+        .await
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> anyhow::Result<i32> {
+        timed(
+            "fail_webhook_delivery",
+            self.storage
+                .fail_webhook_delivery(delivery_id, webhook_id, next_attempt_at, error),
+        )
+        .await
+    }
+
+    async fn disable_webhook(&self, webhook_id: i64) -> anyhow::Result<()> {
+        timed("disable_webhook", self.storage.disable_webhook(webhook_id)).await
+    }
+}
@@ -0,0 +1,393 @@
+use super::Storage;
+use crate::feed_cache::FeedCache;
+use crate::models;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Drops cached feeds once the posts, channels or collections they are rendered from change.
+/// Channels with new posts are announced on `updates` then, e.g. for the WebSub hub.
+pub struct Invalidating {
+    storage: Arc<dyn Storage>,
+    feeds: FeedCache,
+    updates: broadcast::Sender<models::TelegramChatId>,
+}
+
+impl Invalidating {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        feeds: FeedCache,
+        updates: broadcast::Sender<models::TelegramChatId>,
+    ) -> Self {
+        Self {
+            storage,
+            feeds,
+            updates,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for Invalidating {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.storage.migrate().await
+    }
+
+    async fn close(&self) {
+        self.storage.close().await
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.storage.ping().await
+    }
+
+    async fn save_user(&self, user: models::NewUser) -> anyhow::Result<()> {
+        self.storage.save_user(user).await
+    }
+
+    async fn get_user(&self, user_id: i64) -> anyhow::Result<Option<models::User>> {
+        self.storage.get_user(user_id).await
+    }
+
+    async fn get_users(&self) -> anyhow::Result<Vec<models::User>> {
+        self.storage.get_users().await
+    }
+
+    async fn set_user_enabled(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        reason: models::StateReason,
+    ) -> anyhow::Result<bool> {
+        self.storage
+            .set_user_enabled(user_id, enabled, reason)
+            .await
+    }
+
+    async fn ban_user(&self, user_id: i64) -> anyhow::Result<()> {
+        self.storage.ban_user(user_id).await
+    }
+
+    async fn get_user_history(&self, user_id: i64) -> anyhow::Result<Vec<models::UserStateChange>> {
+        self.storage.get_user_history(user_id).await
+    }
+
+    async fn allow_user(&self, user_id: i64, chat_id: i64) -> anyhow::Result<()> {
+        self.storage.allow_user(user_id, chat_id).await
+    }
+
+    async fn save_channel(&self, channel: models::NewChannel) -> anyhow::Result<()> {
+        self.storage.save_channel(channel).await?;
+        // the title shows up in the feeds of the channel and of the collections
+        self.feeds.clear();
+        Ok(())
+    }
+
+    async fn get_channels(&self) -> anyhow::Result<Vec<(models::Channel, i64)>> {
+        self.storage.get_channels().await
+    }
+
+    async fn remove_channel(&self, channel_name: &str) -> anyhow::Result<bool> {
+        let removed = self.storage.remove_channel(channel_name).await?;
+        if removed {
+            self.feeds.clear();
+        }
+        Ok(removed)
+    }
+
+    async fn add_user_channels(
+        &self,
+        user_id: i64,
+        channels: &[models::NewChannel],
+        limits: models::SubscriptionLimits,
+    ) -> anyhow::Result<models::AddedChannels> {
+        self.storage
+            .add_user_channels(user_id, channels, limits)
+            .await
+    }
+
+    async fn remove_user_channels(
+        &self,
+        user_id: i64,
+        channel_names: &[String],
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let (removed, not_found) = self
+            .storage
+            .remove_user_channels(user_id, channel_names)
+            .await?;
+        if !removed.is_empty() {
+            // the channels leave the collections of the user
+            self.feeds.clear();
+        }
+        Ok((removed, not_found))
+    }
+
+    async fn save_channel_posts(
+        &self,
+        posts: &[models::Post],
+        deliver: bool,
+    ) -> anyhow::Result<()> {
+        self.storage.save_channel_posts(posts, deliver).await?;
+        let mut channel_ids: Vec<_> = posts.iter().map(|p| p.chat_id).collect();
+        channel_ids.sort_unstable();
+        channel_ids.dedup();
+        for channel_id in channel_ids {
+            self.feeds.invalidate_channel(channel_id);
+            // nobody may be listening
+            self.updates.send(channel_id).ok();
+        }
+        Ok(())
+    }
+
+    async fn edit_channel_post(
+        &self,
+        chat_id: models::TelegramChatId,
+        telegram_id: models::TelegramPostId,
+        content: &str,
+        has_media: bool,
+    ) -> anyhow::Result<bool> {
+        let edited = self
+            .storage
+            .edit_channel_post(chat_id, telegram_id, content, has_media)
+            .await?;
+        if edited {
+            self.feeds.invalidate_channel(chat_id);
+            // nobody may be listening
+            self.updates.send(chat_id).ok();
+        }
+        Ok(edited)
+    }
+
+    async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<models::Channel>> {
+        self.storage.get_channel(channel_name).await
+    }
+
+    async fn get_channel_by_id(
+        &self,
+        channel_id: models::TelegramChatId,
+    ) -> anyhow::Result<Option<models::Channel>> {
+        self.storage.get_channel_by_id(channel_id).await
+    }
+
+    async fn get_user_channels(&self, user_id: i64) -> anyhow::Result<(i64, Vec<models::Channel>)> {
+        self.storage.get_user_channels(user_id).await
+    }
+
+    async fn get_channel_post_ids(
+        &self,
+        chat_id: models::TelegramChatId,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::TelegramPostId)>> {
+        self.storage.get_channel_post_ids(chat_id, limit).await
+    }
+
+    async fn get_channel_posts(
+        &self,
+        channel_name: &str,
+        page: models::PostsPage,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Channel, Vec<models::Post>)>> {
+        self.storage
+            .get_channel_posts(channel_name, page, limit)
+            .await
+    }
+
+    async fn get_stats(&self) -> anyhow::Result<models::Stats> {
+        self.storage.get_stats().await
+    }
+
+    async fn create_collection(
+        &self,
+        user_id: i64,
+        name: &str,
+        token: &str,
+    ) -> anyhow::Result<bool> {
+        self.storage.create_collection(user_id, name, token).await
+    }
+
+    async fn delete_collection(&self, user_id: i64, name: &str) -> anyhow::Result<bool> {
+        let deleted = self.storage.delete_collection(user_id, name).await?;
+        if deleted {
+            self.feeds.clear();
+        }
+        Ok(deleted)
+    }
+
+    async fn add_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let res = self
+            .storage
+            .add_collection_channels(user_id, name, channel_names)
+            .await?;
+        self.feeds.clear();
+        Ok(res)
+    }
+
+    async fn remove_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let res = self
+            .storage
+            .remove_collection_channels(user_id, name, channel_names)
+            .await?;
+        self.feeds.clear();
+        Ok(res)
+    }
+
+    async fn get_user_collections(&self, user_id: i64) -> anyhow::Result<Vec<models::Collection>> {
+        self.storage.get_user_collections(user_id).await
+    }
+
+    async fn get_collection_posts(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Collection, Vec<(models::Channel, models::Post)>)>> {
+        self.storage.get_collection_posts(token, limit).await
+    }
+
+    async fn get_last_post_id(&self) -> anyhow::Result<i32> {
+        self.storage.get_last_post_id().await
+    }
+
+    async fn get_new_posts(
+        &self,
+        channel_ids: &[models::TelegramChatId],
+        after_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::Channel, models::Post)>> {
+        self.storage
+            .get_new_posts(channel_ids, after_id, limit)
+            .await
+    }
+
+    async fn get_due_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::Delivery>> {
+        self.storage.get_due_deliveries(now, limit).await
+    }
+
+    async fn remove_delivery(&self, delivery_id: i64) -> anyhow::Result<()> {
+        self.storage.remove_delivery(delivery_id).await
+    }
+
+    async fn retry_delivery(
+        &self,
+        delivery_id: i64,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        self.storage
+            .retry_delivery(delivery_id, next_attempt_at, error)
+            .await
+    }
+
+    async fn queue_broadcast(&self, text: &str) -> anyhow::Result<u64> {
+        self.storage.queue_broadcast(text).await
+    }
+
+    async fn remove_user_deliveries(&self, user_id: i64) -> anyhow::Result<u64> {
+        self.storage.remove_user_deliveries(user_id).await
+    }
+
+    async fn save_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+        secret: Option<&str>,
+        expires_at: i64,
+    ) -> anyhow::Result<()> {
+        self.storage
+            .save_websub_subscription(channel_id, callback, secret, expires_at)
+            .await
+    }
+
+    async fn remove_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+    ) -> anyhow::Result<bool> {
+        self.storage
+            .remove_websub_subscription(channel_id, callback)
+            .await
+    }
+
+    async fn get_websub_subscriptions(
+        &self,
+        channel_id: i64,
+        now: i64,
+    ) -> anyhow::Result<Vec<models::WebSubSubscription>> {
+        self.storage.get_websub_subscriptions(channel_id, now).await
+    }
+
+    async fn remove_expired_websub_subscriptions(&self, now: i64) -> anyhow::Result<u64> {
+        self.storage.remove_expired_websub_subscriptions(now).await
+    }
+
+    async fn save_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+        secret: &str,
+    ) -> anyhow::Result<models::Webhook> {
+        self.storage.save_webhook(target, url, secret).await
+    }
+
+    async fn remove_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+    ) -> anyhow::Result<bool> {
+        self.storage.remove_webhook(target, url).await
+    }
+
+    async fn get_webhooks(
+        &self,
+        target: Option<models::WebhookTarget>,
+    ) -> anyhow::Result<Vec<models::Webhook>> {
+        self.storage.get_webhooks(target).await
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::WebhookDelivery>> {
+        self.storage.get_due_webhook_deliveries(now, limit).await
+    }
+
+    async fn complete_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+    ) -> anyhow::Result<()> {
+        self.storage
+            .complete_webhook_delivery(delivery_id, webhook_id)
+            .await
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> anyhow::Result<i32> {
+        self.storage
+            .fail_webhook_delivery(delivery_id, webhook_id, next_attempt_at, error)
+            .await
+    }
+
+    async fn disable_webhook(&self, webhook_id: i64) -> anyhow::Result<()> {
+        self.storage.disable_webhook(webhook_id).await
+    }
+}
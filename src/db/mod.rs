@@ -0,0 +1,385 @@
+use crate::feed_cache::FeedCache;
+use crate::models;
+use crate::settings::DbSettings;
+use anyhow::bail;
+use async_trait::async_trait;
+use sqlx::migrate::{Migrate, Migrator};
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+mod instrumented;
+mod invalidating;
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Applies pending migrations.
+    async fn migrate(&self) -> anyhow::Result<()>;
+
+    async fn close(&self);
+
+    /// Checks that the database is reachable.
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    /// Creates the user or updates `enabled` of the existing one; banned users stay disabled.
+    /// A change of `enabled` is recorded in the history of the user along with the reason.
+    async fn save_user(&self, user: models::NewUser) -> anyhow::Result<()>;
+
+    async fn get_user(&self, user_id: i64) -> anyhow::Result<Option<models::User>>;
+
+    async fn get_users(&self) -> anyhow::Result<Vec<models::User>>;
+
+    /// Returns `false` if there is no such user. A change is recorded as in `save_user`.
+    async fn set_user_enabled(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        reason: models::StateReason,
+    ) -> anyhow::Result<bool>;
+
+    /// Disables the user for good, even if the user has never started the bot.
+    /// The ban is always recorded in the history.
+    async fn ban_user(&self, user_id: i64) -> anyhow::Result<()>;
+
+    /// Returns the recorded changes of the user state, the oldest first.
+    async fn get_user_history(&self, user_id: i64) -> anyhow::Result<Vec<models::UserStateChange>>;
+
+    /// Lets the user use a private instance; the user stays disabled until starting the bot.
+    async fn allow_user(&self, user_id: i64, chat_id: i64) -> anyhow::Result<()>;
+
+    async fn save_channel(&self, channel: models::NewChannel) -> anyhow::Result<()>;
+
+    /// Returns all the channels along with the number of their subscribers.
+    async fn get_channels(&self) -> anyhow::Result<Vec<(models::Channel, i64)>>;
+
+    /// Removes the channel with its posts, subscriptions, queued deliveries
+    /// and its entries in collections.
+    /// Returns `false` if there is no such channel.
+    async fn remove_channel(&self, channel_name: &str) -> anyhow::Result<bool>;
+
+    /// Subscribes the user to the channels in a single transaction, skipping the channels
+    /// which exceed the limits.
+    async fn add_user_channels(
+        &self,
+        user_id: i64,
+        channels: &[models::NewChannel],
+        limits: models::SubscriptionLimits,
+    ) -> anyhow::Result<models::AddedChannels>;
+
+    /// Unsubscribes the user from all the channels in a single transaction, queued deliveries
+    /// of their posts are dropped and the channels are removed from the user collections.
+    /// Returns names of the removed channels and of the channels the user was not subscribed to.
+    async fn remove_user_channels(
+        &self,
+        user_id: i64,
+        channel_names: &[String],
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)>;
+
+    /// Saves the posts; with `deliver` they are also queued for delivery to the enabled
+    /// subscribers of their channels, in the same transaction. Posts already saved are skipped.
+    async fn save_channel_posts(&self, posts: &[models::Post], deliver: bool)
+        -> anyhow::Result<()>;
+
+    /// Updates the content of a saved post and queues it for the webhooks of its channel
+    /// as edited. Returns whether the post is saved.
+    async fn edit_channel_post(
+        &self,
+        chat_id: models::TelegramChatId,
+        telegram_id: models::TelegramPostId,
+        content: &str,
+        has_media: bool,
+    ) -> anyhow::Result<bool>;
+
+    async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<models::Channel>>;
+
+    async fn get_channel_by_id(
+        &self,
+        channel_id: models::TelegramChatId,
+    ) -> anyhow::Result<Option<models::Channel>>;
+
+    /// Returns chat id of the user along with the channels the user is subscribed to.
+    async fn get_user_channels(&self, user_id: i64) -> anyhow::Result<(i64, Vec<models::Channel>)>;
+
+    async fn get_channel_post_ids(
+        &self,
+        chat_id: models::TelegramChatId,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::TelegramPostId)>>;
+
+    /// Returns up to `limit` posts of the page, the newest first.
+    /// Returns `None` if there is no such channel.
+    async fn get_channel_posts(
+        &self,
+        channel_name: &str,
+        page: models::PostsPage,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Channel, Vec<models::Post>)>>;
+
+    async fn get_stats(&self) -> anyhow::Result<models::Stats>;
+
+    /// Creates an empty collection of the user, `token` identifies its feeds.
+    /// Returns `false` if the user already has a collection with the name.
+    async fn create_collection(
+        &self,
+        user_id: i64,
+        name: &str,
+        token: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Removes the collection, subscriptions to its channels stay.
+    /// Returns `false` if there is no such collection.
+    async fn delete_collection(&self, user_id: i64, name: &str) -> anyhow::Result<bool>;
+
+    /// Adds the channels the user is subscribed to to the collection. Returns `None` if there
+    /// is no such collection, otherwise names of the added channels and of the channels
+    /// the user is not subscribed to.
+    async fn add_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>>;
+
+    /// Returns `None` if there is no such collection, otherwise names of the removed channels
+    /// and of the channels which are not in the collection.
+    async fn remove_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>>;
+
+    /// Returns the collections of the user ordered by name.
+    async fn get_user_collections(&self, user_id: i64) -> anyhow::Result<Vec<models::Collection>>;
+
+    /// Returns the collection along with the latest posts of its channels, the newest first,
+    /// each with the channel it comes from.
+    async fn get_collection_posts(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Collection, Vec<(models::Channel, models::Post)>)>>;
+
+    /// Returns the id of the last saved post, 0 if there are no posts.
+    async fn get_last_post_id(&self) -> anyhow::Result<i32>;
+
+    /// Returns up to `limit` posts of the channels saved after the post with id `after_id`,
+    /// in the order of saving, each with its id and the channel it comes from.
+    async fn get_new_posts(
+        &self,
+        channel_ids: &[models::TelegramChatId],
+        after_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::Channel, models::Post)>>;
+
+    /// Returns the oldest queued delivery of every chat of an enabled user, provided it is due
+    /// by `now` (unix time), in the order of queueing. Later deliveries of a chat wait for it.
+    async fn get_due_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::Delivery>>;
+
+    /// Removes a delivery once it is sent or given up on.
+    async fn remove_delivery(&self, delivery_id: i64) -> anyhow::Result<()>;
+
+    /// Counts the failed attempt and postpones the delivery until `next_attempt_at`.
+    async fn retry_delivery(
+        &self,
+        delivery_id: i64,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Queues the text for all the enabled users like a post, returns the number of the users.
+    async fn queue_broadcast(&self, text: &str) -> anyhow::Result<u64>;
+
+    /// Removes all the queued deliveries of the user, returns their number.
+    async fn remove_user_deliveries(&self, user_id: i64) -> anyhow::Result<u64>;
+
+    /// Subscribes the WebSub callback to the channel feed until `expires_at` (unix time),
+    /// renewing the lease and replacing the secret if it is subscribed already.
+    async fn save_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+        secret: Option<&str>,
+        expires_at: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Returns `false` if the callback is not subscribed to the channel feed.
+    async fn remove_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Returns the subscriptions to the channel feed which have not expired by `now`.
+    async fn get_websub_subscriptions(
+        &self,
+        channel_id: i64,
+        now: i64,
+    ) -> anyhow::Result<Vec<models::WebSubSubscription>>;
+
+    /// Removes the subscriptions expired by `now`, returns their number.
+    async fn remove_expired_websub_subscriptions(&self, now: i64) -> anyhow::Result<u64>;
+
+    /// Adds the webhook, an existing one with the same URL is enabled again and keeps
+    /// its secret. Posts saved with `deliver` are queued for the enabled webhooks.
+    async fn save_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+        secret: &str,
+    ) -> anyhow::Result<models::Webhook>;
+
+    /// Removes the webhook along with its queued posts.
+    /// Returns `false` if there is no such webhook.
+    async fn remove_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Returns the webhooks of the target, all of them if it is `None`, in the order of adding.
+    async fn get_webhooks(
+        &self,
+        target: Option<models::WebhookTarget>,
+    ) -> anyhow::Result<Vec<models::Webhook>>;
+
+    /// Returns queued posts of enabled webhooks due by `now` (unix time), in the order of
+    /// queueing.
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::WebhookDelivery>>;
+
+    /// Removes the delivered post and resets the failures of the webhook.
+    async fn complete_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+    ) -> anyhow::Result<()>;
+
+    /// Counts the failed attempt and postpones the post until `next_attempt_at`,
+    /// or removes it if it is `None`. Returns the failures of the webhook in a row.
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> anyhow::Result<i32>;
+
+    /// Disables the webhook and removes its queued posts.
+    async fn disable_webhook(&self, webhook_id: i64) -> anyhow::Result<()>;
+}
+
+/// Posts are ordered by `(pub_date, telegram_id)`, so a post dated back stays in its place.
+type PostKey = (i32, models::TelegramPostId);
+
+/// Keys the posts of the page are older (the first) or newer (the second) than. `cursor` is
+/// the key of the post of a `Before` or `After` page, `None` if there is no such post.
+fn page_bounds(page: models::PostsPage, cursor: Option<PostKey>) -> Option<(PostKey, PostKey)> {
+    const NEWEST: PostKey = (i32::MAX, i64::MAX);
+    const OLDEST: PostKey = (i32::MIN, i64::MIN);
+    match (page, cursor) {
+        (models::PostsPage::Latest | models::PostsPage::Oldest, _) => Some((NEWEST, OLDEST)),
+        (models::PostsPage::Before(_), Some(cursor)) => Some((cursor, OLDEST)),
+        (models::PostsPage::After(_), Some(cursor)) => Some((NEWEST, cursor)),
+        (models::PostsPage::Before(_) | models::PostsPage::After(_), None) => None,
+    }
+}
+
+/// Storage selected by the scheme of `db.path`: `postgres://` or `sqlite://`.
+#[derive(Clone)]
+pub struct DbService {
+    storage: Arc<dyn Storage>,
+}
+
+impl DbService {
+    pub async fn new(settings: &DbSettings) -> anyhow::Result<Self> {
+        let storage: Arc<dyn Storage> = if is_sqlite(&settings.path) {
+            Arc::new(sqlite::SqliteStorage::connect(settings).await?)
+        } else {
+            Arc::new(postgres::PgStorage::connect(settings).await?)
+        };
+        if settings.run_migrations {
+            log::info!("applying migrations");
+            storage.migrate().await?;
+        } else {
+            log::info!("migrations are disabled");
+        }
+        Ok(Self {
+            storage: Arc::new(instrumented::Instrumented::new(storage)),
+        })
+    }
+
+    /// Makes the storage drop the cached feeds affected by changes made through it
+    /// and announce the channels with new posts on `updates`.
+    pub fn with_feed_cache(
+        self,
+        feeds: FeedCache,
+        updates: broadcast::Sender<models::TelegramChatId>,
+    ) -> Self {
+        Self {
+            storage: Arc::new(invalidating::Invalidating::new(
+                self.storage,
+                feeds,
+                updates,
+            )),
+        }
+    }
+}
+
+impl Deref for DbService {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        self.storage.as_ref()
+    }
+}
+
+pub fn is_sqlite(path: &str) -> bool {
+    path.starts_with("sqlite:")
+}
+
+pub fn is_postgres(path: &str) -> bool {
+    path.starts_with("postgres://") || path.starts_with("postgresql://")
+}
+
+/// Fails if the database was migrated by a newer version of tgfeed. Only reads the schema,
+/// `tracked` tells whether the table of applied migrations exists.
+async fn check_schema_version<C>(
+    conn: &mut C,
+    migrator: &Migrator,
+    tracked: bool,
+) -> anyhow::Result<()>
+where
+    C: Migrate + Send + ?Sized,
+{
+    if !tracked {
+        log::info!("database schema is not initialized yet");
+        return Ok(());
+    }
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .max();
+    let known = migrator.iter().map(|m| m.version).max().unwrap_or(0);
+    match applied {
+        Some(applied) if applied > known => bail!(
+            "database schema version {applied} is newer than {known} known to this binary, upgrade tgfeed"
+        ),
+        Some(applied) => log::info!("database schema version {applied}, latest known {known}"),
+        None => log::info!("database schema is not initialized yet"),
+    }
+    Ok(())
+}
@@ -0,0 +1,1324 @@
+use super::{check_schema_version, page_bounds, Storage};
+use crate::models;
+use crate::settings::DbSettings;
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::{Postgres, Row, Transaction};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+/// The first released migration, see [`PgStorage::accept_released_initial`].
+const INITIAL_VERSION: i64 = 20211110195714;
+
+/// Queries are checked at build time against `sqlx-data.json`, run `cargo sqlx prepare`
+/// with `DATABASE_URL` of a migrated database after changing them.
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    pub async fn connect(settings: &DbSettings) -> anyhow::Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .connect(&settings.path)
+            .await?;
+        let storage = Self { pool };
+        let tracked = storage.table_exists("_sqlx_migrations").await?;
+        check_schema_version(&mut *storage.pool.acquire().await?, &MIGRATOR, tracked).await?;
+        Ok(storage)
+    }
+
+    async fn table_exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM information_schema.tables
+            WHERE table_schema = current_schema() AND table_name = $1)",
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    /// The released initial migration dropped the tables before creating them, it only creates
+    /// the missing ones now. Databases migrated by it are recorded with its new checksum, so
+    /// the migrator does not refuse to run.
+    async fn accept_released_initial(&self) -> anyhow::Result<()> {
+        if !self.table_exists("_sqlx_migrations").await? {
+            return Ok(());
+        }
+        let initial = MIGRATOR
+            .iter()
+            .find(|m| m.version == INITIAL_VERSION)
+            .expect("initial migration is embedded");
+        let updated = sqlx::query(
+            "UPDATE _sqlx_migrations SET checksum = $2 WHERE version = $1 AND checksum <> $2",
+        )
+        .bind(initial.version)
+        .bind(initial.checksum.as_ref())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if updated > 0 {
+            log::warn!("checksum of the applied migration {INITIAL_VERSION} is updated");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.accept_released_initial().await?;
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn save_user(&self, user: models::NewUser) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let before = sqlx::query_scalar!(
+            "SELECT enabled FROM users WHERE id = $1 FOR UPDATE",
+            user.user_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let enabled = sqlx::query_scalar!(
+            r#"INSERT INTO users (id, enabled, chat_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT(id) DO UPDATE SET enabled = excluded.enabled AND NOT users.banned
+            RETURNING enabled"#,
+            user.user_id,
+            user.enabled,
+            user.chat_id,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if before != Some(enabled) {
+            record_state(&mut tx, user.user_id, enabled, user.reason).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: i64) -> anyhow::Result<Option<models::User>> {
+        Ok(sqlx::query_as!(
+            models::User,
+            "SELECT id, enabled, banned, allowed, chat_id FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_users(&self) -> anyhow::Result<Vec<models::User>> {
+        Ok(sqlx::query_as!(
+            models::User,
+            "SELECT id, enabled, banned, allowed, chat_id FROM users ORDER BY id"
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn set_user_enabled(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        reason: models::StateReason,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let before = sqlx::query_scalar!(
+            "SELECT enabled FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let before = match before {
+            None => return Ok(false),
+            Some(before) => before,
+        };
+        sqlx::query!(
+            "UPDATE users SET enabled = $2 WHERE id = $1",
+            user_id,
+            enabled,
+        )
+        .execute(&mut tx)
+        .await?;
+        if before != enabled {
+            record_state(&mut tx, user_id, enabled, reason).await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn ban_user(&self, user_id: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // users chat with the bot privately, so the chat id is the user id
+        sqlx::query!(
+            r#"INSERT INTO users (id, enabled, banned, chat_id)
+            VALUES ($1, false, true, $1)
+            ON CONFLICT(id) DO UPDATE SET enabled = false, banned = true, allowed = false"#,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?;
+        record_state(&mut tx, user_id, false, models::StateReason::Banned).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_user_history(&self, user_id: i64) -> anyhow::Result<Vec<models::UserStateChange>> {
+        Ok(sqlx::query_as!(
+            models::UserStateChange,
+            r#"SELECT enabled, reason, changed_at
+            FROM user_state_changes
+            WHERE user_id = $1
+            ORDER BY id"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn allow_user(&self, user_id: i64, chat_id: i64) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO users (id, enabled, allowed, chat_id)
+            VALUES ($1, false, true, $2)
+            ON CONFLICT(id) DO UPDATE SET allowed = true"#,
+            user_id,
+            chat_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_channel(&self, channel: models::NewChannel) -> anyhow::Result<()> {
+        sqlx::query_as!(
+            Channel,
+            r#"INSERT INTO channels (id, title, username)
+            VALUES ($1, $2, $3)
+            -- TODO: do update id??? 0_o
+            ON CONFLICT(username) DO UPDATE SET title = excluded.title, id=excluded.id"#,
+            channel.telegram_id,
+            channel.title,
+            channel.username,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_channels(&self) -> anyhow::Result<Vec<(models::Channel, i64)>> {
+        let rows = sqlx::query!(
+            r#"SELECT c.id, c.title, c.username, count(uc.id) as "subscribers!"
+            FROM channels c
+            LEFT JOIN user_channel uc
+                ON uc.channel_id = c.id
+            GROUP BY c.id
+            ORDER BY c.username"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let channel = models::Channel {
+                    id: r.id,
+                    title: r.title,
+                    username: r.username,
+                };
+                (channel, r.subscribers)
+            })
+            .collect())
+    }
+
+    async fn remove_channel(&self, channel_name: &str) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!("SELECT id FROM channels WHERE username = $1", channel_name)
+            .fetch_optional(&mut tx)
+            .await?;
+        let channel_id = match rec {
+            None => return Ok(false),
+            Some(rec) => rec.id,
+        };
+        sqlx::query!("DELETE FROM user_channel WHERE channel_id = $1", channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "DELETE FROM collection_channel WHERE channel_id = $1",
+            channel_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM websub_subscriptions WHERE channel_id = $1",
+            channel_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM webhook_deliveries
+            WHERE post_id IN (SELECT id FROM posts WHERE chat_id = $1)
+                OR webhook_id IN (SELECT id FROM webhooks WHERE channel_id = $1)"#,
+            channel_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM webhooks WHERE channel_id = $1", channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "DELETE FROM deliveries WHERE post_id IN (SELECT id FROM posts WHERE chat_id = $1)",
+            channel_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM posts WHERE chat_id = $1", channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("DELETE FROM channels WHERE id = $1", channel_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn add_user_channels(
+        &self,
+        user_id: i64,
+        channels: &[models::NewChannel],
+        limits: models::SubscriptionLimits,
+    ) -> anyhow::Result<models::AddedChannels> {
+        let mut result = models::AddedChannels::default();
+        let mut tx = self.pool.begin().await?;
+        let counts = sqlx::query!(
+            r#"SELECT
+                (SELECT count(*) FROM user_channel WHERE user_id = $1) as "subscriptions!",
+                (SELECT count(*) FROM channels) as "channels!""#,
+            user_id,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        let (mut subscriptions, mut total) = (counts.subscriptions, counts.channels);
+        for channel in channels {
+            let existing = sqlx::query!(
+                r#"SELECT c.id, uc.id as "subscription_id?"
+                FROM channels c
+                LEFT JOIN user_channel uc
+                    ON uc.channel_id = c.id AND uc.user_id = $1
+                WHERE c.username = $2"#,
+                user_id,
+                channel.username,
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+            if matches!(&existing, Some(r) if r.subscription_id.is_some()) {
+                result.already_present.push(channel.username.clone());
+                continue;
+            }
+            if limits.exceeded(subscriptions, total, existing.is_some()) {
+                result.over_limit.push(channel.username.clone());
+                continue;
+            }
+            sqlx::query!(
+                r#"INSERT INTO channels (id, title, username)
+                VALUES ($1, $2, $3)
+                ON CONFLICT(username) DO UPDATE SET title = excluded.title, id=excluded.id"#,
+                channel.telegram_id,
+                channel.title,
+                channel.username,
+            )
+            .execute(&mut tx)
+            .await?;
+
+            let res = sqlx::query!(
+                r#"INSERT INTO user_channel (user_id, channel_id)
+                VALUES ($1, $2)
+                ON CONFLICT(user_id, channel_id) DO NOTHING"#,
+                user_id,
+                channel.telegram_id,
+            )
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                subscriptions += 1;
+                result.added.push(channel.username.clone());
+            } else {
+                result.already_present.push(channel.username.clone());
+            }
+            if existing.is_none() {
+                total += 1;
+            }
+        }
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn remove_user_channels(
+        &self,
+        user_id: i64,
+        channel_names: &[String],
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let mut removed = vec![];
+        let mut not_found = vec![];
+        let mut tx = self.pool.begin().await?;
+        for channel_name in channel_names {
+            let res = sqlx::query!(
+                r#"DELETE FROM user_channel uc
+                    USING channels c
+                WHERE c.id = uc.channel_id
+                    AND uc.user_id = $1 AND c.username = $2"#,
+                user_id,
+                channel_name,
+            )
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                sqlx::query!(
+                    r#"DELETE FROM deliveries d
+                        USING posts p, channels c
+                    WHERE p.id = d.post_id AND c.id = p.chat_id
+                        AND d.user_id = $1 AND c.username = $2"#,
+                    user_id,
+                    channel_name,
+                )
+                .execute(&mut tx)
+                .await?;
+                sqlx::query!(
+                    r#"DELETE FROM collection_channel cc
+                        USING collections col, channels c
+                    WHERE col.id = cc.collection_id AND c.id = cc.channel_id
+                        AND col.user_id = $1 AND c.username = $2"#,
+                    user_id,
+                    channel_name,
+                )
+                .execute(&mut tx)
+                .await?;
+                removed.push(channel_name.clone());
+            } else {
+                not_found.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok((removed, not_found))
+    }
+
+    async fn save_channel_posts(
+        &self,
+        posts: &[models::Post],
+        deliver: bool,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for p in posts.iter() {
+            // a post already saved, e.g. by a backfill, is neither saved nor delivered again
+            let rec = sqlx::query!(
+                r#"INSERT INTO posts
+                    (title, link, telegram_id, pub_date, content, chat_id, has_media)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (chat_id, telegram_id) DO NOTHING
+                RETURNING id"#,
+                p.title,
+                p.link,
+                p.telegram_id,
+                p.pub_date,
+                p.content,
+                p.chat_id,
+                p.has_media
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+            let rec = match rec {
+                Some(rec) => rec,
+                None => continue,
+            };
+            if deliver {
+                sqlx::query!(
+                    r#"INSERT INTO deliveries (post_id, user_id, chat_id)
+                    SELECT $1, u.id, u.chat_id
+                    FROM user_channel uc
+                    INNER JOIN users u
+                        ON u.id = uc.user_id
+                    WHERE uc.channel_id = $2 AND u.enabled"#,
+                    rec.id,
+                    p.chat_id
+                )
+                .execute(&mut tx)
+                .await?;
+                sqlx::query!(
+                    r#"INSERT INTO webhook_deliveries (webhook_id, post_id)
+                    SELECT w.id, $1
+                    FROM webhooks w
+                    WHERE w.enabled
+                        AND (w.channel_id = $2 OR w.user_id IN (
+                            SELECT u.id
+                            FROM user_channel uc
+                            INNER JOIN users u
+                                ON u.id = uc.user_id
+                            WHERE uc.channel_id = $2 AND u.enabled
+                        ))"#,
+                    rec.id,
+                    p.chat_id
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn edit_channel_post(
+        &self,
+        chat_id: models::TelegramChatId,
+        telegram_id: models::TelegramPostId,
+        content: &str,
+        has_media: bool,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            r#"UPDATE posts SET content = $3, has_media = $4
+            WHERE chat_id = $1 AND telegram_id = $2
+            RETURNING id"#,
+            chat_id,
+            telegram_id,
+            content,
+            has_media
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let rec = match rec {
+            Some(rec) => rec,
+            None => return Ok(false),
+        };
+        sqlx::query!(
+            r#"INSERT INTO webhook_deliveries (webhook_id, post_id, event)
+            SELECT w.id, $1, 'post.edited'
+            FROM webhooks w
+            WHERE w.enabled
+                AND (w.channel_id = $2 OR w.user_id IN (
+                    SELECT u.id
+                    FROM user_channel uc
+                    INNER JOIN users u
+                        ON u.id = uc.user_id
+                    WHERE uc.channel_id = $2 AND u.enabled
+                ))"#,
+            rec.id,
+            chat_id
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<models::Channel>> {
+        Ok(sqlx::query_as!(
+            models::Channel,
+            "SELECT id, title, username from channels where username = $1",
+            channel_name
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_channel_by_id(
+        &self,
+        channel_id: models::TelegramChatId,
+    ) -> anyhow::Result<Option<models::Channel>> {
+        Ok(sqlx::query_as!(
+            models::Channel,
+            "SELECT id, title, username FROM channels WHERE id = $1",
+            channel_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_user_channels(&self, user_id: i64) -> anyhow::Result<(i64, Vec<models::Channel>)> {
+        let rec = sqlx::query!(r#"select chat_id from users where id = $1"#, user_id,)
+            .fetch_one(&self.pool)
+            .await?;
+        let chat_id = rec.chat_id;
+
+        let channels = sqlx::query_as!(
+            models::Channel,
+            r#"SELECT c.id, c.title, c.username
+             FROM channels c
+             INNER JOIN user_channel uc
+                ON uc.channel_id = c.id
+             WHERE uc.user_id = $1
+             "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok((chat_id, channels))
+    }
+
+    async fn get_channel_post_ids(
+        &self,
+        chat_id: models::TelegramChatId,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::TelegramPostId)>> {
+        let rows = sqlx::query!(
+            r#"SELECT id, telegram_id
+            FROM posts
+            WHERE chat_id = $1
+            LIMIT $2"#,
+            chat_id,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|v| (v.id, v.telegram_id)).collect())
+    }
+
+    async fn get_channel_posts(
+        &self,
+        channel_name: &str,
+        page: models::PostsPage,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Channel, Vec<models::Post>)>> {
+        let ch = match self.get_channel(channel_name).await? {
+            None => return Ok(None),
+            Some(ch) => ch,
+        };
+        let cursor = match page {
+            models::PostsPage::Before(telegram_id) | models::PostsPage::After(telegram_id) => {
+                sqlx::query_scalar!(
+                    "SELECT pub_date FROM posts WHERE chat_id = $1 AND telegram_id = $2",
+                    ch.id,
+                    telegram_id
+                )
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|pub_date| (pub_date, telegram_id))
+            }
+            models::PostsPage::Latest | models::PostsPage::Oldest => None,
+        };
+        let (before, after) = match page_bounds(page, cursor) {
+            None => return Ok(Some((ch, vec![]))),
+            Some(bounds) => bounds,
+        };
+        let newest_first = matches!(
+            page,
+            models::PostsPage::Latest | models::PostsPage::Before(_)
+        );
+        let mut rows = if newest_first {
+            sqlx::query(
+                r#"SELECT title, link, telegram_id, pub_date, content, chat_id, has_media
+                FROM posts
+                WHERE chat_id = $1 AND (pub_date, telegram_id) < ($2, $3)
+                ORDER BY pub_date DESC, telegram_id DESC
+                LIMIT $4"#,
+            )
+            .bind(ch.id)
+            .bind(before.0)
+            .bind(before.1)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?
+        } else {
+            sqlx::query(
+                r#"SELECT title, link, telegram_id, pub_date, content, chat_id, has_media
+                FROM posts
+                WHERE chat_id = $1 AND (pub_date, telegram_id) > ($2, $3)
+                ORDER BY pub_date, telegram_id
+                LIMIT $4"#,
+            )
+            .bind(ch.id)
+            .bind(after.0)
+            .bind(after.1)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?
+        };
+        if !newest_first {
+            rows.reverse();
+        }
+        let mut posts = Vec::with_capacity(rows.len());
+        rows.into_iter().for_each(|r| {
+            let post = models::Post {
+                title: r.get("title"),
+                link: r.get("link"),
+                telegram_id: r.get("telegram_id"),
+                pub_date: r.get("pub_date"),
+                content: r.get("content"),
+                has_media: r.get("has_media"),
+                chat_id: r.get("chat_id"),
+            };
+            posts.push(post);
+        });
+        Ok(Some((ch, posts)))
+    }
+
+    async fn get_stats(&self) -> anyhow::Result<models::Stats> {
+        Ok(sqlx::query_as!(
+            models::Stats,
+            r#"SELECT
+                (SELECT count(*) FROM users) as "users!",
+                (SELECT count(*) FROM users WHERE enabled) as "enabled_users!",
+                (SELECT count(*) FROM channels) as "channels!",
+                (SELECT count(*) FROM user_channel) as "subscriptions!",
+                (SELECT count(*) FROM posts) as "posts!",
+                (SELECT count(*) FROM posts
+                    WHERE pub_date > extract(epoch FROM now())::integer - 86400) as "posts_last_day!""#
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_due_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::Delivery>> {
+        Ok(sqlx::query_as!(
+            models::Delivery,
+            r#"SELECT d.id, d.user_id, d.chat_id, d.attempts,
+                COALESCE(c.title, '') AS "channel!", p.title,
+                COALESCE(p.link, '') AS "link!", b.text AS "broadcast?"
+            FROM deliveries d
+            INNER JOIN users u
+                ON u.id = d.user_id
+            LEFT JOIN posts p
+                ON p.id = d.post_id
+            LEFT JOIN channels c
+                ON c.id = p.chat_id
+            LEFT JOIN broadcasts b
+                ON b.id = d.broadcast_id
+            WHERE u.enabled
+                AND d.next_attempt_at <= $1
+                AND d.id = (SELECT min(id) FROM deliveries WHERE chat_id = d.chat_id)
+            ORDER BY d.id
+            LIMIT $2"#,
+            now,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn remove_delivery(&self, delivery_id: i64) -> anyhow::Result<()> {
+        sqlx::query!("DELETE FROM deliveries WHERE id = $1", delivery_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn retry_delivery(
+        &self,
+        delivery_id: i64,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"UPDATE deliveries
+            SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+            WHERE id = $1"#,
+            delivery_id,
+            next_attempt_at,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn queue_broadcast(&self, text: &str) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let broadcast_id = sqlx::query_scalar!(
+            "INSERT INTO broadcasts (text) VALUES ($1) RETURNING id",
+            text
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        let res = sqlx::query!(
+            r#"INSERT INTO deliveries (broadcast_id, user_id, chat_id)
+            SELECT $1, id, chat_id FROM users WHERE enabled"#,
+            broadcast_id
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_user_deliveries(&self, user_id: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query!("DELETE FROM deliveries WHERE user_id = $1", user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_collection(
+        &self,
+        user_id: i64,
+        name: &str,
+        token: &str,
+    ) -> anyhow::Result<bool> {
+        let res = sqlx::query!(
+            r#"INSERT INTO collections (user_id, name, token)
+            VALUES ($1, $2, $3)
+            ON CONFLICT(user_id, name) DO NOTHING"#,
+            user_id,
+            name,
+            token,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn delete_collection(&self, user_id: i64, name: &str) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            "SELECT id FROM collections WHERE user_id = $1 AND name = $2",
+            user_id,
+            name
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let collection_id = match rec {
+            None => return Ok(false),
+            Some(rec) => rec.id,
+        };
+        sqlx::query!(
+            "DELETE FROM collection_channel WHERE collection_id = $1",
+            collection_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM collections WHERE id = $1", collection_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn add_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            "SELECT id FROM collections WHERE user_id = $1 AND name = $2",
+            user_id,
+            name
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let collection_id = match rec {
+            None => return Ok(None),
+            Some(rec) => rec.id,
+        };
+        let mut added = vec![];
+        let mut not_subscribed = vec![];
+        for channel_name in channel_names {
+            let res = sqlx::query!(
+                r#"INSERT INTO collection_channel (collection_id, channel_id)
+                SELECT $1, c.id
+                FROM channels c
+                INNER JOIN user_channel uc
+                    ON uc.channel_id = c.id
+                WHERE uc.user_id = $2 AND c.username = $3
+                ON CONFLICT(collection_id, channel_id) DO UPDATE SET channel_id = excluded.channel_id"#,
+                collection_id,
+                user_id,
+                channel_name,
+            )
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                added.push(channel_name.clone());
+            } else {
+                not_subscribed.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok(Some((added, not_subscribed)))
+    }
+
+    async fn remove_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            "SELECT id FROM collections WHERE user_id = $1 AND name = $2",
+            user_id,
+            name
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let collection_id = match rec {
+            None => return Ok(None),
+            Some(rec) => rec.id,
+        };
+        let mut removed = vec![];
+        let mut not_found = vec![];
+        for channel_name in channel_names {
+            let res = sqlx::query!(
+                r#"DELETE FROM collection_channel cc
+                    USING channels c
+                WHERE c.id = cc.channel_id
+                    AND cc.collection_id = $1 AND c.username = $2"#,
+                collection_id,
+                channel_name,
+            )
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                removed.push(channel_name.clone());
+            } else {
+                not_found.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok(Some((removed, not_found)))
+    }
+
+    async fn get_user_collections(&self, user_id: i64) -> anyhow::Result<Vec<models::Collection>> {
+        Ok(sqlx::query_as!(
+            models::Collection,
+            r#"SELECT c.id, c.name, c.token, count(cc.id) as "channels!"
+            FROM collections c
+            LEFT JOIN collection_channel cc
+                ON cc.collection_id = c.id
+            WHERE c.user_id = $1
+            GROUP BY c.id
+            ORDER BY c.name"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_collection_posts(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Collection, Vec<(models::Channel, models::Post)>)>> {
+        let collection = sqlx::query_as!(
+            models::Collection,
+            r#"SELECT c.id, c.name, c.token,
+                (SELECT count(*) FROM collection_channel WHERE collection_id = c.id) as "channels!"
+            FROM collections c
+            WHERE c.token = $1"#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let collection = match collection {
+            None => return Ok(None),
+            Some(collection) => collection,
+        };
+        let rows = sqlx::query!(
+            r#"SELECT ch.id as channel_id, ch.title as channel_title, ch.username,
+                p.title, p.link, p.telegram_id, p.pub_date, p.content, p.chat_id,
+                p.has_media
+            FROM collection_channel cc
+            INNER JOIN channels ch
+                ON ch.id = cc.channel_id
+            INNER JOIN posts p
+                ON p.chat_id = ch.id
+            WHERE cc.collection_id = $1
+            ORDER BY p.pub_date DESC, p.id DESC
+            LIMIT $2"#,
+            collection.id,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let posts = rows
+            .into_iter()
+            .map(|r| {
+                let channel = models::Channel {
+                    id: r.channel_id,
+                    title: r.channel_title,
+                    username: r.username,
+                };
+                let post = models::Post {
+                    title: r.title,
+                    link: r.link,
+                    telegram_id: r.telegram_id,
+                    pub_date: r.pub_date,
+                    content: r.content,
+                    has_media: r.has_media,
+                    chat_id: r.chat_id,
+                };
+                (channel, post)
+            })
+            .collect();
+        Ok(Some((collection, posts)))
+    }
+
+    async fn get_last_post_id(&self) -> anyhow::Result<i32> {
+        let id = sqlx::query_scalar!("SELECT max(id) FROM posts")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id.unwrap_or_default())
+    }
+
+    async fn get_new_posts(
+        &self,
+        channel_ids: &[models::TelegramChatId],
+        after_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::Channel, models::Post)>> {
+        let mut posts = vec![];
+        for channel_id in channel_ids {
+            let rows = sqlx::query!(
+                r#"SELECT p.id, ch.id as channel_id, ch.title as channel_title, ch.username,
+                    p.title, p.link, p.telegram_id, p.pub_date, p.content, p.chat_id,
+                    p.has_media
+                FROM posts p
+                INNER JOIN channels ch
+                    ON ch.id = p.chat_id
+                WHERE p.chat_id = $1 AND p.id > $2
+                ORDER BY p.id
+                LIMIT $3"#,
+                channel_id,
+                after_id,
+                limit,
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            posts.extend(rows.into_iter().map(|r| {
+                let channel = models::Channel {
+                    id: r.channel_id,
+                    title: r.channel_title,
+                    username: r.username,
+                };
+                let post = models::Post {
+                    title: r.title,
+                    link: r.link,
+                    telegram_id: r.telegram_id,
+                    pub_date: r.pub_date,
+                    content: r.content,
+                    has_media: r.has_media,
+                    chat_id: r.chat_id,
+                };
+                (r.id, channel, post)
+            }));
+        }
+        posts.sort_by_key(|(id, ..)| *id);
+        posts.truncate(limit.max(0) as usize);
+        Ok(posts)
+    }
+
+    async fn save_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+        secret: Option<&str>,
+        expires_at: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO websub_subscriptions (channel_id, callback, secret, expires_at)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(channel_id, callback) DO UPDATE
+            SET secret = excluded.secret, expires_at = excluded.expires_at"#,
+            channel_id,
+            callback,
+            secret,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+    ) -> anyhow::Result<bool> {
+        let res = sqlx::query!(
+            "DELETE FROM websub_subscriptions WHERE channel_id = $1 AND callback = $2",
+            channel_id,
+            callback
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn get_websub_subscriptions(
+        &self,
+        channel_id: i64,
+        now: i64,
+    ) -> anyhow::Result<Vec<models::WebSubSubscription>> {
+        Ok(sqlx::query_as!(
+            models::WebSubSubscription,
+            r#"SELECT c.username AS "channel!", s.callback, s.secret, s.expires_at
+            FROM websub_subscriptions s
+            INNER JOIN channels c
+                ON c.id = s.channel_id
+            WHERE s.channel_id = $1 AND s.expires_at > $2
+            ORDER BY s.id"#,
+            channel_id,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn remove_expired_websub_subscriptions(&self, now: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query!(
+            "DELETE FROM websub_subscriptions WHERE expires_at <= $1",
+            now
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn save_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+        secret: &str,
+    ) -> anyhow::Result<models::Webhook> {
+        let (user_id, channel_id) = target.ids();
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            r#"SELECT id FROM webhooks
+            WHERE user_id IS NOT DISTINCT FROM $1
+                AND channel_id IS NOT DISTINCT FROM $2
+                AND url = $3"#,
+            user_id,
+            channel_id,
+            url
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let webhook_id = match rec {
+            Some(rec) => {
+                sqlx::query!(
+                    r#"UPDATE webhooks SET enabled = true, failures = 0, last_error = NULL
+                    WHERE id = $1"#,
+                    rec.id
+                )
+                .execute(&mut tx)
+                .await?;
+                rec.id
+            }
+            None => {
+                sqlx::query!(
+                    r#"INSERT INTO webhooks (user_id, channel_id, url, secret)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id"#,
+                    user_id,
+                    channel_id,
+                    url,
+                    secret
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .id
+            }
+        };
+        let webhook = sqlx::query_as!(
+            models::Webhook,
+            r#"SELECT w.id, w.user_id, c.username AS "channel?", w.url, w.secret, w.enabled,
+                w.failures, w.last_error
+            FROM webhooks w
+            LEFT JOIN channels c
+                ON c.id = w.channel_id
+            WHERE w.id = $1"#,
+            webhook_id
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(webhook)
+    }
+
+    async fn remove_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+    ) -> anyhow::Result<bool> {
+        let (user_id, channel_id) = target.ids();
+        let mut tx = self.pool.begin().await?;
+        let rec = sqlx::query!(
+            r#"SELECT id FROM webhooks
+            WHERE user_id IS NOT DISTINCT FROM $1
+                AND channel_id IS NOT DISTINCT FROM $2
+                AND url = $3"#,
+            user_id,
+            channel_id,
+            url
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let webhook_id = match rec {
+            None => return Ok(false),
+            Some(rec) => rec.id,
+        };
+        sqlx::query!(
+            "DELETE FROM webhook_deliveries WHERE webhook_id = $1",
+            webhook_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM webhooks WHERE id = $1", webhook_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_webhooks(
+        &self,
+        target: Option<models::WebhookTarget>,
+    ) -> anyhow::Result<Vec<models::Webhook>> {
+        let (user_id, channel_id) = target.map(|t| t.ids()).unwrap_or_default();
+        Ok(sqlx::query_as!(
+            models::Webhook,
+            r#"SELECT w.id, w.user_id, c.username AS "channel?", w.url, w.secret, w.enabled,
+                w.failures, w.last_error
+            FROM webhooks w
+            LEFT JOIN channels c
+                ON c.id = w.channel_id
+            WHERE ($1::bigint IS NULL OR w.user_id = $1)
+                AND ($2::bigint IS NULL OR w.channel_id = $2)
+            ORDER BY w.id"#,
+            user_id,
+            channel_id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::WebhookDelivery>> {
+        Ok(sqlx::query_as!(
+            models::WebhookDelivery,
+            r#"SELECT d.id, d.webhook_id, w.user_id, w.url, w.secret, d.attempts,
+                c.id AS "channel_id!", c.username AS "channel!", c.title AS "channel_title!",
+                p.telegram_id, p.title, p.link, p.pub_date, p.content, p.has_media, d.event
+            FROM webhook_deliveries d
+            INNER JOIN webhooks w
+                ON w.id = d.webhook_id
+            INNER JOIN posts p
+                ON p.id = d.post_id
+            INNER JOIN channels c
+                ON c.id = p.chat_id
+            WHERE w.enabled AND d.next_attempt_at <= $1
+            ORDER BY d.id
+            LIMIT $2"#,
+            now,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn complete_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM webhook_deliveries WHERE id = $1", delivery_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("UPDATE webhooks SET failures = 0 WHERE id = $1", webhook_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> anyhow::Result<i32> {
+        let mut tx = self.pool.begin().await?;
+        match next_attempt_at {
+            Some(next_attempt_at) => {
+                sqlx::query!(
+                    r#"UPDATE webhook_deliveries
+                    SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+                    WHERE id = $1"#,
+                    delivery_id,
+                    next_attempt_at,
+                    error
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM webhook_deliveries WHERE id = $1", delivery_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        let rec = sqlx::query!(
+            r#"UPDATE webhooks SET failures = failures + 1, last_error = $2
+            WHERE id = $1
+            RETURNING failures"#,
+            webhook_id,
+            error
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(rec.failures)
+    }
+
+    async fn disable_webhook(&self, webhook_id: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM webhook_deliveries WHERE webhook_id = $1",
+            webhook_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE webhooks SET enabled = false WHERE id = $1",
+            webhook_id
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+async fn record_state(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    enabled: bool,
+    reason: models::StateReason,
+) -> anyhow::Result<()> {
+    let reason: &str = reason.into();
+    sqlx::query!(
+        "INSERT INTO user_state_changes (user_id, enabled, reason) VALUES ($1, $2, $3)",
+        user_id,
+        enabled,
+        reason
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
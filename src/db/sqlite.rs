@@ -0,0 +1,1248 @@
+use super::{check_schema_version, page_bounds, Storage};
+use crate::models;
+use crate::settings::DbSettings;
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::{Row, Sqlite, Transaction};
+use std::str::FromStr;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// SQLite storage for personal deployments. Queries are checked at runtime,
+/// since the compile-time checked macros are bound to the postgres database.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(settings: &DbSettings) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(&settings.path)?.create_if_missing(true);
+        let pool = if settings.path.contains(":memory:") {
+            // every connection gets its own in-memory database, so keep the only one forever
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(settings.max_connections)
+        }
+        .connect_with(options)
+        .await?;
+        let tracked: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+        )
+        .fetch_one(&pool)
+        .await?;
+        check_schema_version(&mut *pool.acquire().await?, &MIGRATOR, tracked).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn save_user(&self, user: models::NewUser) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let before: Option<bool> = sqlx::query_scalar("SELECT enabled FROM users WHERE id = ?1")
+            .bind(user.user_id)
+            .fetch_optional(&mut tx)
+            .await?;
+        let enabled: bool = sqlx::query_scalar(
+            r#"INSERT INTO users (id, enabled, chat_id)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(id) DO UPDATE SET enabled = excluded.enabled AND NOT users.banned
+            RETURNING enabled"#,
+        )
+        .bind(user.user_id)
+        .bind(user.enabled)
+        .bind(user.chat_id)
+        .fetch_one(&mut tx)
+        .await?;
+        if before != Some(enabled) {
+            record_state(&mut tx, user.user_id, enabled, user.reason).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: i64) -> anyhow::Result<Option<models::User>> {
+        Ok(sqlx::query_as::<_, models::User>(
+            "SELECT id, enabled, banned, allowed, chat_id FROM users WHERE id = ?1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_users(&self) -> anyhow::Result<Vec<models::User>> {
+        Ok(sqlx::query_as::<_, models::User>(
+            "SELECT id, enabled, banned, allowed, chat_id FROM users ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn set_user_enabled(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        reason: models::StateReason,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let before: Option<bool> = sqlx::query_scalar("SELECT enabled FROM users WHERE id = ?1")
+            .bind(user_id)
+            .fetch_optional(&mut tx)
+            .await?;
+        let before = match before {
+            None => return Ok(false),
+            Some(before) => before,
+        };
+        sqlx::query("UPDATE users SET enabled = ?2 WHERE id = ?1")
+            .bind(user_id)
+            .bind(enabled)
+            .execute(&mut tx)
+            .await?;
+        if before != enabled {
+            record_state(&mut tx, user_id, enabled, reason).await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn ban_user(&self, user_id: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // users chat with the bot privately, so the chat id is the user id
+        sqlx::query(
+            r#"INSERT INTO users (id, enabled, banned, chat_id)
+            VALUES (?1, false, true, ?1)
+            ON CONFLICT(id) DO UPDATE SET enabled = false, banned = true, allowed = false"#,
+        )
+        .bind(user_id)
+        .execute(&mut tx)
+        .await?;
+        record_state(&mut tx, user_id, false, models::StateReason::Banned).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_user_history(&self, user_id: i64) -> anyhow::Result<Vec<models::UserStateChange>> {
+        Ok(sqlx::query_as::<_, models::UserStateChange>(
+            r#"SELECT enabled, reason, changed_at
+            FROM user_state_changes
+            WHERE user_id = ?1
+            ORDER BY id"#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn allow_user(&self, user_id: i64, chat_id: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO users (id, enabled, allowed, chat_id)
+            VALUES (?1, false, true, ?2)
+            ON CONFLICT(id) DO UPDATE SET allowed = true"#,
+        )
+        .bind(user_id)
+        .bind(chat_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_channel(&self, channel: models::NewChannel) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO channels (id, title, username)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(username) DO UPDATE SET title = excluded.title, id=excluded.id"#,
+        )
+        .bind(channel.telegram_id)
+        .bind(channel.title)
+        .bind(channel.username)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_channels(&self) -> anyhow::Result<Vec<(models::Channel, i64)>> {
+        let rows = sqlx::query(
+            r#"SELECT c.id, c.title, c.username, count(uc.id) as subscribers
+            FROM channels c
+            LEFT JOIN user_channel uc
+                ON uc.channel_id = c.id
+            GROUP BY c.id
+            ORDER BY c.username"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let channel = models::Channel {
+                    id: r.get("id"),
+                    title: r.get("title"),
+                    username: r.get("username"),
+                };
+                (channel, r.get("subscribers"))
+            })
+            .collect())
+    }
+
+    async fn remove_channel(&self, channel_name: &str) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let channel_id: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM channels WHERE username = ?1")
+                .bind(channel_name)
+                .fetch_optional(&mut tx)
+                .await?;
+        let channel_id = match channel_id {
+            None => return Ok(false),
+            Some(channel_id) => channel_id,
+        };
+        sqlx::query("DELETE FROM user_channel WHERE channel_id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM collection_channel WHERE channel_id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM websub_subscriptions WHERE channel_id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            r#"DELETE FROM webhook_deliveries
+            WHERE post_id IN (SELECT id FROM posts WHERE chat_id = ?1)
+                OR webhook_id IN (SELECT id FROM webhooks WHERE channel_id = ?1)"#,
+        )
+        .bind(channel_id)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM webhooks WHERE channel_id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM deliveries WHERE post_id IN (SELECT id FROM posts WHERE chat_id = ?1)",
+        )
+        .bind(channel_id)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM posts WHERE chat_id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM channels WHERE id = ?1")
+            .bind(channel_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn add_user_channels(
+        &self,
+        user_id: i64,
+        channels: &[models::NewChannel],
+        limits: models::SubscriptionLimits,
+    ) -> anyhow::Result<models::AddedChannels> {
+        let mut result = models::AddedChannels::default();
+        let mut tx = self.pool.begin().await?;
+        let (mut subscriptions, mut total): (i64, i64) = sqlx::query_as(
+            r#"SELECT
+                (SELECT count(*) FROM user_channel WHERE user_id = ?1),
+                (SELECT count(*) FROM channels)"#,
+        )
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+        for channel in channels {
+            let existing: Option<(i64, Option<i64>)> = sqlx::query_as(
+                r#"SELECT c.id, uc.id
+                FROM channels c
+                LEFT JOIN user_channel uc
+                    ON uc.channel_id = c.id AND uc.user_id = ?1
+                WHERE c.username = ?2"#,
+            )
+            .bind(user_id)
+            .bind(&channel.username)
+            .fetch_optional(&mut tx)
+            .await?;
+            if matches!(existing, Some((_, Some(_)))) {
+                result.already_present.push(channel.username.clone());
+                continue;
+            }
+            if limits.exceeded(subscriptions, total, existing.is_some()) {
+                result.over_limit.push(channel.username.clone());
+                continue;
+            }
+            sqlx::query(
+                r#"INSERT INTO channels (id, title, username)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT(username) DO UPDATE SET title = excluded.title, id=excluded.id"#,
+            )
+            .bind(channel.telegram_id)
+            .bind(&channel.title)
+            .bind(&channel.username)
+            .execute(&mut tx)
+            .await?;
+
+            let res = sqlx::query(
+                r#"INSERT INTO user_channel (user_id, channel_id)
+                VALUES (?1, ?2)
+                ON CONFLICT(user_id, channel_id) DO NOTHING"#,
+            )
+            .bind(user_id)
+            .bind(channel.telegram_id)
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                subscriptions += 1;
+                result.added.push(channel.username.clone());
+            } else {
+                result.already_present.push(channel.username.clone());
+            }
+            if existing.is_none() {
+                total += 1;
+            }
+        }
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn remove_user_channels(
+        &self,
+        user_id: i64,
+        channel_names: &[String],
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let mut removed = vec![];
+        let mut not_found = vec![];
+        let mut tx = self.pool.begin().await?;
+        for channel_name in channel_names {
+            let res = sqlx::query(
+                r#"DELETE FROM user_channel
+                WHERE user_id = ?1
+                    AND channel_id IN (SELECT id FROM channels WHERE username = ?2)"#,
+            )
+            .bind(user_id)
+            .bind(channel_name)
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                sqlx::query(
+                    r#"DELETE FROM deliveries
+                    WHERE user_id = ?1
+                        AND post_id IN (
+                            SELECT p.id FROM posts p
+                            INNER JOIN channels c ON c.id = p.chat_id
+                            WHERE c.username = ?2
+                        )"#,
+                )
+                .bind(user_id)
+                .bind(channel_name)
+                .execute(&mut tx)
+                .await?;
+                sqlx::query(
+                    r#"DELETE FROM collection_channel
+                    WHERE collection_id IN (SELECT id FROM collections WHERE user_id = ?1)
+                        AND channel_id IN (SELECT id FROM channels WHERE username = ?2)"#,
+                )
+                .bind(user_id)
+                .bind(channel_name)
+                .execute(&mut tx)
+                .await?;
+                removed.push(channel_name.clone());
+            } else {
+                not_found.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok((removed, not_found))
+    }
+
+    async fn save_channel_posts(
+        &self,
+        posts: &[models::Post],
+        deliver: bool,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for p in posts.iter() {
+            // a post already saved, e.g. by a backfill, is neither saved nor delivered again
+            let post_id: Option<i64> = sqlx::query_scalar(
+                r#"INSERT INTO posts
+                    (title, link, telegram_id, pub_date, content, chat_id, has_media)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (chat_id, telegram_id) DO NOTHING
+                RETURNING id"#,
+            )
+            .bind(&p.title)
+            .bind(&p.link)
+            .bind(p.telegram_id)
+            .bind(p.pub_date)
+            .bind(&p.content)
+            .bind(p.chat_id)
+            .bind(p.has_media)
+            .fetch_optional(&mut tx)
+            .await?;
+            let post_id = match post_id {
+                Some(post_id) => post_id,
+                None => continue,
+            };
+            if deliver {
+                sqlx::query(
+                    r#"INSERT INTO deliveries (post_id, user_id, chat_id)
+                    SELECT ?1, u.id, u.chat_id
+                    FROM user_channel uc
+                    INNER JOIN users u
+                        ON u.id = uc.user_id
+                    WHERE uc.channel_id = ?2 AND u.enabled"#,
+                )
+                .bind(post_id)
+                .bind(p.chat_id)
+                .execute(&mut tx)
+                .await?;
+                sqlx::query(
+                    r#"INSERT INTO webhook_deliveries (webhook_id, post_id)
+                    SELECT w.id, ?1
+                    FROM webhooks w
+                    WHERE w.enabled
+                        AND (w.channel_id = ?2 OR w.user_id IN (
+                            SELECT u.id
+                            FROM user_channel uc
+                            INNER JOIN users u
+                                ON u.id = uc.user_id
+                            WHERE uc.channel_id = ?2 AND u.enabled
+                        ))"#,
+                )
+                .bind(post_id)
+                .bind(p.chat_id)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn edit_channel_post(
+        &self,
+        chat_id: models::TelegramChatId,
+        telegram_id: models::TelegramPostId,
+        content: &str,
+        has_media: bool,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let post_id: Option<i64> = sqlx::query_scalar(
+            r#"UPDATE posts SET content = ?3, has_media = ?4
+            WHERE chat_id = ?1 AND telegram_id = ?2
+            RETURNING id"#,
+        )
+        .bind(chat_id)
+        .bind(telegram_id)
+        .bind(content)
+        .bind(has_media)
+        .fetch_optional(&mut tx)
+        .await?;
+        let post_id = match post_id {
+            Some(post_id) => post_id,
+            None => return Ok(false),
+        };
+        sqlx::query(
+            r#"INSERT INTO webhook_deliveries (webhook_id, post_id, event)
+            SELECT w.id, ?1, 'post.edited'
+            FROM webhooks w
+            WHERE w.enabled
+                AND (w.channel_id = ?2 OR w.user_id IN (
+                    SELECT u.id
+                    FROM user_channel uc
+                    INNER JOIN users u
+                        ON u.id = uc.user_id
+                    WHERE uc.channel_id = ?2 AND u.enabled
+                ))"#,
+        )
+        .bind(post_id)
+        .bind(chat_id)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<models::Channel>> {
+        Ok(sqlx::query_as::<_, models::Channel>(
+            "SELECT id, title, username from channels where username = ?1",
+        )
+        .bind(channel_name)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_channel_by_id(
+        &self,
+        channel_id: models::TelegramChatId,
+    ) -> anyhow::Result<Option<models::Channel>> {
+        Ok(sqlx::query_as::<_, models::Channel>(
+            "SELECT id, title, username FROM channels WHERE id = ?1",
+        )
+        .bind(channel_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn get_user_channels(&self, user_id: i64) -> anyhow::Result<(i64, Vec<models::Channel>)> {
+        let chat_id: i64 = sqlx::query_scalar("select chat_id from users where id = ?1")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let channels = sqlx::query_as::<_, models::Channel>(
+            r#"SELECT c.id, c.title, c.username
+             FROM channels c
+             INNER JOIN user_channel uc
+                ON uc.channel_id = c.id
+             WHERE uc.user_id = ?1
+             "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok((chat_id, channels))
+    }
+
+    async fn get_channel_post_ids(
+        &self,
+        chat_id: models::TelegramChatId,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::TelegramPostId)>> {
+        let rows = sqlx::query(
+            r#"SELECT id, telegram_id
+            FROM posts
+            WHERE chat_id = ?1
+            LIMIT ?2"#,
+        )
+        .bind(chat_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| (r.get("id"), r.get("telegram_id")))
+            .collect())
+    }
+
+    async fn get_channel_posts(
+        &self,
+        channel_name: &str,
+        page: models::PostsPage,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Channel, Vec<models::Post>)>> {
+        let ch = match self.get_channel(channel_name).await? {
+            None => return Ok(None),
+            Some(ch) => ch,
+        };
+        let cursor = match page {
+            models::PostsPage::Before(telegram_id) | models::PostsPage::After(telegram_id) => {
+                sqlx::query_scalar::<_, i32>(
+                    "SELECT pub_date FROM posts WHERE chat_id = ?1 AND telegram_id = ?2",
+                )
+                .bind(ch.id)
+                .bind(telegram_id)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|pub_date| (pub_date, telegram_id))
+            }
+            models::PostsPage::Latest | models::PostsPage::Oldest => None,
+        };
+        let (before, after) = match page_bounds(page, cursor) {
+            None => return Ok(Some((ch, vec![]))),
+            Some(bounds) => bounds,
+        };
+        let newest_first = matches!(
+            page,
+            models::PostsPage::Latest | models::PostsPage::Before(_)
+        );
+        let posts = if newest_first {
+            sqlx::query_as::<_, models::Post>(
+                r#"SELECT title, link, telegram_id, pub_date, content, chat_id, has_media
+                FROM posts
+                WHERE chat_id = ?1 AND (pub_date, telegram_id) < (?2, ?3)
+                ORDER BY pub_date DESC, telegram_id DESC
+                LIMIT ?4"#,
+            )
+            .bind(ch.id)
+            .bind(before.0)
+            .bind(before.1)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?
+        } else {
+            let mut posts = sqlx::query_as::<_, models::Post>(
+                r#"SELECT title, link, telegram_id, pub_date, content, chat_id, has_media
+                FROM posts
+                WHERE chat_id = ?1 AND (pub_date, telegram_id) > (?2, ?3)
+                ORDER BY pub_date, telegram_id
+                LIMIT ?4"#,
+            )
+            .bind(ch.id)
+            .bind(after.0)
+            .bind(after.1)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            posts.reverse();
+            posts
+        };
+        Ok(Some((ch, posts)))
+    }
+
+    async fn get_stats(&self) -> anyhow::Result<models::Stats> {
+        Ok(sqlx::query_as::<_, models::Stats>(
+            r#"SELECT
+                (SELECT count(*) FROM users) as users,
+                (SELECT count(*) FROM users WHERE enabled) as enabled_users,
+                (SELECT count(*) FROM channels) as channels,
+                (SELECT count(*) FROM user_channel) as subscriptions,
+                (SELECT count(*) FROM posts) as posts,
+                (SELECT count(*) FROM posts
+                    WHERE pub_date > CAST(strftime('%s', 'now') AS integer) - 86400) as posts_last_day"#,
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_due_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::Delivery>> {
+        Ok(sqlx::query_as::<_, models::Delivery>(
+            r#"SELECT d.id, d.user_id, d.chat_id, d.attempts,
+                COALESCE(c.title, '') AS channel, p.title, COALESCE(p.link, '') AS link,
+                b.text AS broadcast
+            FROM deliveries d
+            INNER JOIN users u
+                ON u.id = d.user_id
+            LEFT JOIN posts p
+                ON p.id = d.post_id
+            LEFT JOIN channels c
+                ON c.id = p.chat_id
+            LEFT JOIN broadcasts b
+                ON b.id = d.broadcast_id
+            WHERE u.enabled
+                AND d.next_attempt_at <= ?1
+                AND d.id = (SELECT min(id) FROM deliveries WHERE chat_id = d.chat_id)
+            ORDER BY d.id
+            LIMIT ?2"#,
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn remove_delivery(&self, delivery_id: i64) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM deliveries WHERE id = ?1")
+            .bind(delivery_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn retry_delivery(
+        &self,
+        delivery_id: i64,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE deliveries
+            SET attempts = attempts + 1, next_attempt_at = ?2, last_error = ?3
+            WHERE id = ?1"#,
+        )
+        .bind(delivery_id)
+        .bind(next_attempt_at)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn queue_broadcast(&self, text: &str) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let broadcast_id: i64 =
+            sqlx::query_scalar("INSERT INTO broadcasts (text) VALUES (?1) RETURNING id")
+                .bind(text)
+                .fetch_one(&mut tx)
+                .await?;
+        let res = sqlx::query(
+            r#"INSERT INTO deliveries (broadcast_id, user_id, chat_id)
+            SELECT ?1, id, chat_id FROM users WHERE enabled"#,
+        )
+        .bind(broadcast_id)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_user_deliveries(&self, user_id: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query("DELETE FROM deliveries WHERE user_id = ?1")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_collection(
+        &self,
+        user_id: i64,
+        name: &str,
+        token: &str,
+    ) -> anyhow::Result<bool> {
+        let res = sqlx::query(
+            r#"INSERT INTO collections (user_id, name, token)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(user_id, name) DO NOTHING"#,
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(token)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn delete_collection(&self, user_id: i64, name: &str) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let collection_id: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM collections WHERE user_id = ?1 AND name = ?2")
+                .bind(user_id)
+                .bind(name)
+                .fetch_optional(&mut tx)
+                .await?;
+        let collection_id = match collection_id {
+            None => return Ok(false),
+            Some(collection_id) => collection_id,
+        };
+        sqlx::query("DELETE FROM collection_channel WHERE collection_id = ?1")
+            .bind(collection_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM collections WHERE id = ?1")
+            .bind(collection_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn add_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let mut tx = self.pool.begin().await?;
+        let collection_id: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM collections WHERE user_id = ?1 AND name = ?2")
+                .bind(user_id)
+                .bind(name)
+                .fetch_optional(&mut tx)
+                .await?;
+        let collection_id = match collection_id {
+            None => return Ok(None),
+            Some(collection_id) => collection_id,
+        };
+        let mut added = vec![];
+        let mut not_subscribed = vec![];
+        for channel_name in channel_names {
+            let res = sqlx::query(
+                r#"INSERT INTO collection_channel (collection_id, channel_id)
+                SELECT ?1, c.id
+                FROM channels c
+                INNER JOIN user_channel uc
+                    ON uc.channel_id = c.id
+                WHERE uc.user_id = ?2 AND c.username = ?3
+                ON CONFLICT(collection_id, channel_id) DO UPDATE SET channel_id = excluded.channel_id"#,
+            )
+            .bind(collection_id)
+            .bind(user_id)
+            .bind(channel_name)
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                added.push(channel_name.clone());
+            } else {
+                not_subscribed.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok(Some((added, not_subscribed)))
+    }
+
+    async fn remove_collection_channels(
+        &self,
+        user_id: i64,
+        name: &str,
+        channel_names: &[String],
+    ) -> anyhow::Result<Option<(Vec<String>, Vec<String>)>> {
+        let mut tx = self.pool.begin().await?;
+        let collection_id: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM collections WHERE user_id = ?1 AND name = ?2")
+                .bind(user_id)
+                .bind(name)
+                .fetch_optional(&mut tx)
+                .await?;
+        let collection_id = match collection_id {
+            None => return Ok(None),
+            Some(collection_id) => collection_id,
+        };
+        let mut removed = vec![];
+        let mut not_found = vec![];
+        for channel_name in channel_names {
+            let res = sqlx::query(
+                r#"DELETE FROM collection_channel
+                WHERE collection_id = ?1
+                    AND channel_id IN (SELECT id FROM channels WHERE username = ?2)"#,
+            )
+            .bind(collection_id)
+            .bind(channel_name)
+            .execute(&mut tx)
+            .await?;
+            if res.rows_affected() > 0 {
+                removed.push(channel_name.clone());
+            } else {
+                not_found.push(channel_name.clone());
+            }
+        }
+        tx.commit().await?;
+        Ok(Some((removed, not_found)))
+    }
+
+    async fn get_user_collections(&self, user_id: i64) -> anyhow::Result<Vec<models::Collection>> {
+        Ok(sqlx::query_as::<_, models::Collection>(
+            r#"SELECT c.id, c.name, c.token, count(cc.id) AS channels
+            FROM collections c
+            LEFT JOIN collection_channel cc
+                ON cc.collection_id = c.id
+            WHERE c.user_id = ?1
+            GROUP BY c.id
+            ORDER BY c.name"#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_collection_posts(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> anyhow::Result<Option<(models::Collection, Vec<(models::Channel, models::Post)>)>> {
+        let collection = sqlx::query_as::<_, models::Collection>(
+            r#"SELECT c.id, c.name, c.token,
+                (SELECT count(*) FROM collection_channel WHERE collection_id = c.id) AS channels
+            FROM collections c
+            WHERE c.token = ?1"#,
+        )
+        .bind(token)
+        .fetch_optional(&self.pool)
+        .await?;
+        let collection = match collection {
+            None => return Ok(None),
+            Some(collection) => collection,
+        };
+        let rows = sqlx::query(
+            r#"SELECT ch.id AS channel_id, ch.title AS channel_title, ch.username,
+                p.title, p.link, p.telegram_id, p.pub_date, p.content, p.chat_id,
+                p.has_media
+            FROM collection_channel cc
+            INNER JOIN channels ch
+                ON ch.id = cc.channel_id
+            INNER JOIN posts p
+                ON p.chat_id = ch.id
+            WHERE cc.collection_id = ?1
+            ORDER BY p.pub_date DESC, p.id DESC
+            LIMIT ?2"#,
+        )
+        .bind(collection.id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        let posts = rows
+            .into_iter()
+            .map(|r| {
+                let channel = models::Channel {
+                    id: r.get("channel_id"),
+                    title: r.get("channel_title"),
+                    username: r.get("username"),
+                };
+                let post = models::Post {
+                    title: r.get("title"),
+                    link: r.get("link"),
+                    telegram_id: r.get("telegram_id"),
+                    pub_date: r.get("pub_date"),
+                    content: r.get("content"),
+                    has_media: r.get("has_media"),
+                    chat_id: r.get("chat_id"),
+                };
+                (channel, post)
+            })
+            .collect();
+        Ok(Some((collection, posts)))
+    }
+
+    async fn get_last_post_id(&self) -> anyhow::Result<i32> {
+        let id: Option<i32> = sqlx::query_scalar("SELECT max(id) FROM posts")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id.unwrap_or_default())
+    }
+
+    async fn get_new_posts(
+        &self,
+        channel_ids: &[models::TelegramChatId],
+        after_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(i32, models::Channel, models::Post)>> {
+        let mut posts = vec![];
+        for channel_id in channel_ids {
+            let rows = sqlx::query(
+                r#"SELECT p.id, ch.id AS channel_id, ch.title AS channel_title, ch.username,
+                    p.title, p.link, p.telegram_id, p.pub_date, p.content, p.chat_id,
+                    p.has_media
+                FROM posts p
+                INNER JOIN channels ch
+                    ON ch.id = p.chat_id
+                WHERE p.chat_id = ?1 AND p.id > ?2
+                ORDER BY p.id
+                LIMIT ?3"#,
+            )
+            .bind(channel_id)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            posts.extend(rows.into_iter().map(|r| {
+                let channel = models::Channel {
+                    id: r.get("channel_id"),
+                    title: r.get("channel_title"),
+                    username: r.get("username"),
+                };
+                let post = models::Post {
+                    title: r.get("title"),
+                    link: r.get("link"),
+                    telegram_id: r.get("telegram_id"),
+                    pub_date: r.get("pub_date"),
+                    content: r.get("content"),
+                    has_media: r.get("has_media"),
+                    chat_id: r.get("chat_id"),
+                };
+                (r.get("id"), channel, post)
+            }));
+        }
+        posts.sort_by_key(|(id, ..)| *id);
+        posts.truncate(limit.max(0) as usize);
+        Ok(posts)
+    }
+
+    async fn save_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+        secret: Option<&str>,
+        expires_at: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO websub_subscriptions (channel_id, callback, secret, expires_at)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(channel_id, callback) DO UPDATE
+            SET secret = excluded.secret, expires_at = excluded.expires_at"#,
+        )
+        .bind(channel_id)
+        .bind(callback)
+        .bind(secret)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_websub_subscription(
+        &self,
+        channel_id: i64,
+        callback: &str,
+    ) -> anyhow::Result<bool> {
+        let res =
+            sqlx::query("DELETE FROM websub_subscriptions WHERE channel_id = ?1 AND callback = ?2")
+                .bind(channel_id)
+                .bind(callback)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn get_websub_subscriptions(
+        &self,
+        channel_id: i64,
+        now: i64,
+    ) -> anyhow::Result<Vec<models::WebSubSubscription>> {
+        Ok(sqlx::query_as::<_, models::WebSubSubscription>(
+            r#"SELECT c.username AS channel, s.callback, s.secret, s.expires_at
+            FROM websub_subscriptions s
+            INNER JOIN channels c
+                ON c.id = s.channel_id
+            WHERE s.channel_id = ?1 AND s.expires_at > ?2
+            ORDER BY s.id"#,
+        )
+        .bind(channel_id)
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn remove_expired_websub_subscriptions(&self, now: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query("DELETE FROM websub_subscriptions WHERE expires_at <= ?1")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn save_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+        secret: &str,
+    ) -> anyhow::Result<models::Webhook> {
+        let (user_id, channel_id) = target.ids();
+        let mut tx = self.pool.begin().await?;
+        let webhook_id: Option<i64> = sqlx::query_scalar(
+            "SELECT id FROM webhooks WHERE user_id IS ?1 AND channel_id IS ?2 AND url = ?3",
+        )
+        .bind(user_id)
+        .bind(channel_id)
+        .bind(url)
+        .fetch_optional(&mut tx)
+        .await?;
+        let webhook_id = match webhook_id {
+            Some(webhook_id) => {
+                sqlx::query(
+                    r#"UPDATE webhooks SET enabled = true, failures = 0, last_error = NULL
+                    WHERE id = ?1"#,
+                )
+                .bind(webhook_id)
+                .execute(&mut tx)
+                .await?;
+                webhook_id
+            }
+            None => {
+                sqlx::query_scalar(
+                    r#"INSERT INTO webhooks (user_id, channel_id, url, secret)
+                    VALUES (?1, ?2, ?3, ?4)
+                    RETURNING id"#,
+                )
+                .bind(user_id)
+                .bind(channel_id)
+                .bind(url)
+                .bind(secret)
+                .fetch_one(&mut tx)
+                .await?
+            }
+        };
+        let webhook = sqlx::query_as::<_, models::Webhook>(
+            r#"SELECT w.id, w.user_id, c.username AS channel, w.url, w.secret, w.enabled,
+                w.failures, w.last_error
+            FROM webhooks w
+            LEFT JOIN channels c
+                ON c.id = w.channel_id
+            WHERE w.id = ?1"#,
+        )
+        .bind(webhook_id)
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(webhook)
+    }
+
+    async fn remove_webhook(
+        &self,
+        target: models::WebhookTarget,
+        url: &str,
+    ) -> anyhow::Result<bool> {
+        let (user_id, channel_id) = target.ids();
+        let mut tx = self.pool.begin().await?;
+        let webhook_id: Option<i64> = sqlx::query_scalar(
+            "SELECT id FROM webhooks WHERE user_id IS ?1 AND channel_id IS ?2 AND url = ?3",
+        )
+        .bind(user_id)
+        .bind(channel_id)
+        .bind(url)
+        .fetch_optional(&mut tx)
+        .await?;
+        let webhook_id = match webhook_id {
+            None => return Ok(false),
+            Some(webhook_id) => webhook_id,
+        };
+        sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id = ?1")
+            .bind(webhook_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM webhooks WHERE id = ?1")
+            .bind(webhook_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_webhooks(
+        &self,
+        target: Option<models::WebhookTarget>,
+    ) -> anyhow::Result<Vec<models::Webhook>> {
+        let (user_id, channel_id) = target.map(|t| t.ids()).unwrap_or_default();
+        Ok(sqlx::query_as::<_, models::Webhook>(
+            r#"SELECT w.id, w.user_id, c.username AS channel, w.url, w.secret, w.enabled,
+                w.failures, w.last_error
+            FROM webhooks w
+            LEFT JOIN channels c
+                ON c.id = w.channel_id
+            WHERE (?1 IS NULL OR w.user_id = ?1) AND (?2 IS NULL OR w.channel_id = ?2)
+            ORDER BY w.id"#,
+        )
+        .bind(user_id)
+        .bind(channel_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_due_webhook_deliveries(
+        &self,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<models::WebhookDelivery>> {
+        Ok(sqlx::query_as::<_, models::WebhookDelivery>(
+            r#"SELECT d.id, d.webhook_id, w.user_id, w.url, w.secret, d.attempts,
+                c.id AS channel_id, c.username AS channel, c.title AS channel_title,
+                p.telegram_id, p.title, p.link, p.pub_date, p.content, p.has_media, d.event
+            FROM webhook_deliveries d
+            INNER JOIN webhooks w
+                ON w.id = d.webhook_id
+            INNER JOIN posts p
+                ON p.id = d.post_id
+            INNER JOIN channels c
+                ON c.id = p.chat_id
+            WHERE w.enabled AND d.next_attempt_at <= ?1
+            ORDER BY d.id
+            LIMIT ?2"#,
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn complete_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = ?1")
+            .bind(delivery_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE webhooks SET failures = 0 WHERE id = ?1")
+            .bind(webhook_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn fail_webhook_delivery(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> anyhow::Result<i32> {
+        let mut tx = self.pool.begin().await?;
+        match next_attempt_at {
+            Some(next_attempt_at) => {
+                sqlx::query(
+                    r#"UPDATE webhook_deliveries
+                    SET attempts = attempts + 1, next_attempt_at = ?2, last_error = ?3
+                    WHERE id = ?1"#,
+                )
+                .bind(delivery_id)
+                .bind(next_attempt_at)
+                .bind(error)
+                .execute(&mut tx)
+                .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM webhook_deliveries WHERE id = ?1")
+                    .bind(delivery_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        let failures: i32 = sqlx::query_scalar(
+            r#"UPDATE webhooks SET failures = failures + 1, last_error = ?2
+            WHERE id = ?1
+            RETURNING failures"#,
+        )
+        .bind(webhook_id)
+        .bind(error)
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(failures)
+    }
+
+    async fn disable_webhook(&self, webhook_id: i64) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id = ?1")
+            .bind(webhook_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE webhooks SET enabled = false WHERE id = ?1")
+            .bind(webhook_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+async fn record_state(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: i64,
+    enabled: bool,
+    reason: models::StateReason,
+) -> anyhow::Result<()> {
+    let reason: &str = reason.into();
+    sqlx::query("INSERT INTO user_state_changes (user_id, enabled, reason) VALUES (?1, ?2, ?3)")
+        .bind(user_id)
+        .bind(enabled)
+        .bind(reason)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
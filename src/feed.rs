@@ -0,0 +1,251 @@
+use crate::{models, opml};
+use rss::extension::atom::{AtomExtensionBuilder, Link};
+use rss::extension::{ExtensionBuilder, ExtensionMap};
+use rss::{ChannelBuilder, GuidBuilder, ItemBuilder, SourceBuilder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+const FEED_HISTORY_NAMESPACE: &str = "http://purl.org/syndication/history/1.0";
+
+/// Links to the other pages of a paged or archived feed (RFC 5005) and to the WebSub hub.
+#[derive(Debug, Default)]
+pub struct History {
+    /// Relations along with the URLs, e.g. `("next", ...)`.
+    pub links: Vec<(&'static str, String)>,
+    /// Marks an archive document, which is not supposed to change.
+    pub archive: bool,
+}
+
+/// A channel as JSON, sent to webhooks and streamed along with its posts.
+#[derive(Debug, Serialize)]
+pub struct JsonChannel<'a> {
+    id: i64,
+    username: &'a str,
+    title: &'a str,
+    url: String,
+    feed_url: String,
+}
+
+impl<'a> JsonChannel<'a> {
+    pub fn new(base_url: &str, channel: &'a models::Channel) -> Self {
+        Self {
+            id: channel.id,
+            username: &channel.username,
+            title: &channel.title,
+            url: format!("https://t.me/{}", channel.username),
+            feed_url: opml::feed_url(base_url, &channel.username),
+        }
+    }
+}
+
+/// A post as JSON, see `JsonChannel`.
+#[derive(Debug, Serialize)]
+pub struct JsonPost<'a> {
+    /// Public id of the message in the channel.
+    id: i64,
+    url: String,
+    title: Option<&'a str>,
+    /// HTML, as in the feeds.
+    content: &'a str,
+    /// Unix time.
+    published_at: i32,
+    /// Where the photos and animations of the post are viewed, they are not saved.
+    media: Vec<String>,
+}
+
+impl<'a> JsonPost<'a> {
+    pub fn new(channel: &models::Channel, p: &'a models::Post) -> Self {
+        let url = link(channel, p);
+        let media = if p.has_media {
+            vec![format!("{url}?single")]
+        } else {
+            vec![]
+        };
+        Self {
+            id: p.telegram_id() >> 20,
+            url,
+            title: p.title().as_deref(),
+            content: p.content(),
+            published_at: p.pub_date(),
+            media,
+        }
+    }
+}
+
+/// Renders posts of the channel as an RSS 2.0 document with the given links.
+pub fn render(channel: &models::Channel, posts: &[models::Post], history: &History) -> String {
+    let items: Vec<_> = posts
+        .iter()
+        .map(|p| item(channel, p).title(p.title().clone()).build())
+        .collect();
+    let links: Vec<_> = history
+        .links
+        .iter()
+        .map(|(rel, href)| {
+            let mut link = Link::default();
+            link.set_rel(*rel);
+            link.set_href(href.as_str());
+            link
+        })
+        .collect();
+    let mut builder = ChannelBuilder::default();
+    builder
+        .title(channel.title.clone())
+        .link(format!("https://t.me/{}", channel.username))
+        .description(format!(
+            "Posts of the telegram channel @{}",
+            channel.username
+        ))
+        .items(items);
+    if !links.is_empty() {
+        builder.atom_ext(Some(AtomExtensionBuilder::default().links(links).build()));
+    }
+    if history.archive {
+        let archive = ExtensionBuilder::default().name("fh:archive").build();
+        let mut extensions = ExtensionMap::new();
+        extensions
+            .entry("fh".to_string())
+            .or_default()
+            .insert("archive".to_string(), vec![archive]);
+        builder
+            .namespaces(BTreeMap::from([(
+                "fh".to_string(),
+                FEED_HISTORY_NAMESPACE.to_string(),
+            )]))
+            .extensions(extensions);
+    }
+    builder.build().to_string()
+}
+
+/// Renders posts of the collection channels merged in the given order as an RSS 2.0 document.
+/// Items are titled with the channel they come from and point to it as their source.
+pub fn render_collection(
+    base_url: &str,
+    collection: &models::Collection,
+    posts: &[(models::Channel, models::Post)],
+) -> String {
+    let items: Vec<_> = posts
+        .iter()
+        .map(|(channel, p)| {
+            let title = match p.title() {
+                Some(title) => format!("{}: {}", channel.title, title),
+                None => channel.title.clone(),
+            };
+            item(channel, p)
+                .title(Some(title))
+                .source(Some(
+                    SourceBuilder::default()
+                        .url(format!("https://t.me/{}", channel.username))
+                        .title(Some(channel.title.clone()))
+                        .build(),
+                ))
+                .build()
+        })
+        .collect();
+    ChannelBuilder::default()
+        .title(collection.name.clone())
+        .link(opml::collection_feed_url(base_url, &collection.token))
+        .description(format!(
+            "Posts of the telegram channels in the collection {}",
+            collection.name
+        ))
+        .items(items)
+        .build()
+        .to_string()
+}
+
+/// Renders posts of the collection as an RSS 2.0 document with an item per day (UTC),
+/// which lists links to the posts of the day. Posts are expected the newest first.
+pub fn render_digest(
+    base_url: &str,
+    collection: &models::Collection,
+    posts: &[(models::Channel, models::Post)],
+) -> String {
+    let feed_url = opml::collection_feed_url(base_url, &collection.token);
+    let mut days: Vec<(time::Date, i32, String)> = vec![];
+    for (channel, p) in posts {
+        let date = match OffsetDateTime::from_unix_timestamp(p.pub_date() as i64) {
+            Ok(dt) => dt.date(),
+            Err(_) => continue,
+        };
+        if days.last().map(|(d, ..)| *d != date).unwrap_or(true) {
+            days.push((date, p.pub_date(), String::new()));
+        }
+        let (_, _, list) = days.last_mut().unwrap();
+        let title = match p.title() {
+            Some(title) => format!("{}: {}", channel.title, title),
+            None => channel.title.clone(),
+        };
+        *list += format!(
+            r#"<li><a href="{}">{}</a></li>"#,
+            quick_xml::escape::escape(&link(channel, p)),
+            quick_xml::escape::escape(&title)
+        )
+        .as_str();
+    }
+    let items: Vec<_> = days
+        .into_iter()
+        .map(|(date, newest, list)| {
+            ItemBuilder::default()
+                .title(Some(format!("{}: {}", collection.name, date)))
+                .link(Some(format!("{feed_url}/digest")))
+                .guid(Some(
+                    GuidBuilder::default()
+                        .value(format!("{}/{}", collection.token, date))
+                        .permalink(false)
+                        .build(),
+                ))
+                .pub_date(pub_date(newest))
+                .description(Some(format!("<ul>{list}</ul>")))
+                .build()
+        })
+        .collect();
+    ChannelBuilder::default()
+        .title(format!("{} digest", collection.name))
+        .link(feed_url)
+        .description(format!(
+            "Daily digest of the telegram channels in the collection {}",
+            collection.name
+        ))
+        .items(items)
+        .build()
+        .to_string()
+}
+
+/// An item of the post without the title, feeds title posts differently.
+fn item(channel: &models::Channel, p: &models::Post) -> ItemBuilder {
+    let mut builder = ItemBuilder::default();
+    builder
+        .link(Some(link(channel, p)))
+        .guid(Some(
+            GuidBuilder::default()
+                .value(format!("{}/{}", channel.username, p.telegram_id()))
+                .permalink(false)
+                .build(),
+        ))
+        .pub_date(pub_date(p.pub_date()))
+        .description(Some(p.content().to_string()));
+    builder
+}
+
+fn link(channel: &models::Channel, p: &models::Post) -> String {
+    if p.link().is_empty() {
+        post_link(&channel.username, p.telegram_id())
+    } else {
+        p.link().to_string()
+    }
+}
+
+/// TDLib message ids are the public ones shifted by 20 bits.
+pub fn post_link(channel_name: &str, telegram_id: models::TelegramPostId) -> String {
+    format!("https://t.me/{}/{}", channel_name, telegram_id >> 20)
+}
+
+fn pub_date(timestamp: i32) -> Option<String> {
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()?
+        .format(&Rfc2822)
+        .ok()
+}
@@ -0,0 +1,248 @@
+use crate::models::PostsPage;
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What is requested rather than the URL, so equivalent requests share an entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FeedKey {
+    /// The page of the channel posts along with the number of the posts if asked for,
+    /// feeds of the default size link to their pages differently.
+    Channel(String, PostsPage, Option<i64>),
+    Archive(String, PostsPage),
+    Collection(String),
+    Digest(String),
+}
+
+/// A rendered feed along with its validators.
+#[derive(Debug)]
+pub struct Feed {
+    pub body: String,
+    /// Strong and quoted, derived from the newest post and the body.
+    pub etag: String,
+    /// Publication time of the newest post, `None` for a feed without posts.
+    pub last_modified: Option<SystemTime>,
+    /// The channel whose posts make up the feed, `None` for feeds merging several channels.
+    channel_id: Option<i64>,
+}
+
+impl Feed {
+    pub fn new(body: String, newest_post: Option<i32>, channel_id: Option<i64>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = format!(
+            "\"{:x}-{:016x}\"",
+            newest_post.unwrap_or_default(),
+            hasher.finish()
+        );
+        Self {
+            body,
+            etag,
+            last_modified: newest_post.map(|t| UNIX_EPOCH + Duration::from_secs(t.max(0) as u64)),
+            channel_id,
+        }
+    }
+
+    /// Whether the client already has this version of the feed. `If-None-Match` takes
+    /// precedence, `If-Modified-Since` is only checked without it (RFC 9110, 13.2.2).
+    pub fn is_not_modified(
+        &self,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> bool {
+        if let Some(tags) = if_none_match {
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+        let since = if_modified_since.and_then(|s| httpdate::parse_http_date(s).ok());
+        match (since, self.last_modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+struct Entry {
+    feed: Arc<Feed>,
+    cached_at: Instant,
+}
+
+struct Inner {
+    /// `None` if caching is disabled.
+    feeds: Option<LruCache<FeedKey, Entry>>,
+    /// Incremented on every invalidation.
+    generation: u64,
+}
+
+/// Rendered feeds, least recently used ones are evicted. Shared by the HTTP handlers
+/// rendering feeds and the storage saving posts, which invalidates them.
+#[derive(Clone)]
+pub struct FeedCache {
+    inner: Arc<Mutex<Inner>>,
+    /// Posts saved by another process, e.g. the backfill command, show up after this.
+    ttl: Duration,
+}
+
+impl FeedCache {
+    /// A `capacity` of 0 disables caching.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                feeds: NonZeroUsize::new(capacity).map(LruCache::new),
+                generation: 0,
+            })),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &FeedKey) -> Option<Arc<Feed>> {
+        let mut inner = self.inner.lock().unwrap();
+        let feeds = inner.feeds.as_mut()?;
+        let cached = feeds
+            .get(key)
+            .map(|e| (e.cached_at.elapsed() < self.ttl, e.feed.clone()));
+        match cached {
+            Some((true, feed)) => Some(feed),
+            Some((false, _)) => {
+                feeds.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// To be passed to `put` along with the feed rendered after the call.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// Caches the feed unless the cache has been invalidated since `generation`,
+    /// the feed may miss the posts saved meanwhile then.
+    pub fn put(&self, key: FeedKey, feed: Feed, generation: u64) -> Arc<Feed> {
+        let feed = Arc::new(feed);
+        let mut inner = self.inner.lock().unwrap();
+        let current = inner.generation == generation;
+        if let (true, Some(feeds)) = (current, inner.feeds.as_mut()) {
+            let entry = Entry {
+                feed: feed.clone(),
+                cached_at: Instant::now(),
+            };
+            feeds.put(key, entry);
+        }
+        feed
+    }
+
+    /// Drops the feeds new posts of the channel show up in: its own one and all the merged ones.
+    pub fn invalidate_channel(&self, channel_id: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        if let Some(feeds) = inner.feeds.as_mut() {
+            let stale: Vec<_> = feeds
+                .iter()
+                .filter(|(_, e)| e.feed.channel_id.map_or(true, |id| id == channel_id))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in stale.iter() {
+                feeds.pop(key);
+            }
+        }
+    }
+
+    /// Drops all the feeds, e.g. once a channel title or a collection changes.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        if let Some(feeds) = inner.feeds.as_mut() {
+            feeds.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn channel_key(name: &str) -> FeedKey {
+        FeedKey::Channel(name.to_string(), PostsPage::Latest, None)
+    }
+
+    fn put(cache: &FeedCache, key: FeedKey, channel_id: Option<i64>) {
+        let feed = Feed::new(format!("{key:?}"), Some(1_700_000_000), channel_id);
+        cache.put(key, feed, cache.generation());
+    }
+
+    #[test]
+    fn posts_invalidate_feeds_they_show_up_in() {
+        let cache = FeedCache::new(10, TTL);
+        put(&cache, channel_key("first"), Some(1));
+        put(&cache, channel_key("second"), Some(2));
+        put(&cache, FeedKey::Digest("token".to_string()), None);
+
+        cache.invalidate_channel(1);
+        assert!(cache.get(&channel_key("first")).is_none());
+        assert!(cache.get(&channel_key("second")).is_some());
+        assert!(cache.get(&FeedKey::Digest("token".to_string())).is_none());
+
+        cache.clear();
+        assert!(cache.get(&channel_key("second")).is_none());
+    }
+
+    #[test]
+    fn feeds_rendered_before_invalidation_are_not_cached() {
+        let cache = FeedCache::new(10, TTL);
+        let generation = cache.generation();
+        let feed = Feed::new("stale".to_string(), None, Some(1));
+        cache.invalidate_channel(1);
+        assert_eq!(
+            cache.put(channel_key("first"), feed, generation).body,
+            "stale"
+        );
+        assert!(cache.get(&channel_key("first")).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_and_expired_feeds_are_evicted() {
+        let cache = FeedCache::new(2, TTL);
+        put(&cache, channel_key("first"), Some(1));
+        put(&cache, channel_key("second"), Some(2));
+        cache.get(&channel_key("first"));
+        put(&cache, channel_key("third"), Some(3));
+        assert!(cache.get(&channel_key("first")).is_some());
+        assert!(cache.get(&channel_key("second")).is_none());
+
+        let cache = FeedCache::new(2, Duration::ZERO);
+        put(&cache, channel_key("first"), Some(1));
+        assert!(cache.get(&channel_key("first")).is_none());
+
+        let disabled = FeedCache::new(0, TTL);
+        put(&disabled, channel_key("first"), Some(1));
+        assert!(disabled.get(&channel_key("first")).is_none());
+    }
+
+    #[test]
+    fn conditional_requests_match_validators() {
+        let feed = Feed::new("body".to_string(), Some(1_700_000_000), Some(1));
+        let etag = feed.etag.clone();
+        assert!(feed.is_not_modified(Some(&etag), None));
+        assert!(feed.is_not_modified(Some(&format!("\"other\", W/{etag}")), None));
+        assert!(feed.is_not_modified(Some("*"), None));
+        // the etag wins over the date
+        assert!(!feed.is_not_modified(Some("\"other\""), Some("Tue, 14 Nov 2023 22:13:20 GMT")));
+
+        assert!(feed.is_not_modified(None, Some("Tue, 14 Nov 2023 22:13:20 GMT")));
+        assert!(!feed.is_not_modified(None, Some("Tue, 14 Nov 2023 22:13:19 GMT")));
+        assert!(!feed.is_not_modified(None, Some("yesterday")));
+        assert!(!feed.is_not_modified(None, None));
+
+        let empty = Feed::new("".to_string(), None, Some(1));
+        assert!(empty.last_modified.is_none());
+        assert!(!empty.is_not_modified(None, Some("Tue, 14 Nov 2023 22:13:20 GMT")));
+    }
+}
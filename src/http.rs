@@ -0,0 +1,828 @@
+use crate::db::DbService;
+use crate::feed_cache::{Feed, FeedCache, FeedKey};
+use crate::models::PostsPage;
+use crate::settings::{FeedsSettings, HttpSettings};
+use crate::stream::Streams;
+use crate::telegram::{ComponentState, Health};
+use crate::websub::{Hub, SubscriptionRequest};
+use crate::{feed, metrics, models, opml};
+use anyhow::Result;
+use axum::extract::{Form, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::sse::{KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use serde::Deserialize;
+use std::future::Future;
+use std::net::SocketAddr;
+
+#[derive(Clone)]
+pub struct HttpState {
+    pub db: DbService,
+    pub health: Health,
+    pub feed_settings: FeedsSettings,
+    pub feeds: FeedCache,
+    /// `None` if WebSub is disabled.
+    pub websub: Option<Hub>,
+    /// `None` if the stream of new posts is disabled.
+    pub stream: Option<Streams>,
+}
+
+/// Posts in a collection feed, the digest covers more to span a few days.
+const COLLECTION_FEED_POSTS: i64 = 50;
+const COLLECTION_DIGEST_POSTS: i64 = 500;
+
+/// Serves HTTP endpoints until `shutdown` completes.
+pub async fn serve<F>(settings: &HttpSettings, state: HttpState, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let addr: SocketAddr = settings.listen.parse()?;
+    let server = axum::Server::try_bind(&addr)?.serve(router(state).into_make_service());
+    log::info!("listening on {}", server.local_addr());
+    server.with_graceful_shutdown(shutdown).await?;
+    Ok(())
+}
+
+fn router(state: HttpState) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/websub", post(websub_hub))
+        .route("/stream", get(stream))
+        .route("/collections/:token", get(collection_feed))
+        .route("/collections/:token/digest", get(collection_digest))
+        .route("/:channel", get(channel_feed))
+        .route("/:channel/archive", get(channel_archive))
+        .with_state(state)
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+/// Alive unless the database is unreachable or a telegram component is stopped for good.
+async fn healthz(State(state): State<HttpState>) -> (StatusCode, String) {
+    let (db_ok, report) = report(&state).await;
+    let stopped = state
+        .health
+        .states()
+        .iter()
+        .any(|(_, s)| *s == ComponentState::Stopped);
+    (status(db_ok && !stopped), report)
+}
+
+/// Ready once both clients are authorized and running and the database is reachable.
+async fn readyz(State(state): State<HttpState>) -> (StatusCode, String) {
+    let (db_ok, report) = report(&state).await;
+    (status(db_ok && state.health.is_running()), report)
+}
+
+/// Accepts (un)subscription requests of WebSub subscribers (WebSub, section 5.1),
+/// the intent is verified with the subscriber afterwards.
+async fn websub_hub(
+    State(state): State<HttpState>,
+    Form(request): Form<SubscriptionRequest>,
+) -> Response {
+    let hub = match &state.websub {
+        Some(hub) => hub,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    match hub.accept(request) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+    }
+}
+
+/// Query of the stream, comma separated channel names or links.
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    channels: String,
+}
+
+/// New posts of the channels as server-sent events, clients passing `Last-Event-ID`
+/// get the posts saved after the one with that id first.
+async fn stream(
+    State(state): State<HttpState>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let streams = match &state.stream {
+        Some(streams) => streams,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let base_url = &state.feed_settings.base_url;
+    let names: Option<Vec<_>> = query
+        .channels
+        .split(',')
+        .map(|name| opml::channel_name_from_link(base_url, name))
+        .collect();
+    let mut names = match names {
+        Some(names) => names,
+        None => {
+            let reason = "channels must be comma separated channel names or links";
+            return (StatusCode::BAD_REQUEST, reason).into_response();
+        }
+    };
+    names.sort();
+    names.dedup();
+    let max_channels = streams.settings().max_channels;
+    if names.len() > max_channels {
+        let reason = format!("at most {max_channels} channels may be streamed at once");
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    let last_event_id = match headers.get("last-event-id") {
+        None => None,
+        Some(id) => match id.to_str().ok().and_then(|id| id.trim().parse().ok()) {
+            Some(id) => Some(id),
+            None => {
+                let reason = "Last-Event-ID must be an id of an event sent before";
+                return (StatusCode::BAD_REQUEST, reason).into_response();
+            }
+        },
+    };
+    let mut channel_ids = vec![];
+    for name in names.iter() {
+        match state.db.get_channel(name).await {
+            Ok(Some(channel)) => channel_ids.push(channel.id),
+            Ok(None) => {
+                return (StatusCode::NOT_FOUND, format!("channel {name} not found")).into_response()
+            }
+            Err(err) => {
+                log::error!("cannot get channel {name}: {err:#}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+    match streams.open(channel_ids, last_event_id).await {
+        Ok(events) => Sse::new(events)
+            .keep_alive(KeepAlive::new().interval(streams.settings().keep_alive()))
+            .into_response(),
+        Err(err) => {
+            log::error!("cannot open stream of {names:?}: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Query of a channel feed, `before` and `after` are telegram ids of posts.
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    limit: Option<i64>,
+    before: Option<i64>,
+    after: Option<i64>,
+}
+
+impl PageQuery {
+    /// Returns `None` if both `before` and `after` are given.
+    fn page(&self, default: PostsPage) -> Option<PostsPage> {
+        match (self.before, self.after) {
+            (None, None) => Some(default),
+            (Some(before), None) => Some(PostsPage::Before(before)),
+            (None, Some(after)) => Some(PostsPage::After(after)),
+            (Some(_), Some(_)) => None,
+        }
+    }
+}
+
+/// A paged feed (RFC 5005, section 3) of `?limit=` posts, the latest ones by default.
+/// Readers walk it with `next` and `previous` links.
+async fn channel_feed(
+    State(state): State<HttpState>,
+    Path(channel_name): Path<String>,
+    Query(query): Query<PageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let page = match query.page(PostsPage::Latest) {
+        Some(page) => page,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let settings = &state.feed_settings;
+    let limit = query.limit.map(|limit| limit.clamp(1, settings.max_items));
+    let key = FeedKey::Channel(channel_name.clone(), page, limit);
+    let (db, websub) = (&state.db, state.websub.is_some());
+    cached_feed(&state, key, &headers, || async move {
+        render_channel_feed(db, settings, websub, &channel_name, page, limit).await
+    })
+    .await
+}
+
+/// Renders the page of the channel feed, `None` if there is no such channel. `limit` is
+/// kept in the links only if the reader has asked for it. The latest page without it is
+/// the topic of the WebSub hub, which is advertised there if `websub` is set.
+pub async fn render_channel_feed(
+    db: &DbService,
+    settings: &FeedsSettings,
+    websub: bool,
+    channel_name: &str,
+    page: PostsPage,
+    limit: Option<i64>,
+) -> Result<Option<Feed>> {
+    let items = limit.unwrap_or(settings.default_items);
+    let found = channel_page(db, channel_name, page, items).await?;
+    Ok(found.map(|(channel, posts, older, newer)| {
+        let feed_url = opml::feed_url(&settings.base_url, &channel.username);
+        let page_url = |cursor: String| match limit {
+            Some(_) if cursor.is_empty() => format!("{feed_url}?limit={items}"),
+            Some(_) => format!("{feed_url}?limit={items}&{cursor}"),
+            None if cursor.is_empty() => feed_url.clone(),
+            None => format!("{feed_url}?{cursor}"),
+        };
+        let mut history = feed::History::default();
+        if websub && page == PostsPage::Latest && limit.is_none() {
+            history
+                .links
+                .push(("hub", opml::websub_hub_url(&settings.base_url)));
+            history.links.push(("self", feed_url.clone()));
+        }
+        history.links.push(("first", page_url(String::new())));
+        if let (true, Some(oldest)) = (older, posts.last()) {
+            history
+                .links
+                .push(("next", page_url(format!("before={}", oldest.telegram_id))));
+            if page == PostsPage::Latest {
+                history.links.push((
+                    "prev-archive",
+                    format!("{feed_url}/archive?before={}", oldest.telegram_id),
+                ));
+            }
+        }
+        if let (true, Some(newest)) = (newer, posts.first()) {
+            history.links.push((
+                "previous",
+                page_url(format!("after={}", newest.telegram_id)),
+            ));
+        }
+        let newest = posts.iter().map(|p| p.pub_date).max();
+        let body = feed::render(&channel, &posts, &history);
+        Feed::new(body, newest, Some(channel.id))
+    }))
+}
+
+/// Archived feeds (RFC 5005, section 4) of `feeds.max_items` posts, which let readers walk
+/// the complete history of the channel. Starts from the oldest posts.
+async fn channel_archive(
+    State(state): State<HttpState>,
+    Path(channel_name): Path<String>,
+    Query(query): Query<PageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let page = match query.page(PostsPage::Oldest) {
+        Some(page) => page,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let settings = &state.feed_settings;
+    let limit = settings.max_items;
+    let key = FeedKey::Archive(channel_name.clone(), page);
+    let db = &state.db;
+    cached_feed(&state, key, &headers, || async move {
+        let found = channel_page(db, &channel_name, page, limit).await?;
+        Ok(found.map(|(channel, posts, older, newer)| {
+            let feed_url = opml::feed_url(&settings.base_url, &channel.username);
+            let mut history = feed::History {
+                links: vec![("current", feed_url.clone())],
+                archive: true,
+            };
+            if let (true, Some(oldest)) = (older, posts.last()) {
+                history.links.push((
+                    "prev-archive",
+                    format!("{feed_url}/archive?before={}", oldest.telegram_id),
+                ));
+            }
+            if let (true, Some(newest)) = (newer, posts.first()) {
+                history.links.push((
+                    "next-archive",
+                    format!("{feed_url}/archive?after={}", newest.telegram_id),
+                ));
+            }
+            let newest = posts.iter().map(|p| p.pub_date).max();
+            let body = feed::render(&channel, &posts, &history);
+            Feed::new(body, newest, Some(channel.id))
+        }))
+    })
+    .await
+}
+
+/// Returns up to `limit` posts of the page, the newest first, along with whether
+/// there are older and newer posts than those.
+async fn channel_page(
+    db: &DbService,
+    channel_name: &str,
+    page: PostsPage,
+    limit: i64,
+) -> Result<Option<(models::Channel, Vec<models::Post>, bool, bool)>> {
+    // one more post tells if there are more in the direction of the page
+    let found = db.get_channel_posts(channel_name, page, limit + 1).await?;
+    Ok(found.map(|(channel, mut posts)| {
+        let more = posts.len() as i64 > limit;
+        let (older, newer) = match page {
+            PostsPage::Latest => (more, false),
+            PostsPage::Before(_) => (more, true),
+            PostsPage::Oldest => (false, more),
+            PostsPage::After(_) => (true, more),
+        };
+        if more {
+            match page {
+                PostsPage::Latest | PostsPage::Before(_) => posts.truncate(limit as usize),
+                PostsPage::Oldest | PostsPage::After(_) => {
+                    posts.remove(0);
+                }
+            }
+        }
+        (channel, posts, older, newer)
+    }))
+}
+
+async fn collection_feed(
+    State(state): State<HttpState>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let key = FeedKey::Collection(token.clone());
+    let (db, base_url) = (&state.db, &state.feed_settings.base_url);
+    cached_feed(&state, key, &headers, || async move {
+        let found = db
+            .get_collection_posts(&token, COLLECTION_FEED_POSTS)
+            .await?;
+        Ok(found.map(|(collection, posts)| {
+            let body = feed::render_collection(base_url, &collection, &posts);
+            Feed::new(body, newest_post(&posts), None)
+        }))
+    })
+    .await
+}
+
+async fn collection_digest(
+    State(state): State<HttpState>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let key = FeedKey::Digest(token.clone());
+    let (db, base_url) = (&state.db, &state.feed_settings.base_url);
+    cached_feed(&state, key, &headers, || async move {
+        let found = db
+            .get_collection_posts(&token, COLLECTION_DIGEST_POSTS)
+            .await?;
+        Ok(found.map(|(collection, posts)| {
+            let body = feed::render_digest(base_url, &collection, &posts);
+            Feed::new(body, newest_post(&posts), None)
+        }))
+    })
+    .await
+}
+
+fn newest_post(posts: &[(models::Channel, models::Post)]) -> Option<i32> {
+    posts.iter().map(|(_, p)| p.pub_date).max()
+}
+
+/// Serves the feed from the cache, rendering it with `render` on a miss. `render` returns
+/// `None` if there is no such feed. Clients which have the feed already get a 304.
+async fn cached_feed<F, Fut>(
+    state: &HttpState,
+    key: FeedKey,
+    headers: &HeaderMap,
+    render: F,
+) -> Response
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<Feed>>>,
+{
+    let feed = match state.feeds.get(&key) {
+        Some(feed) => {
+            metrics::FEED_CACHE.with_label_values(&["hit"]).inc();
+            feed
+        }
+        None => {
+            metrics::FEED_CACHE.with_label_values(&["miss"]).inc();
+            let generation = state.feeds.generation();
+            match render().await {
+                Ok(Some(feed)) => state.feeds.put(key, feed, generation),
+                Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    log::error!("cannot render feed {key:?}: {err:#}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+    };
+    let header_str = |name: &HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+    let not_modified = feed.is_not_modified(
+        header_str(&header::IF_NONE_MATCH),
+        header_str(&header::IF_MODIFIED_SINCE),
+    );
+    let mut validators = vec![(header::ETAG, feed.etag.clone())];
+    if let Some(last_modified) = feed.last_modified {
+        validators.push((
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(last_modified),
+        ));
+    }
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        (
+            [(header::CONTENT_TYPE, "application/rss+xml")],
+            feed.body.clone(),
+        )
+            .into_response()
+    };
+    for (name, value) in validators {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+async fn report(state: &HttpState) -> (bool, String) {
+    let db_ok = match state.db.ping().await {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("database is unreachable: {err}");
+            false
+        }
+    };
+    let mut s = format!("db: {}\n", if db_ok { "ok" } else { "unreachable" });
+    for (component, component_state) in state.health.states() {
+        s += format!("{component}: {component_state}\n").as_str();
+    }
+    (db_ok, s)
+}
+
+fn status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{DbSettings, StreamSettings, WebSubSettings};
+    use crate::telegram::Component;
+    use axum::body::{Body, BoxBody, HttpBody};
+    use axum::http::Request;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+    use tower::ServiceExt;
+
+    async fn state() -> HttpState {
+        let db = DbService::new(&DbSettings {
+            path: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            run_migrations: true,
+        })
+        .await
+        .unwrap();
+        let feeds = FeedCache::new(10, Duration::from_secs(60));
+        let (updates, _) = broadcast::channel(10);
+        let db = db.with_feed_cache(feeds.clone(), updates.clone());
+        let feed_settings = FeedsSettings {
+            base_url: "https://feeds.example.com".to_string(),
+            default_items: 2,
+            max_items: 3,
+        };
+        let hub = Hub::new(db.clone(), WebSubSettings::default(), feed_settings.clone()).unwrap();
+        let streams = Streams::new(
+            db.clone(),
+            StreamSettings {
+                max_channels: 2,
+                // new posts show up without polling
+                poll_interval_ms: 60_000,
+                ..StreamSettings::default()
+            },
+            feed_settings.base_url.clone(),
+            updates,
+        );
+        HttpState {
+            db,
+            health: Health::default(),
+            feed_settings,
+            feeds,
+            websub: Some(hub),
+            stream: Some(streams),
+        }
+    }
+
+    async fn get(state: &HttpState, uri: &str) -> (StatusCode, String) {
+        let (status, _, body) = get_with(state, uri, &[]).await;
+        (status, body)
+    }
+
+    async fn get_with(
+        state: &HttpState,
+        uri: &str,
+        headers: &[(HeaderName, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut req = Request::get(uri);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let resp = router(state.clone())
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Subscribes a user to the rustlang channel and saves a post of it.
+    async fn save_post(state: &HttpState, telegram_id: i64, pub_date: i32) {
+        state
+            .db
+            .save_user(models::NewUser {
+                user_id: 1,
+                chat_id: 1,
+                enabled: true,
+                reason: models::StateReason::Started,
+            })
+            .await
+            .unwrap();
+        let channel = models::NewChannel {
+            telegram_id: -1001,
+            title: "Rust".to_string(),
+            username: "rustlang".to_string(),
+        };
+        state
+            .db
+            .add_user_channels(1, &[channel], models::SubscriptionLimits::default())
+            .await
+            .unwrap();
+        state
+            .db
+            .save_channel_posts(
+                &[models::Post {
+                    title: Some("Release".to_string()),
+                    link: "".to_string(),
+                    telegram_id: telegram_id << 20,
+                    pub_date,
+                    content: "a <b>new</b> release".to_string(),
+                    chat_id: -1001,
+                    has_media: false,
+                }],
+                false,
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ready_once_all_components_are_running() {
+        let state = state().await;
+        state.health.set(Component::Bot, ComponentState::Running);
+        state.health.set(Component::User, ComponentState::Starting);
+        assert_eq!(
+            get(&state, "/readyz").await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "db: ok\nbot: running\nuser: starting\n".to_string()
+            )
+        );
+        assert_eq!(get(&state, "/healthz").await.0, StatusCode::OK);
+
+        state.health.set(Component::User, ComponentState::Running);
+        assert_eq!(get(&state, "/readyz").await.0, StatusCode::OK);
+
+        state.health.set(Component::User, ComponentState::Stopped);
+        assert_eq!(
+            get(&state, "/healthz").await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn unhealthy_without_database() {
+        let state = state().await;
+        state.health.set(Component::Bot, ComponentState::Running);
+        state.db.close().await;
+        let (status, body) = get(&state, "/healthz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.starts_with("db: unreachable\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_rendered() {
+        let state = state().await;
+        state.db.get_users().await.unwrap();
+        let (status, body) = get(&state, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("tgfeed_db_query_duration_seconds_count{query=\"get_users\"}"));
+    }
+
+    #[tokio::test]
+    async fn collection_feeds_are_served_by_token() {
+        let state = state().await;
+        save_post(&state, 1, 1_700_000_000).await;
+        state
+            .db
+            .create_collection(1, "tech", "token")
+            .await
+            .unwrap();
+        state
+            .db
+            .add_collection_channels(1, "tech", &["rustlang".to_string()])
+            .await
+            .unwrap();
+
+        let (status, body) = get(&state, "/collections/token").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<title>Rust: Release</title>"));
+        assert!(body.contains(r#"<source url="https://t.me/rustlang">Rust</source>"#));
+
+        let (status, body) = get(&state, "/collections/token/digest").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<title>tech: 2023-11-14</title>"));
+        assert!(body.contains("https://t.me/rustlang/1"));
+
+        assert_eq!(
+            get(&state, "/collections/missing").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn feeds_are_cached_until_new_posts() {
+        let state = state().await;
+        save_post(&state, 1, 1_700_000_000).await;
+        let (status, headers, body) = get_with(&state, "/rustlang", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("https://t.me/rustlang/1"));
+        assert_eq!(
+            headers[header::LAST_MODIFIED],
+            "Tue, 14 Nov 2023 22:13:20 GMT"
+        );
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+
+        let (status, headers, body) =
+            get_with(&state, "/rustlang", &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers[header::ETAG], etag.as_str());
+        assert!(body.is_empty());
+        let since = (header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(
+            get_with(&state, "/rustlang", &[since]).await.0,
+            StatusCode::NOT_MODIFIED
+        );
+
+        save_post(&state, 2, 1_700_000_100).await;
+        let (status, headers, body) =
+            get_with(&state, "/rustlang", &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("https://t.me/rustlang/2"));
+        assert_ne!(headers[header::ETAG], etag.as_str());
+
+        assert_eq!(get(&state, "/missing").await.0, StatusCode::NOT_FOUND);
+        let (_, body) = get(&state, "/metrics").await;
+        assert!(body.contains("tgfeed_feed_cache_total{result=\"hit\"}"));
+    }
+
+    #[tokio::test]
+    async fn channel_feeds_are_paged_and_archived() {
+        let state = state().await;
+        for id in 1..=5 {
+            save_post(&state, id, 1_700_000_000 + id as i32).await;
+        }
+        let posts = |body: &str| {
+            (1..=5)
+                .rev()
+                .filter(|id| body.contains(&format!("https://t.me/rustlang/{id}<")))
+                .collect::<Vec<_>>()
+        };
+        let id = |id: i64| id << 20;
+
+        let (status, body) = get(&state, "/rustlang").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(posts(&body), vec![5, 4]);
+        assert!(body.contains(r#"href="https://feeds.example.com/websub""#));
+        assert!(body.contains(&format!("rustlang?before={}", id(4))));
+        assert!(body.contains(&format!("rustlang/archive?before={}", id(4))));
+        assert!(!body.contains("rel=\"previous\""));
+
+        // the limit is capped by feeds.max_items
+        let (_, body) = get(&state, "/rustlang?limit=10").await;
+        assert_eq!(posts(&body), vec![5, 4, 3]);
+        // only the latest page without the limit is the websub topic
+        assert!(!body.contains("rel=\"hub\""));
+        assert!(body.contains(&format!("rustlang?limit=3&amp;before={}", id(3))));
+
+        let (_, body) = get(&state, &format!("/rustlang?before={}", id(4))).await;
+        assert_eq!(posts(&body), vec![3, 2]);
+        assert!(body.contains(&format!("rustlang?before={}", id(2))));
+        assert!(body.contains(&format!("rustlang?after={}", id(3))));
+        let (_, body) = get(&state, &format!("/rustlang?after={}", id(1))).await;
+        assert_eq!(posts(&body), vec![3, 2]);
+        assert_eq!(
+            get(&state, "/rustlang?before=1&after=2").await.0,
+            StatusCode::BAD_REQUEST
+        );
+
+        // archives start from the oldest posts
+        let (status, body) = get(&state, "/rustlang/archive").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(posts(&body), vec![3, 2, 1]);
+        assert!(body.contains("<fh:archive"));
+        assert!(body.contains(&format!("rustlang/archive?after={}", id(3))));
+        assert!(!body.contains("prev-archive"));
+        let (_, body) = get(&state, &format!("/rustlang/archive?after={}", id(3))).await;
+        assert_eq!(posts(&body), vec![5, 4]);
+        assert!(body.contains(&format!("rustlang/archive?before={}", id(4))));
+        assert!(!body.contains("next-archive"));
+    }
+
+    #[tokio::test]
+    async fn websub_requests_are_validated() {
+        let state = state().await;
+        let subscribe = |topic: &str, callback: &str| {
+            let form = format!("hub.mode=subscribe&hub.topic={topic}&hub.callback={callback}");
+            let req = Request::post("/websub")
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(form))
+                .unwrap();
+            router(state.clone()).oneshot(req)
+        };
+        let topic = "https%3A%2F%2Ffeeds.example.com%2Frustlang";
+        let callback = "https%3A%2F%2Fsubscriber.example.com%2Fcallback";
+        let resp = subscribe(topic, callback).await;
+        assert_eq!(resp.unwrap().status(), StatusCode::ACCEPTED);
+        let resp = subscribe("https%3A%2F%2Fother.example.com%2Frustlang", callback).await;
+        assert_eq!(resp.unwrap().status(), StatusCode::BAD_REQUEST);
+        // the hub does not make requests to the private network
+        for callback in [
+            "http%3A%2F%2F127.0.0.1%3A9%2Fcallback",
+            "http%3A%2F%2F10.0.0.1%2Fcallback",
+            "http%3A%2F%2Flocalhost%2Fcallback",
+        ] {
+            let resp = subscribe(topic, callback).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{callback}");
+        }
+    }
+
+    /// The next server-sent event along with its data.
+    async fn next_event(body: &mut BoxBody) -> (String, serde_json::Value) {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("no event in time")
+            .unwrap()
+            .unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        let data = event
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let data = serde_json::from_str(data).unwrap();
+        (event, data)
+    }
+
+    #[tokio::test]
+    async fn new_posts_are_streamed_after_the_last_event() {
+        let state = state().await;
+        save_post(&state, 1, 1_700_000_000).await;
+        save_post(&state, 2, 1_700_000_100).await;
+        for (uri, status) in [
+            ("/stream?channels=rustlang,missing", StatusCode::NOT_FOUND),
+            ("/stream?channels=rustlang,bad!", StatusCode::BAD_REQUEST),
+            (
+                "/stream?channels=first,second,third",
+                StatusCode::BAD_REQUEST,
+            ),
+            ("/stream", StatusCode::BAD_REQUEST),
+        ] {
+            assert_eq!(get(&state, uri).await.0, status, "{uri}");
+        }
+        let (status, ..) = get_with(
+            &state,
+            "/stream?channels=rustlang",
+            &[(HeaderName::from_static("last-event-id"), "first")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let req = Request::get("/stream?channels=rustlang,@rustlang")
+            .header("Last-Event-ID", "1")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+        let mut body = resp.into_body();
+
+        // the post after the last event, then the ones saved meanwhile right away
+        let (event, data) = next_event(&mut body).await;
+        assert!(event.contains("event: post\n") && event.contains("id: 2\n"));
+        assert_eq!(data["channel"]["username"], "rustlang");
+        assert_eq!(data["post"]["url"], "https://t.me/rustlang/2");
+        assert_eq!(data["post"]["content"], "a <b>new</b> release");
+        save_post(&state, 3, 1_700_000_200).await;
+        let (event, data) = next_event(&mut body).await;
+        assert!(event.contains("id: 3\n"));
+        assert_eq!(data["post"]["id"], 3);
+    }
+}
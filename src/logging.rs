@@ -0,0 +1,94 @@
+use crate::settings::{LogFormat, LoggingSettings};
+use std::io::{self, Write};
+use std::sync::Arc;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+const REDACTED: &str = "[redacted]";
+
+/// Sets up the global subscriber; records of the `log` crate, e.g. the ones of rust_tdlib
+/// and sqlx, get to it as well, within the current span.
+pub fn init(settings: &LoggingSettings, secrets: Vec<String>) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&settings.level))
+        .unwrap_or_else(|err| {
+            eprintln!("invalid logging.level {}: {err}", settings.level);
+            EnvFilter::new("info")
+        });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(Redacting::new(secrets));
+    let result = match settings.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+    if let Err(err) = result {
+        eprintln!("cannot initialize logging: {err}");
+    }
+}
+
+/// Writes to stderr with the secrets replaced. Every event is written at once,
+/// so a secret is never split between writes.
+struct Redacting {
+    secrets: Arc<Vec<String>>,
+}
+
+impl Redacting {
+    fn new(secrets: Vec<String>) -> Self {
+        Self {
+            secrets: Arc::new(secrets),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Redacting {
+    type Writer = RedactingWriter<io::Stderr>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            secrets: self.secrets.clone(),
+            out: io::stderr(),
+        }
+    }
+}
+
+struct RedactingWriter<W> {
+    secrets: Arc<Vec<String>>,
+    out: W,
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut text = String::from_utf8_lossy(buf).into_owned();
+        for secret in self.secrets.iter() {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        self.out.write_all(text.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut out = vec![];
+        let mut writer = RedactingWriter {
+            secrets: Arc::new(vec!["123:token".to_string()]),
+            out: &mut out,
+        };
+        writer
+            .write_all(b"auth with 123:token, again 123:token\n")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "auth with [redacted], again [redacted]\n"
+        );
+    }
+}
@@ -1,35 +1,186 @@
 mod app;
+mod cli;
 mod db;
+mod feed;
+mod feed_cache;
+mod http;
+mod logging;
+mod metrics;
 pub mod models;
+mod opml;
+mod outbox;
 mod settings;
+mod stream;
 mod telegram;
+mod webhooks;
+mod websub;
 
 extern crate time;
 
 use crate::app::App;
+use clap::Parser;
+use cli::{Cli, Command};
 use db::DbService;
-use settings::Settings;
+use feed_cache::FeedCache;
+use http::HttpState;
+use settings::{LoggingSettings, Settings};
+use std::process::ExitCode;
 use std::time::Duration;
+use stream::Streams;
 use telegram::TelegramService;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, oneshot};
+use webhooks::Webhooks;
+use websub::Hub;
 
 #[tokio::main]
-async fn main() {
-    env_logger::init();
-    let settings = Settings::new().expect("can't get config");
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let settings = match Settings::new(cli.config.as_deref()) {
+        Ok(settings) => settings,
+        Err(err) => {
+            logging::init(&LoggingSettings::default(), vec![]);
+            log::error!("{err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(&settings.logging, settings.secrets());
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(settings).await,
+        Command::Admin(command) => match cli::run(command, settings).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("{err:#}");
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+async fn serve(settings: Settings) -> ExitCode {
     log::info!("initializing database");
-    let db = DbService::new(settings.db.path.as_str())
+    let feeds = FeedCache::new(
+        settings.http.feed_cache_size,
+        settings.http.feed_cache_ttl(),
+    );
+    let (updates, _) = broadcast::channel(settings.websub.updates_queue_size);
+    let db = DbService::new(&settings.db)
         .await
-        .expect("can't connect to db");
+        .expect("can't connect to db")
+        .with_feed_cache(feeds.clone(), updates.clone());
+    let hub = settings.websub.enabled.then(|| {
+        Hub::new(db.clone(), settings.websub.clone(), settings.feeds.clone())
+            .expect("cannot create websub hub")
+    });
+    let hub_task = hub.clone().map(|hub| hub.start(updates.subscribe()));
+    let streams = settings.stream.enabled.then(|| {
+        Streams::new(
+            db.clone(),
+            settings.stream.clone(),
+            settings.feeds.base_url.clone(),
+            updates.clone(),
+        )
+    });
+    let webhooks_task = Webhooks::new(
+        db.clone(),
+        settings.webhooks.clone(),
+        settings.feeds.base_url.clone(),
+    )
+    .expect("cannot create webhooks")
+    .start();
 
     let telegram = TelegramService::new(
-        settings.telegram.api_hash,
-        settings.telegram.api_id,
-        settings.telegram.phone,
-        settings.telegram.bot_token,
+        settings.telegram,
+        settings.ingestion,
+        settings.delivery.clone(),
+        settings.feeds.base_url.clone(),
+        settings.limits.clone(),
     );
 
-    let app = App::new(telegram, db);
-    let waiter = app.start().await.expect("cannot start application");
-    waiter.await;
-    log::info!("finished");
+    // probes and metrics are served while the clients are being authorized as well
+    let (stop_http, http_stopped) = oneshot::channel::<()>();
+    let http_settings = settings.http.clone();
+    let http_state = HttpState {
+        db: db.clone(),
+        health: telegram.health(),
+        feed_settings: settings.feeds,
+        feeds,
+        websub: hub,
+        stream: streams,
+    };
+    let http = tokio::spawn(async move {
+        let shutdown = async {
+            http_stopped.await.ok();
+        };
+        if let Err(err) = http::serve(&http_settings, http_state, shutdown).await {
+            log::error!("http server failed: {err:#}");
+        }
+    });
+
+    let app = App::new(
+        telegram,
+        db,
+        settings.delivery,
+        settings.limits.subscription_limits(),
+        settings.webhooks.max_per_user,
+    );
+    // starting waits for the clients to be authorized, which may take long, so the signals
+    // are listened for from the start; polled first, the handlers are installed before it
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let result = tokio::select! {
+        biased;
+        _ = &mut shutdown => {
+            log::info!("shutting down before the application is started");
+            Ok(Ok(()))
+        }
+        started = app.start() => {
+            let mut waiter = started.expect("cannot start application");
+            tokio::select! {
+                result = &mut waiter => result,
+                _ = &mut shutdown => {
+                    log::info!("shutting down");
+                    app.stop().await;
+                    waiter.await
+                }
+            }
+        }
+    };
+    stop_http.send(()).ok();
+    if let Some(hub_task) = hub_task {
+        hub_task.abort();
+    }
+    webhooks_task.abort();
+    if let Err(err) = http.await {
+        log::error!("http server panicked: {err}");
+    }
+    match result {
+        Ok(Ok(())) => {
+            log::info!("finished");
+            ExitCode::SUCCESS
+        }
+        Ok(Err(err)) => {
+            log::error!("finished with error: {err}");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            log::error!("application panicked: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(err) => {
+            log::error!("cannot listen for SIGTERM: {err}");
+            tokio::signal::ctrl_c().await.ok();
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("got SIGINT"),
+        _ = sigterm.recv() => log::info!("got SIGTERM"),
+    }
 }
@@ -0,0 +1,143 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+pub static UPDATES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_updates_received_total",
+        "TDLib updates received by the client",
+        &["client"]
+    )
+    .unwrap()
+});
+
+pub static POSTS_INGESTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_posts_ingested_total",
+        "Posts saved to the database",
+        &["chat_id"]
+    )
+    .unwrap()
+});
+
+pub static BOT_COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_bot_commands_total",
+        "Commands and messages received by the bot",
+        &["command"]
+    )
+    .unwrap()
+});
+
+pub static DELIVERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_deliveries_total",
+        "Messages sent by the bot",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static FEED_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_feed_cache_total",
+        "Feed requests served from the cache or rendered",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static WEBSUB_PUSHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_websub_pushes_total",
+        "Feeds pushed to WebSub subscribers",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static WEBHOOK_DELIVERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tgfeed_webhook_deliveries_total",
+        "Posts sent to webhooks",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static STREAM_CLIENTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "tgfeed_stream_clients",
+        "Clients connected to the stream of new posts"
+    )
+    .unwrap()
+});
+
+pub static DB_QUERY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "tgfeed_db_query_duration_seconds",
+        "Duration of storage calls",
+        &["query"]
+    )
+    .unwrap()
+});
+
+static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "tgfeed_queue_depth",
+        "Items waiting in the internal queues",
+        &["queue"]
+    )
+    .unwrap()
+});
+
+type DepthFn = Box<dyn Fn() -> Option<usize> + Send>;
+
+/// Queue depths are read on scrape, a queue is forgotten once its function returns `None`.
+static QUEUES: Lazy<Mutex<Vec<(String, DepthFn)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Reports the depth returned by `depth` as the `queue` gauge, replacing the previous one.
+pub fn watch_queue<F>(queue: &str, depth: F)
+where
+    F: Fn() -> Option<usize> + Send + 'static,
+{
+    let mut queues = QUEUES.lock().unwrap();
+    queues.retain(|(name, _)| name != queue);
+    queues.push((queue.to_string(), Box::new(depth)));
+}
+
+/// Reports the number of messages in the channel; the channel is not kept open by that.
+pub fn watch_channel<T: Send + 'static>(queue: &str, sender: &mpsc::Sender<T>) {
+    let sender = sender.downgrade();
+    watch_queue(queue, move || {
+        sender
+            .upgrade()
+            .map(|sender| sender.max_capacity() - sender.capacity())
+    });
+}
+
+/// Renders all the metrics in the prometheus text format.
+pub fn render() -> String {
+    QUEUES
+        .lock()
+        .unwrap()
+        .retain(|(queue, depth)| match depth() {
+            Some(depth) => {
+                QUEUE_DEPTH.with_label_values(&[queue]).set(depth as i64);
+                true
+            }
+            None => {
+                QUEUE_DEPTH.remove_label_values(&[queue]).ok();
+                false
+            }
+        });
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("cannot encode metrics: {err}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
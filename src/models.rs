@@ -9,6 +9,8 @@ pub struct Post {
     pub pub_date: i32,
     pub content: String,
     pub chat_id: TelegramChatId,
+    /// Whether the post has a photo or an animation, they are viewed at the link of the post.
+    pub has_media: bool,
 }
 
 impl Post {
@@ -32,30 +34,68 @@ impl Post {
     }
 }
 
+/// Which posts of a channel to return, the newest by publish date first. Pages are keyed
+/// on telegram ids of the posts, so they stay put while new posts come in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PostsPage {
+    Latest,
+    Oldest,
+    /// Posts older than the given one.
+    Before(TelegramPostId),
+    /// Posts newer than the given one, the closest to it.
+    After(TelegramPostId),
+}
+
 #[derive(Debug)]
 pub struct NewUser {
     pub user_id: i64,
     pub chat_id: i64,
     pub enabled: bool,
+    /// Recorded in the history of the user if `enabled` changes.
+    pub reason: StateReason,
 }
 
-#[derive(Debug)]
-pub struct NewChannel {
-    pub title: String,
-    pub telegram_id: TelegramChatId,
-    pub username: String,
+/// Why a user is enabled or disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum StateReason {
+    /// The user sent /start.
+    Started,
+    /// The user sent /stop.
+    Stopped,
+    /// The user blocked the bot.
+    Blocked,
+    /// The chat with the user is not reachable anymore, e.g. the account is deleted.
+    ChatGone,
+    /// Disabled by an admin.
+    Disabled,
+    Banned,
 }
 
-#[derive(Debug)]
-pub struct NewUserChannel {
-    pub user_id: i64,
-    pub channel_id: i64,
+/// An entry of the history of the user.
+#[derive(Debug, sqlx::FromRow)]
+pub struct UserStateChange {
+    pub enabled: bool,
+    pub reason: String,
+    /// Unix time.
+    pub changed_at: i64,
 }
 
-#[derive(Debug)]
-pub struct RemoveUserChannel {
-    pub user_id: i64,
-    pub channel_name: String,
+#[derive(Debug, sqlx::FromRow)]
+pub struct User {
+    pub id: i64,
+    pub enabled: bool,
+    pub banned: bool,
+    /// Started the bot with an invite code, matters for private instances only.
+    pub allowed: bool,
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewChannel {
+    pub title: String,
+    pub telegram_id: TelegramChatId,
+    pub username: String,
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -65,3 +105,149 @@ pub struct Channel {
     pub username: String,
     // pub telegram_id: TelegramChatId,
 }
+
+/// A named group of channels the user is subscribed to, with feeds of its own.
+#[derive(Debug, sqlx::FromRow)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    /// Identifies the feeds of the collection, so they cannot be guessed.
+    pub token: String,
+    /// Number of the channels in the collection.
+    pub channels: i64,
+}
+
+#[derive(Debug, Default, sqlx::FromRow)]
+pub struct Stats {
+    pub users: i64,
+    pub enabled_users: i64,
+    pub channels: i64,
+    pub subscriptions: i64,
+    pub posts: i64,
+    pub posts_last_day: i64,
+}
+
+/// Limits on subscriptions, 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubscriptionLimits {
+    pub max_channels_per_user: i64,
+    pub max_channels: i64,
+}
+
+impl SubscriptionLimits {
+    /// Whether one more subscription is over the limits, given the current number of
+    /// the user subscriptions and of all the tracked channels.
+    pub fn exceeded(&self, subscriptions: i64, channels: i64, tracked: bool) -> bool {
+        let over = |count: i64, max: i64| max > 0 && count >= max;
+        over(subscriptions, self.max_channels_per_user)
+            || (!tracked && over(channels, self.max_channels))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AddedChannels {
+    pub added: Vec<String>,
+    pub already_present: Vec<String>,
+    pub over_limit: Vec<String>,
+}
+
+/// A WebSub subscriber of the channel feed.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebSubSubscription {
+    /// Username of the channel.
+    pub channel: String,
+    pub callback: String,
+    /// Signs the pushed feeds if set.
+    pub secret: Option<String>,
+    /// Unix time.
+    pub expires_at: i64,
+}
+
+/// Whose posts a webhook gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookTarget {
+    /// Posts of the channels the user is subscribed to, added by the user.
+    User(i64),
+    /// Posts of the channel, added by an admin.
+    Channel(TelegramChatId),
+}
+
+impl WebhookTarget {
+    /// The user id and the channel id, one of them is set.
+    pub fn ids(&self) -> (Option<i64>, Option<TelegramChatId>) {
+        match *self {
+            WebhookTarget::User(user_id) => (Some(user_id), None),
+            WebhookTarget::Channel(channel_id) => (None, Some(channel_id)),
+        }
+    }
+}
+
+/// An HTTP endpoint new posts are sent to.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    /// Username of the channel.
+    pub channel: Option<String>,
+    pub url: String,
+    /// Payloads are signed with it.
+    pub secret: String,
+    /// Disabled after too many failed attempts in a row.
+    pub enabled: bool,
+    pub failures: i32,
+    pub last_error: Option<String>,
+}
+
+/// A post queued for a webhook.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    /// `None` for webhooks of channels, only admins add them.
+    pub user_id: Option<i64>,
+    pub url: String,
+    pub secret: String,
+    /// Failed attempts so far.
+    pub attempts: i32,
+    pub channel_id: TelegramChatId,
+    /// Username of the channel.
+    pub channel: String,
+    pub channel_title: String,
+    pub telegram_id: TelegramPostId,
+    pub title: Option<String>,
+    pub link: String,
+    pub pub_date: i32,
+    pub content: String,
+    pub has_media: bool,
+    /// `post.created` or `post.edited`.
+    pub event: String,
+}
+
+/// A post queued for delivery to a subscriber.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    /// Failed attempts so far.
+    pub attempts: i32,
+    /// Title of the channel, empty for broadcasts.
+    pub channel: String,
+    pub title: Option<String>,
+    /// Empty for broadcasts.
+    pub link: String,
+    /// Text of the broadcast, `None` for posts.
+    pub broadcast: Option<String>,
+}
+
+impl Delivery {
+    pub fn text(&self) -> String {
+        if let Some(text) = &self.broadcast {
+            return text.clone();
+        }
+        match &self.title {
+            Some(title) => format!("{}\n{}\n{}", self.channel, title, self.link),
+            None => format!("{}\n{}", self.channel, self.link),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::models;
+use anyhow::Result;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+const TELEGRAM_HOSTS: [&str; 2] = ["t.me/", "telegram.me/"];
+
+pub fn feed_url(base_url: &str, channel_name: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), channel_name)
+}
+
+/// Collections are identified by their tokens, the digest is served under `/digest` of it.
+pub fn collection_feed_url(base_url: &str, token: &str) -> String {
+    format!("{}/collections/{}", base_url.trim_end_matches('/'), token)
+}
+
+/// The WebSub hub channel feeds are pushed by.
+pub fn websub_hub_url(base_url: &str) -> String {
+    format!("{}/websub", base_url.trim_end_matches('/'))
+}
+
+pub fn render(base_url: &str, channels: &[models::Channel]) -> String {
+    let mut s = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+  <head>
+    <title>tgfeed subscriptions</title>
+  </head>
+  <body>
+"#,
+    );
+    for ch in channels {
+        s += format!(
+            "    <outline type=\"rss\" text=\"{title}\" title=\"{title}\" xmlUrl=\"{xml_url}\" htmlUrl=\"https://t.me/{username}\"/>\n",
+            title = escape(&ch.title),
+            xml_url = escape(&feed_url(base_url, &ch.username)),
+            username = escape(&ch.username),
+        )
+        .as_str();
+    }
+    s += "  </body>\n</opml>\n";
+    s
+}
+
+/// Extracts channel names from an OPML document or from a plain text list of
+/// `t.me` links (one or several per line). Entries which do not look like a
+/// channel are returned as the second element.
+pub fn parse(base_url: &str, content: &str) -> Result<(Vec<String>, Vec<String>)> {
+    if !content.trim_start().starts_with('<') {
+        return Ok(parse_links(base_url, content));
+    }
+
+    let entries = parse_outlines(content)?;
+    let mut channels = Vec::with_capacity(entries.len());
+    let mut invalid = vec![];
+    for links in entries {
+        match links
+            .iter()
+            .find_map(|link| channel_name_from_link(base_url, link))
+        {
+            Some(name) if !channels.contains(&name) => channels.push(name),
+            Some(_) => {}
+            None => invalid.push(links.join(" ")),
+        }
+    }
+    Ok((channels, invalid))
+}
+
+/// Extracts channel names from whitespace separated `@name`, `t.me` links or bare names.
+pub fn parse_links(base_url: &str, content: &str) -> (Vec<String>, Vec<String>) {
+    let mut channels = vec![];
+    let mut invalid = vec![];
+    for link in content.split_whitespace() {
+        match channel_name_from_link(base_url, link) {
+            Some(name) if !channels.contains(&name) => channels.push(name),
+            Some(_) => {}
+            None => invalid.push(link.to_string()),
+        }
+    }
+    (channels, invalid)
+}
+
+pub fn channel_name_from_link(base_url: &str, link: &str) -> Option<String> {
+    let link = link.trim();
+    let base_url = base_url.trim_end_matches('/');
+    let path = if let Some(name) = link.strip_prefix('@') {
+        name
+    } else if !base_url.is_empty() && link.starts_with(base_url) {
+        link[base_url.len()..].trim_start_matches('/')
+    } else {
+        let link = link
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_start_matches("www.");
+        match TELEGRAM_HOSTS.iter().find(|h| link.starts_with(*h)) {
+            Some(host) => {
+                let path = &link[host.len()..];
+                path.strip_prefix("s/").unwrap_or(path)
+            }
+            None => link,
+        }
+    };
+    let name = path
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    if is_valid_channel_name(name) {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+fn is_valid_channel_name(name: &str) -> bool {
+    (5..=32).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+/// Returns the urls of every feed outline; folders (outlines without urls) are skipped.
+fn parse_outlines(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut reader = Reader::from_str(content);
+    reader.trim_text(true);
+    let mut result = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"outline" => {
+                let mut links = vec![];
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    if let b"xmlUrl" | b"htmlUrl" = attr.key.as_ref() {
+                        links.push(attr.unescape_value()?.to_string());
+                    }
+                }
+                if !links.is_empty() {
+                    result.push(links);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,425 @@
+use crate::db::DbService;
+use crate::settings::DeliverySettings;
+use crate::telegram::{
+    BotResponsePost, BotResponses, Delivered, DeliveryOutcome, ServiceResponses,
+};
+use crate::{metrics, models};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// A post whose outcome is not reported in this time is passed to the bot again,
+/// e.g. it could be lost on a restart of the bot.
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Passes queued posts to the bot: one post per chat at a time in the order they are queued,
+/// within the per-chat and overall limits of telegram. Failed posts are retried with a backoff,
+/// users whose chats are gone are disabled.
+pub struct Outbox {
+    db: DbService,
+    settings: DeliverySettings,
+    /// Posts passed to the bot by chat, along with the time they are passed.
+    in_flight: HashMap<i64, (models::Delivery, Instant)>,
+    /// When the last post is sent to the chat, kept for `chat_interval` only.
+    last_sent: HashMap<i64, Instant>,
+}
+
+impl Outbox {
+    pub fn new(db: DbService, settings: DeliverySettings) -> Self {
+        Self {
+            db,
+            settings,
+            in_flight: HashMap::new(),
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Runs until `results` is closed, i.e. until the bot cannot report outcomes anymore.
+    pub fn start(
+        mut self,
+        to_bot: Sender<ServiceResponses>,
+        mut results: UnboundedReceiver<Delivered>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut poll = tokio::time::interval(self.settings.poll_interval());
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => self.dispatch(&to_bot).await,
+                    delivered = results.recv() => match delivered {
+                        Some(delivered) => self.complete(delivered).await,
+                        None => break,
+                    },
+                }
+            }
+            log::info!("outbox stopped");
+        })
+    }
+
+    /// Posts passed to the bot per poll, so that `messages_per_second` is not exceeded.
+    fn budget(&self) -> usize {
+        let per_poll =
+            self.settings.messages_per_second as u64 * self.settings.poll_interval_ms / 1000;
+        per_poll.max(1) as usize
+    }
+
+    async fn dispatch(&mut self, to_bot: &Sender<ServiceResponses>) {
+        let now = Instant::now();
+        let chat_interval = self.settings.chat_interval();
+        self.last_sent.retain(|_, at| now < *at + chat_interval);
+        self.in_flight.retain(|chat_id, (delivery, at)| {
+            let expired = now >= *at + IN_FLIGHT_TIMEOUT;
+            if expired {
+                log::warn!(
+                    "no outcome of delivery {} to chat {chat_id}, passing it again",
+                    delivery.id
+                );
+            }
+            !expired
+        });
+
+        let budget = self.budget();
+        // posts in flight are still queued, so they are returned as well
+        let limit = (budget + self.in_flight.len()) as i64;
+        let due = match self.db.get_due_deliveries(unix_now(), limit).await {
+            Ok(due) => due,
+            Err(err) => {
+                log::error!("cannot get due deliveries: {err:#}");
+                return;
+            }
+        };
+        let mut passed = 0;
+        for delivery in due {
+            if passed >= budget {
+                break;
+            }
+            let chat_id = delivery.chat_id;
+            if self.in_flight.contains_key(&chat_id) || self.last_sent.contains_key(&chat_id) {
+                continue;
+            }
+            let post = BotResponsePost {
+                delivery_id: delivery.id,
+                chat_id,
+                text: delivery.text(),
+            };
+            match to_bot.try_send(ServiceResponses::Bot(BotResponses::Post(post))) {
+                Ok(()) => {
+                    self.in_flight.insert(chat_id, (delivery, now));
+                    passed += 1;
+                }
+                // the bot is busy with replies, the rest waits for the next poll
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+    }
+
+    async fn complete(&mut self, delivered: Delivered) {
+        let delivery = match self.in_flight.remove(&delivered.chat_id) {
+            Some((delivery, _)) if delivery.id == delivered.delivery_id => delivery,
+            in_flight => {
+                // the outcome came after the timeout, the post is handled again already
+                log::debug!("unexpected outcome of delivery {}", delivered.delivery_id);
+                if let Some(in_flight) = in_flight {
+                    self.in_flight.insert(delivered.chat_id, in_flight);
+                }
+                return;
+            }
+        };
+        let result = match delivered.outcome {
+            DeliveryOutcome::Sent => {
+                self.last_sent.insert(delivery.chat_id, Instant::now());
+                self.db.remove_delivery(delivery.id).await
+            }
+            DeliveryOutcome::Failed { error, retry_after } => {
+                let attempts = delivery.attempts + 1;
+                if attempts >= self.settings.max_attempts {
+                    log::warn!(
+                        "dropping delivery {} to chat {} after {attempts} attempts: {error}",
+                        delivery.id,
+                        delivery.chat_id
+                    );
+                    metrics::DELIVERIES.with_label_values(&["dropped"]).inc();
+                    self.db.remove_delivery(delivery.id).await
+                } else {
+                    let delay = self
+                        .settings
+                        .retry_delay(attempts)
+                        .max(retry_after.unwrap_or_default());
+                    log::info!(
+                        "delivery {} to chat {} failed, retrying in {}s: {error}",
+                        delivery.id,
+                        delivery.chat_id,
+                        delay.as_secs()
+                    );
+                    self.db
+                        .retry_delivery(delivery.id, unix_now() + delay.as_secs() as i64, &error)
+                        .await
+                }
+            }
+            DeliveryOutcome::ChatGone(error) => {
+                log::warn!(
+                    "chat {} is gone, disabling user {}: {error}",
+                    delivery.chat_id,
+                    delivery.user_id
+                );
+                let reason = models::StateReason::ChatGone;
+                disable_user(&self.db, delivery.user_id, delivery.chat_id, reason).await
+            }
+            DeliveryOutcome::Blocked(_) => {
+                log::info!("user {} has blocked the bot", delivery.user_id);
+                let reason = models::StateReason::Blocked;
+                disable_user(&self.db, delivery.user_id, delivery.chat_id, reason).await
+            }
+        };
+        if let Err(err) = result {
+            log::error!("cannot update delivery {}: {err:#}", delivery.id);
+        }
+    }
+}
+
+/// Disables the user, posts queued so far are not sent once the user is back.
+pub async fn disable_user(
+    db: &DbService,
+    user_id: i64,
+    chat_id: i64,
+    reason: models::StateReason,
+) -> anyhow::Result<()> {
+    db.save_user(models::NewUser {
+        user_id,
+        chat_id,
+        enabled: false,
+        reason,
+    })
+    .await?;
+    db.remove_user_deliveries(user_id).await?;
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DbSettings;
+    use tokio::sync::mpsc;
+
+    const CHANNEL_ID: i64 = -1001;
+
+    async fn outbox(subscribers: &[i64]) -> Outbox {
+        let db = DbService::new(&DbSettings {
+            path: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            run_migrations: true,
+        })
+        .await
+        .unwrap();
+        for user_id in subscribers {
+            db.save_user(models::NewUser {
+                user_id: *user_id,
+                chat_id: *user_id,
+                enabled: true,
+                reason: models::StateReason::Started,
+            })
+            .await
+            .unwrap();
+            db.add_user_channels(
+                *user_id,
+                &[models::NewChannel {
+                    title: "Rust".to_string(),
+                    telegram_id: CHANNEL_ID,
+                    username: "rustlang".to_string(),
+                }],
+                models::SubscriptionLimits::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let settings = DeliverySettings {
+            chat_interval_ms: 0,
+            max_attempts: 2,
+            ..DeliverySettings::default()
+        };
+        Outbox::new(db, settings)
+    }
+
+    async fn save_posts(outbox: &Outbox, ids: &[i64]) {
+        let posts: Vec<_> = ids
+            .iter()
+            .map(|id| models::Post {
+                title: Some(format!("post {id}")),
+                link: format!("https://t.me/rustlang/{id}"),
+                telegram_id: *id,
+                pub_date: 1_600_000_000,
+                content: "content".to_string(),
+                chat_id: CHANNEL_ID,
+                has_media: false,
+            })
+            .collect();
+        outbox.db.save_channel_posts(&posts, true).await.unwrap();
+    }
+
+    fn posts(to_bot: &mut mpsc::Receiver<ServiceResponses>) -> Vec<BotResponsePost> {
+        let mut posts = vec![];
+        while let Ok(ServiceResponses::Bot(BotResponses::Post(post))) = to_bot.try_recv() {
+            posts.push(post);
+        }
+        posts
+    }
+
+    fn delivered(post: &BotResponsePost, outcome: DeliveryOutcome) -> Delivered {
+        Delivered {
+            delivery_id: post.delivery_id,
+            chat_id: post.chat_id,
+            outcome,
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_are_sent_in_order_one_per_chat() {
+        let mut outbox = outbox(&[100, 101]).await;
+        save_posts(&outbox, &[1, 2]).await;
+        let (tx, mut to_bot) = mpsc::channel(10);
+
+        outbox.dispatch(&tx).await;
+        let first = posts(&mut to_bot);
+        assert_eq!(
+            first.iter().map(|p| p.chat_id).collect::<Vec<_>>(),
+            vec![100, 101]
+        );
+        assert_eq!(
+            first[0].text,
+            "Rust\npost 1\nhttps://t.me/rustlang/1".to_string()
+        );
+        // the second post waits for the outcome of the first one
+        outbox.dispatch(&tx).await;
+        assert!(posts(&mut to_bot).is_empty());
+
+        outbox
+            .complete(delivered(&first[0], DeliveryOutcome::Sent))
+            .await;
+        outbox.dispatch(&tx).await;
+        let second = posts(&mut to_bot);
+        assert_eq!(second.len(), 1);
+        assert_eq!(
+            (second[0].chat_id, second[0].text.contains("post 2")),
+            (100, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_posts_are_retried_then_dropped() {
+        let mut outbox = outbox(&[100]).await;
+        save_posts(&outbox, &[1]).await;
+        let (tx, mut to_bot) = mpsc::channel(10);
+
+        outbox.dispatch(&tx).await;
+        let post = posts(&mut to_bot).pop().unwrap();
+        let failed = || DeliveryOutcome::Failed {
+            error: "Too Many Requests: retry after 7".to_string(),
+            retry_after: Some(Duration::from_secs(7)),
+        };
+        outbox.complete(delivered(&post, failed())).await;
+        // postponed by the larger of the backoff and the flood wait
+        assert!(outbox
+            .db
+            .get_due_deliveries(unix_now(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        let due = outbox
+            .db
+            .get_due_deliveries(unix_now() + 8, 10)
+            .await
+            .unwrap();
+        assert_eq!(due[0].attempts, 1);
+
+        outbox
+            .in_flight
+            .insert(100, (due[0].clone(), Instant::now()));
+        outbox.complete(delivered(&post, failed())).await;
+        assert!(outbox
+            .db
+            .get_due_deliveries(i64::MAX, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_are_disabled_when_chats_are_gone_or_blocked() {
+        let mut outbox = outbox(&[100]).await;
+        save_posts(&outbox, &[1, 2]).await;
+        let (tx, mut to_bot) = mpsc::channel(10);
+
+        outbox.dispatch(&tx).await;
+        let post = posts(&mut to_bot).pop().unwrap();
+        outbox
+            .complete(delivered(
+                &post,
+                DeliveryOutcome::ChatGone("Chat not found".to_string()),
+            ))
+            .await;
+        assert!(!outbox.db.get_user(100).await.unwrap().unwrap().enabled);
+        assert_eq!(outbox.db.remove_user_deliveries(100).await.unwrap(), 0);
+        let history = outbox.db.get_user_history(100).await.unwrap();
+        assert_eq!(history.last().unwrap().reason, "chat_gone");
+
+        // the user is back on /start
+        outbox
+            .db
+            .save_user(models::NewUser {
+                user_id: 100,
+                chat_id: 100,
+                enabled: true,
+                reason: models::StateReason::Started,
+            })
+            .await
+            .unwrap();
+        assert!(outbox.db.get_user(100).await.unwrap().unwrap().enabled);
+        save_posts(&outbox, &[3]).await;
+        outbox.dispatch(&tx).await;
+        let post = posts(&mut to_bot).pop().unwrap();
+        assert!(post.text.contains("post 3"));
+        outbox
+            .complete(delivered(
+                &post,
+                DeliveryOutcome::Blocked("bot was blocked by the user".to_string()),
+            ))
+            .await;
+        let history: Vec<_> = outbox
+            .db
+            .get_user_history(100)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.reason)
+            .collect();
+        assert_eq!(history, vec!["started", "chat_gone", "started", "blocked"]);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_are_sent_like_posts() {
+        let mut outbox = outbox(&[100, 101]).await;
+        let queued = outbox.db.queue_broadcast("maintenance at 10:00").await;
+        assert_eq!(queued.unwrap(), 2);
+        let (tx, mut to_bot) = mpsc::channel(10);
+
+        outbox.dispatch(&tx).await;
+        let sent = posts(&mut to_bot);
+        assert_eq!(
+            sent.iter()
+                .map(|p| (p.chat_id, p.text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(100, "maintenance at 10:00"), (101, "maintenance at 10:00")]
+        );
+        let blocked = DeliveryOutcome::Blocked("bot was blocked by the user".to_string());
+        outbox.complete(delivered(&sent[0], blocked)).await;
+        assert!(!outbox.db.get_user(100).await.unwrap().unwrap().enabled);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::db::DbService;
+use crate::settings::StreamSettings;
+use crate::{feed, metrics, models};
+use axum::response::sse::Event;
+use futures::Stream;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Posts taken at once, a client resuming after a long break catches up in batches.
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Serialize)]
+struct PostEvent<'a> {
+    channel: feed::JsonChannel<'a>,
+    post: feed::JsonPost<'a>,
+}
+
+/// Streams of new posts of channels as server-sent events. Events are identified by the ids
+/// of the posts in the database, so a client reconnecting with the last one it got as
+/// `Last-Event-ID` gets the posts it has missed meanwhile.
+#[derive(Clone)]
+pub struct Streams {
+    db: DbService,
+    settings: StreamSettings,
+    base_url: String,
+    /// Channels with new posts saved by this process, those saved by the admin commands
+    /// are polled for.
+    updates: broadcast::Sender<models::TelegramChatId>,
+}
+
+impl Streams {
+    pub fn new(
+        db: DbService,
+        settings: StreamSettings,
+        base_url: String,
+        updates: broadcast::Sender<models::TelegramChatId>,
+    ) -> Self {
+        Self {
+            db,
+            settings,
+            base_url,
+            updates,
+        }
+    }
+
+    pub fn settings(&self) -> &StreamSettings {
+        &self.settings
+    }
+
+    /// Starts a stream of the posts of the channels saved after the post with id
+    /// `last_event_id`, or from now on without it. The stream never ends.
+    pub async fn open(
+        &self,
+        channel_ids: Vec<models::TelegramChatId>,
+        last_event_id: Option<i32>,
+    ) -> anyhow::Result<impl Stream<Item = Result<Event, Infallible>>> {
+        // subscribed before looking for the last post, so no post slips in between
+        let updates = self.updates.subscribe();
+        let after_id = match last_event_id {
+            Some(id) => id,
+            None => self.db.get_last_post_id().await?,
+        };
+        let period = self.settings.poll_interval();
+        let mut poll = tokio::time::interval_at(Instant::now() + period, period);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        metrics::STREAM_CLIENTS.inc();
+        let cursor = Cursor {
+            streams: self.clone(),
+            channel_ids,
+            after_id,
+            pending: VecDeque::new(),
+            updates,
+            poll,
+        };
+        Ok(futures::stream::unfold(cursor, |mut cursor| async move {
+            let event = cursor.next().await;
+            Some((Ok(event), cursor))
+        }))
+    }
+}
+
+/// Where a stream is at, counted as a client until dropped along with the stream.
+struct Cursor {
+    streams: Streams,
+    channel_ids: Vec<models::TelegramChatId>,
+    /// Id of the last post taken from the database.
+    after_id: i32,
+    pending: VecDeque<Event>,
+    updates: broadcast::Receiver<models::TelegramChatId>,
+    poll: Interval,
+}
+
+impl Cursor {
+    async fn next(&mut self) -> Event {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return event;
+            }
+            let db = &self.streams.db;
+            match db
+                .get_new_posts(&self.channel_ids, self.after_id, BATCH_SIZE)
+                .await
+            {
+                Ok(posts) if !posts.is_empty() => {
+                    for (id, channel, post) in posts {
+                        let event = self.event(id, &channel, &post);
+                        self.pending.push_back(event);
+                        self.after_id = id;
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => log::error!("cannot get new posts to stream: {err:#}"),
+            }
+            self.wait().await;
+        }
+    }
+
+    fn event(&self, id: i32, channel: &models::Channel, post: &models::Post) -> Event {
+        let data = PostEvent {
+            channel: feed::JsonChannel::new(&self.streams.base_url, channel),
+            post: feed::JsonPost::new(channel, post),
+        };
+        Event::default()
+            .event("post")
+            .id(id.to_string())
+            .json_data(data)
+            .expect("post is serializable")
+    }
+
+    /// Waits for new posts of the channels saved by this process or for the next poll.
+    async fn wait(&mut self) {
+        loop {
+            tokio::select! {
+                _ = self.poll.tick() => return,
+                update = self.updates.recv() => match update {
+                    Ok(channel_id) if !self.channel_ids.contains(&channel_id) => {}
+                    // lagged behind, the posts are looked up anyway
+                    Ok(_) | Err(RecvError::Lagged(_)) => return,
+                    Err(RecvError::Closed) => {
+                        self.poll.tick().await;
+                        return;
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        metrics::STREAM_CLIENTS.dec();
+    }
+}
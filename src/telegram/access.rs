@@ -0,0 +1,114 @@
+use crate::settings::LimitsSettings;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Who may use the bot: everyone, unless the instance is private.
+/// Shared by the bot, which checks users, and the app, which persists allowed users.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    private: bool,
+    invite_codes: Arc<Vec<String>>,
+    allowed: Arc<RwLock<HashSet<i64>>>,
+}
+
+impl Access {
+    pub fn new(limits: &LimitsSettings, admins: &[i64]) -> Self {
+        let allowed = limits.allowlist.iter().chain(admins).copied().collect();
+        Self {
+            private: limits.private,
+            invite_codes: Arc::new(limits.invite_codes.clone()),
+            allowed: Arc::new(RwLock::new(allowed)),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn is_allowed(&self, user_id: i64) -> bool {
+        !self.private || self.allowed.read().unwrap().contains(&user_id)
+    }
+
+    /// Allows the user if the code is one of the invite codes.
+    pub fn redeem(&self, user_id: i64, code: &str) -> bool {
+        if !self.invite_codes.iter().any(|c| c == code) {
+            return false;
+        }
+        log::info!("user {user_id} is allowed by an invite code");
+        self.allow([user_id]);
+        true
+    }
+
+    pub fn allow(&self, user_ids: impl IntoIterator<Item = i64>) {
+        self.allowed.write().unwrap().extend(user_ids);
+    }
+
+    pub fn revoke(&self, user_id: i64) {
+        self.allowed.write().unwrap().remove(&user_id);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    Allowed,
+    /// The first command over the limit, the user should be told to slow down.
+    Exceeded,
+    /// The user has already been told, further commands are dropped silently.
+    AlreadyExceeded,
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Idle users are forgotten once there are that many of them.
+const RATE_PRUNE_THRESHOLD: usize = 1024;
+
+/// Sliding window limit on the number of commands per user (or any other key) per minute,
+/// 0 means unlimited.
+#[derive(Debug)]
+pub struct RateLimiter<K = i64> {
+    per_minute: usize,
+    users: HashMap<K, UserRate>,
+}
+
+#[derive(Debug, Default)]
+struct UserRate {
+    commands: VecDeque<Instant>,
+    notified: bool,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(per_minute: usize) -> Self {
+        Self {
+            per_minute,
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, user_id: K) -> RateLimit {
+        if self.per_minute == 0 {
+            return RateLimit::Allowed;
+        }
+        let now = Instant::now();
+        if self.users.len() >= RATE_PRUNE_THRESHOLD {
+            self.users.retain(|_, rate| {
+                matches!(rate.commands.back(), Some(last) if now.duration_since(*last) < RATE_WINDOW)
+            });
+        }
+        let rate = self.users.entry(user_id).or_default();
+        while matches!(rate.commands.front(), Some(first) if now.duration_since(*first) >= RATE_WINDOW)
+        {
+            rate.commands.pop_front();
+        }
+        if rate.commands.len() < self.per_minute {
+            rate.commands.push_back(now);
+            rate.notified = false;
+            return RateLimit::Allowed;
+        }
+        if rate.notified {
+            return RateLimit::AlreadyExceeded;
+        }
+        rate.notified = true;
+        RateLimit::Exceeded
+    }
+}
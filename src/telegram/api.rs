@@ -0,0 +1,76 @@
+use super::TgClient;
+use anyhow::Result;
+use async_trait::async_trait;
+use rust_tdlib::types::{
+    AnswerCallbackQuery, Chat, Chats, DownloadFile, File, GetChat, GetChatHistory, GetChats, GetMe,
+    GetSupergroup, Message, Messages, Ok as TdOk, SearchPublicChat, SendMessage, SetCommands,
+    Supergroup, User,
+};
+
+/// TDLib calls made by the bot and the user clients.
+/// Implemented by the TDLib client itself and by an in-memory fake in tests.
+#[async_trait]
+pub trait TdApi: Clone + Send + Sync + 'static {
+    async fn send_message(&self, request: SendMessage) -> Result<Message>;
+
+    async fn search_public_chat(&self, request: SearchPublicChat) -> Result<Chat>;
+
+    async fn get_chat_history(&self, request: GetChatHistory) -> Result<Messages>;
+
+    async fn get_chats(&self, request: GetChats) -> Result<Chats>;
+
+    async fn get_chat(&self, request: GetChat) -> Result<Chat>;
+
+    async fn get_supergroup(&self, request: GetSupergroup) -> Result<Supergroup>;
+
+    async fn set_commands(&self, request: SetCommands) -> Result<TdOk>;
+
+    async fn get_me(&self, request: GetMe) -> Result<User>;
+
+    async fn answer_callback_query(&self, request: AnswerCallbackQuery) -> Result<TdOk>;
+
+    async fn download_file(&self, request: DownloadFile) -> Result<File>;
+}
+
+#[async_trait]
+impl TdApi for TgClient {
+    async fn send_message(&self, request: SendMessage) -> Result<Message> {
+        Ok(TgClient::send_message(self, request).await?)
+    }
+
+    async fn search_public_chat(&self, request: SearchPublicChat) -> Result<Chat> {
+        Ok(TgClient::search_public_chat(self, request).await?)
+    }
+
+    async fn get_chat_history(&self, request: GetChatHistory) -> Result<Messages> {
+        Ok(TgClient::get_chat_history(self, request).await?)
+    }
+
+    async fn get_chats(&self, request: GetChats) -> Result<Chats> {
+        Ok(TgClient::get_chats(self, request).await?)
+    }
+
+    async fn get_chat(&self, request: GetChat) -> Result<Chat> {
+        Ok(TgClient::get_chat(self, request).await?)
+    }
+
+    async fn get_supergroup(&self, request: GetSupergroup) -> Result<Supergroup> {
+        Ok(TgClient::get_supergroup(self, request).await?)
+    }
+
+    async fn set_commands(&self, request: SetCommands) -> Result<TdOk> {
+        Ok(TgClient::set_commands(self, request).await?)
+    }
+
+    async fn get_me(&self, request: GetMe) -> Result<User> {
+        Ok(TgClient::get_me(self, request).await?)
+    }
+
+    async fn answer_callback_query(&self, request: AnswerCallbackQuery) -> Result<TdOk> {
+        Ok(TgClient::answer_callback_query(self, request).await?)
+    }
+
+    async fn download_file(&self, request: DownloadFile) -> Result<File> {
+        Ok(TgClient::download_file(self, request).await?)
+    }
+}
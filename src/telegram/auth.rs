@@ -0,0 +1,251 @@
+use super::TgClient;
+use crate::settings::{AuthCodeSource, AuthSettings};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use rust_tdlib::client::{ClientAuthStateHandler, ClientIdentifier};
+use rust_tdlib::types::{
+    AuthorizationStateWaitCode, AuthorizationStateWaitPassword, AuthorizationStateWaitRegistration,
+    FormattedText, InputMessageContent, InputMessageText, SendMessage,
+};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use strum::Display;
+use tokio::sync::oneshot;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Display)]
+#[strum(serialize_all = "snake_case")]
+enum Secret {
+    Code,
+    Password,
+}
+
+/// Authorizes the user account without stdin: the phone code and the 2FA password
+/// are asked from the admin through the bot, or read from a file or an HTTP endpoint.
+#[derive(Debug, Clone)]
+pub struct UserAuthStateHandler {
+    phone: String,
+    settings: AuthSettings,
+    admin_prompt: Option<AdminPrompt>,
+}
+
+impl UserAuthStateHandler {
+    pub fn new(phone: String, settings: AuthSettings, admin_prompt: Option<AdminPrompt>) -> Self {
+        Self {
+            phone,
+            settings,
+            admin_prompt,
+        }
+    }
+
+    async fn get_secret(&self, secret: Secret) -> String {
+        let timeout = Duration::from_secs(self.settings.timeout_secs);
+        log::warn!(
+            "waiting for the {secret} of {} from {:?} for {timeout:?}",
+            self.phone,
+            self.settings.source
+        );
+        let result = match &self.settings.source {
+            AuthCodeSource::Console => read_console(secret).await,
+            AuthCodeSource::Bot { .. } => match &self.admin_prompt {
+                None => Err(anyhow::anyhow!("bot is not configured to ask the admin")),
+                Some(prompt) => tokio::time::timeout(timeout, prompt.ask(secret))
+                    .await
+                    .unwrap_or_else(|_| timeout_error(secret)),
+            },
+            AuthCodeSource::File {
+                code_path,
+                password_path,
+            } => {
+                let path = match secret {
+                    Secret::Code => code_path,
+                    Secret::Password => password_path,
+                };
+                tokio::time::timeout(timeout, poll_file(path))
+                    .await
+                    .unwrap_or_else(|_| timeout_error(secret))
+            }
+            AuthCodeSource::Http { url } => tokio::time::timeout(timeout, poll_http(url, secret))
+                .await
+                .unwrap_or_else(|_| timeout_error(secret)),
+        };
+        match result {
+            Ok(value) => {
+                log::info!("got the {secret}, continuing authorization");
+                value
+            }
+            Err(err) => {
+                // an empty value makes TDLib fail the authorization: on the first start the
+                // service fails to start and stops, a restarted client is restarted again later
+                log::error!("cannot get the {secret}: {err}");
+                "".to_string()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ClientAuthStateHandler for UserAuthStateHandler {
+    async fn handle_wait_code(&self, _wait_code: &AuthorizationStateWaitCode) -> String {
+        let code = self.get_secret(Secret::Code).await;
+        // telegram expires codes sent in messages, so the admin is asked to obfuscate them
+        code.chars().filter(|c| c.is_ascii_digit()).collect()
+    }
+
+    async fn handle_wait_password(
+        &self,
+        _wait_password: &AuthorizationStateWaitPassword,
+    ) -> String {
+        self.get_secret(Secret::Password).await
+    }
+
+    async fn handle_wait_client_identifier(&self) -> ClientIdentifier {
+        ClientIdentifier::PhoneNumber(self.phone.clone())
+    }
+
+    async fn handle_wait_registration(
+        &self,
+        _wait_registration: &AuthorizationStateWaitRegistration,
+    ) -> (String, String) {
+        log::error!(
+            "phone number {} is not registered, register it with an official client first",
+            self.phone
+        );
+        ("".to_string(), "".to_string())
+    }
+}
+
+/// Asks the admin for a secret through the bot and waits for the reply.
+#[derive(Clone)]
+pub struct AdminPrompt {
+    admin_id: i64,
+    inner: Arc<Mutex<PromptInner>>,
+}
+
+#[derive(Default)]
+struct PromptInner {
+    client: Option<TgClient>,
+    pending: Option<oneshot::Sender<String>>,
+}
+
+impl Debug for AdminPrompt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminPrompt")
+            .field("admin_id", &self.admin_id)
+            .finish()
+    }
+}
+
+impl AdminPrompt {
+    pub fn new(admin_id: i64) -> Self {
+        Self {
+            admin_id,
+            inner: Arc::new(Mutex::new(PromptInner::default())),
+        }
+    }
+
+    /// Sets the bot client used to send questions; called on every bot (re)start.
+    pub fn set_client(&self, client: TgClient) {
+        self.inner.lock().unwrap().client = Some(client);
+    }
+
+    /// Passes a message of the user to the pending question.
+    /// Returns `false` if the message is not an answer.
+    pub fn answer(&self, user_id: i64, text: &str) -> bool {
+        if user_id != self.admin_id {
+            return false;
+        }
+        match self.inner.lock().unwrap().pending.take() {
+            None => false,
+            Some(sender) => sender.send(text.trim().to_string()).is_ok(),
+        }
+    }
+
+    async fn ask(&self, secret: Secret) -> Result<String> {
+        let (sender, receiver) = oneshot::channel();
+        let client = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.pending = Some(sender);
+            inner.client.clone()
+        };
+        let client = match client {
+            None => bail!("bot is not started"),
+            Some(client) => client,
+        };
+        let question = match secret {
+            Secret::Code => "tgfeed needs to authorize the reader account, reply with the login code. Separate the digits with spaces, otherwise telegram expires the code",
+            Secret::Password => "tgfeed needs to authorize the reader account, reply with the 2FA password",
+        };
+        client
+            .send_message(
+                SendMessage::builder()
+                    .chat_id(self.admin_id)
+                    .input_message_content(InputMessageContent::InputMessageText(
+                        InputMessageText::builder()
+                            .text(FormattedText::builder().text(question).build())
+                            .build(),
+                    ))
+                    .build(),
+            )
+            .await?;
+        log::info!("asked admin {} for the {secret}", self.admin_id);
+        Ok(receiver.await?)
+    }
+}
+
+fn timeout_error(secret: Secret) -> Result<String> {
+    bail!("timed out waiting for the {secret}")
+}
+
+async fn read_console(secret: Secret) -> Result<String> {
+    println!("enter the {secret}:");
+    let value = tokio::task::spawn_blocking(|| {
+        let mut s = String::new();
+        std::io::stdin().read_line(&mut s).map(|_| s)
+    })
+    .await??;
+    Ok(value.trim().to_string())
+}
+
+/// Waits for the file to appear and removes it once read, so the secret is not reused.
+async fn poll_file(path: &str) -> Result<String> {
+    loop {
+        match tokio::fs::read_to_string(path).await {
+            Ok(value) if !value.trim().is_empty() => {
+                if let Err(err) = tokio::fs::remove_file(path).await {
+                    log::warn!("cannot remove {path}: {err}");
+                }
+                return Ok(value.trim().to_string());
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Polls the endpoint until it responds with a non-empty body.
+async fn poll_http(url: &str, secret: Secret) -> Result<String> {
+    let client = reqwest::Client::new();
+    loop {
+        match client
+            .get(url)
+            .query(&[("kind", secret.to_string())])
+            .send()
+            .await
+        {
+            Ok(resp) if resp.status().is_success() => {
+                let value = resp.text().await?;
+                if !value.trim().is_empty() {
+                    return Ok(value.trim().to_string());
+                }
+            }
+            Ok(resp) => log::debug!("{url} responded with {}", resp.status()),
+            Err(err) => log::warn!("cannot request {url}: {err}"),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
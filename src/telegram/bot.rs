@@ -1,50 +1,198 @@
-use crate::models;
-use crate::telegram::{TgClient, SEND_UPDATE_TIMEOUT};
+use crate::settings::IngestionSettings;
+use crate::telegram::access::{Access, RateLimit, RateLimiter};
+use crate::telegram::api::TdApi;
+use crate::telegram::auth::AdminPrompt;
+use crate::telegram::scheduler::flood_wait;
+use crate::telegram::{Health, TgClient};
+use crate::{metrics, models, opml, webhooks};
 use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures::StreamExt;
 use rust_tdlib::types::{
-    BotCommand as TdLibBotCommand, FormattedText, GetMe, InputMessageContent, InputMessageText,
-    MessageContent, MessageSender, SearchPublicChat, SendMessage, SetCommands, TextEntityType,
-    Update, UpdateNewMessage,
+    AnswerCallbackQuery, BotCommand as TdLibBotCommand, BotCommandScope, BotCommandScopeChat,
+    CallbackQueryPayload, ChatMemberStatus, ChatType, DownloadFile, FormattedText, GetChat, GetMe,
+    GetSupergroup, InlineKeyboardButton, InlineKeyboardButtonType,
+    InlineKeyboardButtonTypeCallback, InputFile, InputFileLocal, InputMessageContent,
+    InputMessageDocument, InputMessageText, Message, MessageContent, MessageForwardOrigin,
+    MessageSender, ReplyMarkup, ReplyMarkupInlineKeyboard, SearchPublicChat, SendMessage,
+    SetCommands, TextEntityType, Update, UpdateChatMember, UpdateNewCallbackQuery,
+    UpdateNewMessage,
 };
-use strum::{Display, EnumIter, EnumMessage, IntoEnumIterator};
-use tokio::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use strum::{Display, EnumIter, EnumMessage, EnumProperty, IntoEnumIterator, IntoStaticStr};
+use tempfile::TempPath;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
 
 #[derive(Debug)]
 pub struct BotUpdate {
     chat_id: i64,
     user_id: i64,
     message: BotCommand,
+    /// Follows the update from TDLib to the replies and the requests to the service.
+    span: Span,
 }
 
+const ADD_CHANNEL_CALLBACK_PREFIX: &str = "add:";
+
 #[derive(Debug)]
-pub struct AddUserChannel {
+pub struct AddUserChannels {
     pub user_id: i64,
-    pub channel_id: i64,
-    pub channel_name: String,
-    pub title: String,
+    pub chat_id: i64,
+    pub channels: Vec<models::NewChannel>,
+    pub unresolved: Vec<String>,
+    /// Channels which are not resolved because the user would exceed the subscription limit.
+    pub over_limit: Vec<String>,
 }
 
 #[derive(Debug)]
-pub struct RemoveUserChannel {
+pub struct RemoveUserChannels {
     pub user_id: i64,
-    pub channel_name: String,
+    pub chat_id: i64,
+    pub channel_names: Vec<String>,
+    pub invalid: Vec<String>,
 }
 
 #[derive(Debug)]
-pub struct UserChat {
+pub struct Broadcast {
+    pub chat_id: i64,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct BanUser {
+    pub chat_id: i64,
     pub user_id: i64,
+}
+
+#[derive(Debug)]
+pub struct RefetchChannel {
     pub chat_id: i64,
+    pub channel: models::NewChannel,
+}
+
+/// What a user does with their collections, channel names are already parsed.
+#[derive(Debug, PartialEq)]
+pub enum CollectionAction {
+    List,
+    Create(String),
+    Delete(String),
+    Add {
+        name: String,
+        channel_names: Vec<String>,
+        invalid: Vec<String>,
+    },
+    Remove {
+        name: String,
+        channel_names: Vec<String>,
+        invalid: Vec<String>,
+    },
 }
 
 #[derive(Debug)]
+pub struct CollectionRequest {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub action: CollectionAction,
+}
+
+/// What a user does with their webhooks, URLs are already checked.
+#[derive(Debug, PartialEq)]
+pub enum WebhookAction {
+    List,
+    Add(String),
+    Remove(String),
+}
+
+#[derive(Debug)]
+pub struct WebhookRequest {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub action: WebhookAction,
+}
+
+#[derive(Debug)]
+pub struct UserChat {
+    pub user_id: i64,
+    pub chat_id: i64,
+}
+
+/// Result of sending a queued post.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryOutcome {
+    Sent,
+    /// Telegram may ask to wait before the next attempt.
+    Failed {
+        error: String,
+        retry_after: Option<Duration>,
+    },
+    /// The chat is not reachable anymore, e.g. the account is deleted.
+    ChatGone(String),
+    Blocked(String),
+}
+
+impl DeliveryOutcome {
+    fn from_error(err: &anyhow::Error) -> Self {
+        let error = format!("{err:#}");
+        if is_blocked(err) {
+            DeliveryOutcome::Blocked(error)
+        } else if CHAT_GONE_ERRORS.iter().any(|e| error.contains(e)) {
+            DeliveryOutcome::ChatGone(error)
+        } else {
+            DeliveryOutcome::Failed {
+                retry_after: flood_wait(err),
+                error,
+            }
+        }
+    }
+}
+
+/// Errors telegram returns for chats the bot can never write to again.
+const CHAT_GONE_ERRORS: &[&str] = &[
+    "Chat not found",
+    "PEER_ID_INVALID",
+    "user is deactivated",
+    "USER_DEACTIVATED",
+];
+
+/// Errors telegram returns once the user has blocked the bot.
+const BLOCKED_ERRORS: &[&str] = &["bot was blocked by the user", "USER_IS_BLOCKED"];
+
+fn is_blocked(err: &anyhow::Error) -> bool {
+    let error = format!("{err:#}");
+    BLOCKED_ERRORS.iter().any(|e| error.contains(e))
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivered {
+    pub delivery_id: i64,
+    pub chat_id: i64,
+    pub outcome: DeliveryOutcome,
+}
+
+#[derive(Debug, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum BotRequests {
     AddUser(UserChat),
     RemoveUser(UserChat),
-    AddUserChannel(AddUserChannel),
-    RemoveUserChannel(RemoveUserChannel),
+    /// The user has blocked the bot; the user is enabled again on /start.
+    Blocked(UserChat),
+    AddUserChannels(AddUserChannels),
+    RemoveUserChannels(RemoveUserChannels),
     ListChannels(i64),
+    ExportChannels(i64),
+    /// Admin requests, the bot lets only admins send them.
+    Stats(i64),
+    Broadcast(Broadcast),
+    BanUser(BanUser),
+    RefetchChannel(RefetchChannel),
+    Collection(CollectionRequest),
+    Webhook(WebhookRequest),
 }
 
 #[derive(Debug)]
@@ -53,159 +201,905 @@ pub struct BotResponseListChannels {
     pub channels: Vec<models::Channel>,
 }
 
+#[derive(Debug)]
+pub struct BotResponseChannelsAdded {
+    pub chat_id: i64,
+    pub added: Vec<String>,
+    pub already_present: Vec<String>,
+    pub unresolved: Vec<String>,
+    pub over_limit: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct BotResponseChannelsRemoved {
+    pub chat_id: i64,
+    pub removed: Vec<String>,
+    pub not_found: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct BotResponseStats {
+    pub chat_id: i64,
+    pub stats: models::Stats,
+}
+
+#[derive(Debug)]
+pub struct BotResponseText {
+    pub chat_id: i64,
+    pub text: String,
+}
+
+/// Collections of the user, the bot adds the URLs of their feeds.
+#[derive(Debug)]
+pub struct BotResponseCollections {
+    pub chat_id: i64,
+    /// Shown above the list, e.g. what has just been done.
+    pub note: Option<String>,
+    pub collections: Vec<models::Collection>,
+}
+
+/// A queued post, the bot reports the outcome with [`Delivered`].
+#[derive(Debug)]
+pub struct BotResponsePost {
+    pub delivery_id: i64,
+    pub chat_id: i64,
+    pub text: String,
+}
+
 #[derive(Debug)]
 pub enum BotResponses {
     ListChannels(BotResponseListChannels),
+    ExportChannels(BotResponseListChannels),
+    ChannelsAdded(BotResponseChannelsAdded),
+    ChannelsRemoved(BotResponseChannelsRemoved),
+    Stats(BotResponseStats),
+    Text(BotResponseText),
+    Post(BotResponsePost),
+    Collections(BotResponseCollections),
 }
 
 type TgUpdate = Receiver<BotUpdate>;
 type FromTgService = Receiver<BotResponses>;
 type ToTgService = Sender<BotRequests>;
+/// Outcomes of posts go straight to the outbox, which passes the posts to the bot in turn,
+/// so neither waits for the other. There are only as many as the posts in flight.
+pub type DeliveredSender = mpsc::UnboundedSender<Delivered>;
 
-pub struct BotClient {
-    client: Option<TgClient>,
+/// Bot options which stay the same across restarts.
+#[derive(Debug, Clone)]
+pub struct BotSettings {
+    pub feeds_base_url: String,
+    /// How many channels are searched concurrently on bulk add or import.
+    pub resolve_concurrency: usize,
+    pub admins: Vec<i64>,
+    /// Channels over this number are not even searched on add or import, 0 means unlimited.
+    pub max_channels_per_user: usize,
+    /// Commands of admins are never limited, 0 means unlimited.
+    pub commands_per_minute: usize,
+    /// Largest file accepted by /import, in bytes.
+    pub max_import_size: u64,
+    /// How many imports run at once, a user runs one at a time.
+    pub max_imports: usize,
 }
 
-#[derive(Debug, Display, EnumMessage, EnumIter)]
+pub struct BotClient<C = TgClient> {
+    client: Option<C>,
+    settings: BotSettings,
+    admin_prompt: Option<AdminPrompt>,
+    health: Health,
+    access: Access,
+}
+
+#[derive(Debug, Display, EnumMessage, EnumIter, EnumProperty)]
 enum BotCommand {
+    /// Along with the invite code of a private instance, if any.
     #[strum(message = "/start", detailed_message = "starts bot interaction")]
-    Start,
+    Start(String),
     #[strum(message = "/stop", detailed_message = "stops bot interaction")]
     Stop,
-    #[strum(message = "/add", detailed_message = "adds a channel")]
+    #[strum(message = "/add", detailed_message = "adds channels")]
     Add(String),
     #[strum(message = "/list", detailed_message = "list of channels")]
     List,
-    #[strum(message = "/remove", detailed_message = "removes a channel")]
+    #[strum(message = "/remove", detailed_message = "removes channels")]
     Remove(String),
+    #[strum(message = "/export", detailed_message = "exports channels as OPML")]
+    Export,
+    #[strum(
+        message = "/import",
+        detailed_message = "imports channels from an OPML or a text file with t.me links"
+    )]
+    Import(Option<ImportFile>),
+    #[strum(
+        message = "/collection",
+        detailed_message = "groups channels into feeds: list, create, delete, add, remove"
+    )]
+    Collection(String),
+    #[strum(
+        message = "/webhook",
+        detailed_message = "sends posts of your channels to your endpoint: list, add, remove"
+    )]
+    Webhook(String),
+    #[strum(
+        message = "/stats",
+        detailed_message = "users, channels and posts per day",
+        props(admin = "true")
+    )]
+    Stats,
+    #[strum(
+        message = "/broadcast",
+        detailed_message = "sends a message to all the users",
+        props(admin = "true")
+    )]
+    Broadcast(String),
+    #[strum(
+        message = "/ban",
+        detailed_message = "disables the user by id for good",
+        props(admin = "true")
+    )]
+    Ban(String),
+    #[strum(
+        message = "/refetch",
+        detailed_message = "updates the channel title and id",
+        props(admin = "true")
+    )]
+    Refetch(String),
+    #[strum(
+        message = "/health",
+        detailed_message = "states of the telegram clients",
+        props(admin = "true")
+    )]
+    Health,
+    /// A post forwarded from the channel with the given chat id.
+    Forwarded(i64),
+    /// A confirmation button pressed: callback query id and the channel name.
+    ConfirmAdd(i64, String),
+    /// A message which is not a command, e.g. an answer to the admin prompt.
+    Text(String),
+    /// The user has blocked the bot, it is not a message actually.
+    Blocked,
+    /// TDLib has sent the message with the given temporary id.
+    Sent(i64),
+    /// TDLib has failed to send the message with the given temporary id.
+    SendFailed(i64, String),
     Invalid,
 }
 
-impl BotClient {
-    pub fn new(client: TgClient) -> Self {
+impl BotCommand {
+    fn is_admin(&self) -> bool {
+        self.get_str("admin").is_some()
+    }
+}
+
+/// A document sent to the bot, it is imported unless it is too big or of another type.
+#[derive(Debug)]
+struct ImportFile {
+    id: i32,
+    name: String,
+    mime_type: String,
+    /// In bytes, zero if telegram does not know it yet.
+    size: u64,
+}
+
+/// Extensions and types of the files channels are imported from.
+const IMPORT_EXTENSIONS: &[&str] = &[".opml", ".xml", ".txt"];
+const IMPORT_MIME_TYPES: &[&str] = &[
+    "text/plain",
+    "text/xml",
+    "text/x-opml",
+    "text/x-opml+xml",
+    "application/xml",
+];
+
+impl ImportFile {
+    fn is_importable(&self) -> bool {
+        let name = self.name.to_lowercase();
+        IMPORT_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+            || IMPORT_MIME_TYPES.contains(&self.mime_type.to_lowercase().as_str())
+    }
+}
+
+/// Imports running at once, overall and by user.
+struct Imports {
+    running: Arc<Semaphore>,
+    users: Arc<std::sync::Mutex<HashSet<i64>>>,
+}
+
+/// Held by a running import, the user may import again once it is dropped.
+struct ImportPermit {
+    _running: OwnedSemaphorePermit,
+    users: Arc<std::sync::Mutex<HashSet<i64>>>,
+    user_id: i64,
+}
+
+impl Imports {
+    fn new(max_imports: usize) -> Self {
+        Self {
+            running: Arc::new(Semaphore::new(max_imports)),
+            users: Arc::default(),
+        }
+    }
+
+    /// Returns the reason to give the user if the import cannot start now.
+    fn start(&self, user_id: i64) -> Result<ImportPermit, &'static str> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains(&user_id) {
+            return Err("the previous file is still being imported, send this one once it is done");
+        }
+        let running = self
+            .running
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| "too many files are being imported, try again in a minute")?;
+        users.insert(user_id);
+        Ok(ImportPermit {
+            _running: running,
+            users: self.users.clone(),
+            user_id,
+        })
+    }
+}
+
+impl Drop for ImportPermit {
+    fn drop(&mut self) {
+        self.users.lock().unwrap().remove(&self.user_id);
+    }
+}
+
+impl<C: TdApi> BotClient<C> {
+    pub fn new(
+        client: C,
+        settings: BotSettings,
+        admin_prompt: Option<AdminPrompt>,
+        health: Health,
+        access: Access,
+    ) -> Self {
         Self {
             client: Some(client),
+            settings,
+            admin_prompt,
+            health,
+            access,
         }
     }
 
     pub async fn start(
         &mut self,
         mut tg_update: TgUpdate,
-        mut from_service: FromTgService,
+        from_service: Arc<Mutex<FromTgService>>,
         to_service: ToTgService,
+        delivered: DeliveredSender,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<JoinHandle<()>> {
         let client = self.client.take().unwrap();
+        let admins = &self.settings.admins;
 
         client
-            .set_commands(
-                SetCommands::builder().commands(
-                    BotCommand::iter()
-                        .filter_map(|cmd| {
-                            let message = cmd.get_message();
-                            let det_message = cmd.get_detailed_message();
-
-                            match (message, det_message) {
-                                (Some(message), Some(det_message)) => Some(
-                                    TdLibBotCommand::builder()
-                                        .command(message)
-                                        .description(det_message)
-                                        .build(),
-                                ),
-                                _ => None,
-                            }
-                        })
-                        .collect(),
-                ),
-            )
+            .set_commands(SetCommands::builder().commands(bot_commands(false)).build())
             .await?;
+        // admins see the admin commands in their chats only
+        for admin_id in admins.iter() {
+            let res = client
+                .set_commands(
+                    SetCommands::builder()
+                        .scope(BotCommandScope::Chat(
+                            BotCommandScopeChat::builder().chat_id(*admin_id).build(),
+                        ))
+                        .commands(bot_commands(true))
+                        .build(),
+                )
+                .await;
+            if let Err(err) = res {
+                log::warn!("cannot set admin commands for {admin_id}: {err}");
+            }
+        }
 
         let me = client.get_me(GetMe::builder().build()).await?;
+        let mut handler = UpdateHandler {
+            client,
+            to_service,
+            delivered,
+            settings: self.settings.clone(),
+            admin_prompt: self.admin_prompt.clone(),
+            health: self.health.clone(),
+            access: self.access.clone(),
+            rate_limiter: RateLimiter::new(self.settings.commands_per_minute),
+            imports: Imports::new(self.settings.max_imports),
+            pending: HashMap::new(),
+        };
 
         Ok(tokio::spawn(async move {
+            // the receiver outlives the task, so a restarted bot gets responses queued for the previous one
+            let mut from_service = from_service.lock().await;
             loop {
                 tokio::select! {
                     Some(tg_upd) = tg_update.recv() => {
                         if tg_upd.user_id == me.id() {
                             continue
                         }
-                        let msg = match &tg_upd.message {
-                            BotCommand::Invalid => {
-                                Some(make_invalid_request_resp(tg_upd.chat_id))
-                            },
-                            BotCommand::List => {
-                                to_service.send(BotRequests::ListChannels(tg_upd.user_id)).await;
-                                None
-                            }
-                            BotCommand::Remove(channel_name) => {
-                                let resp = make_channel_removed_resp(tg_upd.chat_id, channel_name);
-                                to_service.send(BotRequests::RemoveUserChannel(RemoveUserChannel{
-                                    user_id: tg_upd.user_id,
-                                    channel_name: channel_name.trim().to_string(),
-                                })).await;
-                                Some(resp)
+                        let span = tg_upd.span.clone();
+                        handler.handle(tg_upd).instrument(span).await;
+                    },
+
+                    Some(from_srv) = from_service.recv() => {
+                        handler.handle_service_response(from_srv).await;
+                    }
+
+                    _ = shutdown.changed() => {
+                        // new updates are not accepted anymore, but responses which are already queued are still sent
+                        while let Ok(from_srv) = from_service.try_recv() {
+                            handler.handle_service_response(from_srv).await;
+                        }
+                        log::info!("bot stopped");
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// State of the bot task which handles updates and responses one at a time.
+struct UpdateHandler<C> {
+    client: C,
+    to_service: ToTgService,
+    delivered: DeliveredSender,
+    settings: BotSettings,
+    admin_prompt: Option<AdminPrompt>,
+    health: Health,
+    access: Access,
+    rate_limiter: RateLimiter,
+    imports: Imports,
+    /// Messages TDLib is still sending, by their temporary ids.
+    pending: HashMap<i64, PendingSend>,
+}
+
+/// A message accepted by TDLib which is done with once TDLib reports whether it is sent.
+enum PendingSend {
+    Post {
+        delivery_id: i64,
+        chat_id: i64,
+    },
+    /// The document is uploaded from the file, so it is kept until then.
+    Export(TempPath),
+}
+
+impl<C: TdApi> UpdateHandler<C> {
+    async fn handle(&mut self, tg_upd: BotUpdate) {
+        match tg_upd.message {
+            BotCommand::Sent(message_id) => {
+                return self
+                    .handle_send_outcome(tg_upd.chat_id, message_id, None)
+                    .await;
+            }
+            BotCommand::SendFailed(message_id, error) => {
+                return self
+                    .handle_send_outcome(tg_upd.chat_id, message_id, Some(error))
+                    .await;
+            }
+            _ => {}
+        }
+        let client = &self.client;
+        let to_service = &self.to_service;
+        let BotSettings {
+            feeds_base_url,
+            admins,
+            ..
+        } = &self.settings;
+        let resolve_concurrency = self.settings.resolve_concurrency;
+        let max_channels_per_user = self.settings.max_channels_per_user;
+        let max_import_size = self.settings.max_import_size;
+        let (admin_prompt, health, access) = (&self.admin_prompt, &self.health, &self.access);
+        metrics::BOT_COMMANDS
+            .with_label_values(&[&tg_upd.message.to_string().to_lowercase()])
+            .inc();
+        if let BotCommand::Blocked = tg_upd.message {
+            // there is no one to reply to
+            log::info!("user {} has blocked the bot", tg_upd.user_id);
+            self.report_blocked(tg_upd.user_id, tg_upd.chat_id).await;
+            return;
+        }
+        let is_admin = admins.contains(&tg_upd.user_id);
+        if !is_admin {
+            match self.rate_limiter.check(tg_upd.user_id) {
+                RateLimit::Allowed => {}
+                RateLimit::Exceeded => {
+                    log::warn!("user {} exceeded the rate limit", tg_upd.user_id);
+                    deliver(client, make_rate_limited_resp(tg_upd.chat_id)).await;
+                    return;
+                }
+                RateLimit::AlreadyExceeded => return,
+            }
+        }
+        if tg_upd.message.is_admin() && !is_admin {
+            log::warn!(
+                "user {} is not allowed to use {}",
+                tg_upd.user_id,
+                tg_upd.message
+            );
+            deliver(client, make_invalid_request_resp(tg_upd.chat_id)).await;
+            return;
+        }
+        // a plain text may be the answer to the admin prompt, it is checked below
+        let permitted = match &tg_upd.message {
+            _ if access.is_allowed(tg_upd.user_id) => true,
+            BotCommand::Start(code) => access.redeem(tg_upd.user_id, code),
+            BotCommand::Text(_) | BotCommand::Invalid => true,
+            _ => false,
+        };
+        if !permitted {
+            log::warn!(
+                "user {} is not allowed to use the private bot",
+                tg_upd.user_id
+            );
+            deliver(client, make_private_bot_resp(tg_upd.chat_id)).await;
+            return;
+        }
+        let msg = match &tg_upd.message {
+            BotCommand::Invalid => Some(make_invalid_request_resp(tg_upd.chat_id)),
+            BotCommand::Blocked | BotCommand::Sent(_) | BotCommand::SendFailed(..) => None,
+            BotCommand::Text(text) => match &admin_prompt {
+                Some(prompt) if prompt.answer(tg_upd.user_id, text) => None,
+                _ => Some(make_invalid_request_resp(tg_upd.chat_id)),
+            },
+            BotCommand::List => {
+                to_service
+                    .send(BotRequests::ListChannels(tg_upd.user_id))
+                    .await;
+                None
+            }
+            BotCommand::Remove(args) => {
+                let (channel_names, invalid) = opml::parse_links(&feeds_base_url, args);
+                if channel_names.is_empty() && invalid.is_empty() {
+                    Some(make_no_channels_resp(tg_upd.chat_id))
+                } else {
+                    to_service
+                        .send(BotRequests::RemoveUserChannels(RemoveUserChannels {
+                            user_id: tg_upd.user_id,
+                            chat_id: tg_upd.chat_id,
+                            channel_names,
+                            invalid,
+                        }))
+                        .await;
+                    None
+                }
+            }
+            BotCommand::Add(args) => {
+                let (channel_names, invalid) = opml::parse_links(&feeds_base_url, args);
+                if channel_names.is_empty() && invalid.is_empty() {
+                    Some(make_no_channels_resp(tg_upd.chat_id))
+                } else {
+                    let (client, to_service) = (client.clone(), to_service.clone());
+                    let (user_id, chat_id) = (tg_upd.user_id, tg_upd.chat_id);
+                    self.spawn_command(async move {
+                        let (channel_names, over_limit) =
+                            split_over_limit(channel_names, max_channels_per_user);
+                        let (channels, mut unresolved) =
+                            resolve_channels(&client, channel_names, resolve_concurrency).await;
+                        unresolved.extend(invalid);
+                        to_service
+                            .send(BotRequests::AddUserChannels(AddUserChannels {
+                                user_id,
+                                chat_id,
+                                channels,
+                                unresolved,
+                                over_limit,
+                            }))
+                            .await;
+                        None
+                    });
+                    None
+                }
+            }
+            BotCommand::Forwarded(channel_id) => {
+                let (client, channel_id, chat_id) = (client.clone(), *channel_id, tg_upd.chat_id);
+                self.spawn_command(async move {
+                    match get_public_channel(&client, channel_id).await {
+                        Err(err) => {
+                            log::error!("cannot get forwarded channel {channel_id}: {err}");
+                            Some(make_invalid_request_resp(chat_id))
+                        }
+                        Ok(None) => Some(make_private_channel_resp(chat_id)),
+                        Ok(Some(channel)) => Some(make_confirm_add_resp(chat_id, &channel)),
+                    }
+                });
+                None
+            }
+            BotCommand::ConfirmAdd(query_id, channel_name) => {
+                let (client, to_service) = (client.clone(), to_service.clone());
+                let (query_id, channel_name) = (*query_id, channel_name.clone());
+                let (user_id, chat_id) = (tg_upd.user_id, tg_upd.chat_id);
+                self.spawn_command(async move {
+                    client
+                        .answer_callback_query(
+                            AnswerCallbackQuery::builder()
+                                .callback_query_id(query_id)
+                                .build(),
+                        )
+                        .await;
+                    let (channels, unresolved) =
+                        resolve_channels(&client, vec![channel_name], resolve_concurrency).await;
+                    to_service
+                        .send(BotRequests::AddUserChannels(AddUserChannels {
+                            user_id,
+                            chat_id,
+                            channels,
+                            unresolved,
+                            over_limit: vec![],
+                        }))
+                        .await;
+                    None
+                });
+                None
+            }
+            BotCommand::Export => {
+                to_service
+                    .send(BotRequests::ExportChannels(tg_upd.user_id))
+                    .await;
+                None
+            }
+            BotCommand::Import(None) => Some(make_import_usage_resp(tg_upd.chat_id)),
+            BotCommand::Import(Some(file)) if !file.is_importable() => Some(make_text_resp(
+                tg_upd.chat_id,
+                "only OPML files and text files with t.me links are imported",
+            )),
+            BotCommand::Import(Some(file)) if file.size > max_import_size => Some(make_text_resp(
+                tg_upd.chat_id,
+                format!("the file is too big, at most {} KB", max_import_size / 1024),
+            )),
+            BotCommand::Import(Some(file)) => match self.imports.start(tg_upd.user_id) {
+                Err(reason) => Some(make_text_resp(tg_upd.chat_id, reason)),
+                Ok(permit) => {
+                    let (client, to_service) = (client.clone(), to_service.clone());
+                    let feeds_base_url = feeds_base_url.clone();
+                    let (file_id, user_id, chat_id) = (file.id, tg_upd.user_id, tg_upd.chat_id);
+                    self.spawn_command(async move {
+                        let imported = import_channels(
+                            &client,
+                            &feeds_base_url,
+                            file_id,
+                            max_import_size,
+                            resolve_concurrency,
+                            max_channels_per_user,
+                        )
+                        .await;
+                        drop(permit);
+                        match imported {
+                            Err(err) => {
+                                log::error!("cannot import channels: {err}");
+                                Some(make_text_resp(chat_id, "cannot read the file"))
                             }
-                            BotCommand::Add(channel_name) => {
-                                match client.search_public_chat(SearchPublicChat::builder().username(channel_name).build()).await {
-                                    Err(_) => Some(make_channel_not_found_resp(tg_upd.chat_id, channel_name)),
-                                    Ok(ch) => {
-                                        let resp = make_channel_added_resp(tg_upd.chat_id, channel_name);
-                                        to_service.send(BotRequests::AddUserChannel(AddUserChannel{
-                                            user_id: tg_upd.user_id,
-                                            channel_name: channel_name.trim().to_string(),
-                                            title: ch.title().trim().to_string(),
-                                            channel_id: ch.id(),
-                                        })).await;
-                                        Some(resp)
-                                    }
-                                }
-                            },
-                            BotCommand::Stop => {
-                                to_service.send(BotRequests::RemoveUser(
-                                    UserChat{
-                                        user_id: tg_upd.user_id,
-                                        chat_id: tg_upd.chat_id,
-                                }
-                                )).await;
-                                Some(make_stop_resp(tg_upd.chat_id))
+                            Ok((channels, unresolved, over_limit)) => {
+                                to_service
+                                    .send(BotRequests::AddUserChannels(AddUserChannels {
+                                        user_id,
+                                        chat_id,
+                                        channels,
+                                        unresolved,
+                                        over_limit,
+                                    }))
+                                    .await;
+                                None
                             }
-                            BotCommand::Start => {
-                                to_service.send(BotRequests::AddUser(
-                                    UserChat{
-                                        user_id: tg_upd.user_id,
-                                        chat_id: tg_upd.chat_id,
-                                })).await;
-                                Some(make_start_resp(tg_upd.chat_id))
+                        }
+                    });
+                    None
+                }
+            },
+            BotCommand::Stats => {
+                to_service.send(BotRequests::Stats(tg_upd.chat_id)).await;
+                None
+            }
+            BotCommand::Broadcast(text) if text.trim().is_empty() => {
+                Some(make_text_resp(tg_upd.chat_id, "nothing to broadcast"))
+            }
+            BotCommand::Broadcast(text) => {
+                to_service
+                    .send(BotRequests::Broadcast(Broadcast {
+                        chat_id: tg_upd.chat_id,
+                        text: text.trim().to_string(),
+                    }))
+                    .await;
+                None
+            }
+            BotCommand::Ban(args) => match args.trim().parse::<i64>() {
+                Ok(user_id) if !admins.contains(&user_id) => {
+                    to_service
+                        .send(BotRequests::BanUser(BanUser {
+                            chat_id: tg_upd.chat_id,
+                            user_id,
+                        }))
+                        .await;
+                    None
+                }
+                _ => Some(make_text_resp(
+                    tg_upd.chat_id,
+                    "specify the id of a user to ban",
+                )),
+            },
+            BotCommand::Refetch(args) => {
+                let (channel_names, invalid) = opml::parse_links(&feeds_base_url, args);
+                if channel_names.len() != 1 || !invalid.is_empty() {
+                    Some(make_text_resp(
+                        tg_upd.chat_id,
+                        "specify a single channel to refetch",
+                    ))
+                } else {
+                    let (client, to_service) = (client.clone(), to_service.clone());
+                    let chat_id = tg_upd.chat_id;
+                    self.spawn_command(async move {
+                        let (mut channels, _) = resolve_channels(&client, channel_names, 1).await;
+                        match channels.pop() {
+                            None => Some(make_text_resp(chat_id, "channel not found")),
+                            Some(channel) => {
+                                to_service
+                                    .send(BotRequests::RefetchChannel(RefetchChannel {
+                                        chat_id,
+                                        channel,
+                                    }))
+                                    .await;
+                                None
                             }
-                        };
-                        if let Some(msg) = msg {
-                            client.send_message(msg).await;
                         }
-                    },
+                    });
+                    None
+                }
+            }
+            BotCommand::Collection(args) => match parse_collection_action(&feeds_base_url, args) {
+                Some(action) => {
+                    to_service
+                        .send(BotRequests::Collection(CollectionRequest {
+                            user_id: tg_upd.user_id,
+                            chat_id: tg_upd.chat_id,
+                            action,
+                        }))
+                        .await;
+                    None
+                }
+                None => Some(make_collection_usage_resp(tg_upd.chat_id)),
+            },
+            BotCommand::Webhook(args) => match parse_webhook_action(args) {
+                Some(action) => {
+                    to_service
+                        .send(BotRequests::Webhook(WebhookRequest {
+                            user_id: tg_upd.user_id,
+                            chat_id: tg_upd.chat_id,
+                            action,
+                        }))
+                        .await;
+                    None
+                }
+                None => Some(make_webhook_usage_resp(tg_upd.chat_id)),
+            },
+            BotCommand::Health => Some(make_health_resp(tg_upd.chat_id, &health)),
+            BotCommand::Stop => {
+                to_service
+                    .send(BotRequests::RemoveUser(UserChat {
+                        user_id: tg_upd.user_id,
+                        chat_id: tg_upd.chat_id,
+                    }))
+                    .await;
+                Some(make_stop_resp(tg_upd.chat_id))
+            }
+            BotCommand::Start(_) => {
+                to_service
+                    .send(BotRequests::AddUser(UserChat {
+                        user_id: tg_upd.user_id,
+                        chat_id: tg_upd.chat_id,
+                    }))
+                    .await;
+                Some(make_start_resp(tg_upd.chat_id))
+            }
+        };
+        if let Some(msg) = msg {
+            deliver(client, msg).await;
+        }
+    }
 
-                    Some(from_srv) = from_service.recv() => {
-                        match from_srv {
-                            BotResponses::ListChannels(channels) => {
-                                client.send_message(make_list_channels(channels.chat_id, channels.channels)).await;
+    /// Runs a command which waits on TDLib off the update loop, so a flood wait does not hold
+    /// up the commands of other users and the outcomes of deliveries. The reply, if any,
+    /// is sent once the command is done.
+    fn spawn_command<F>(&self, command: F)
+    where
+        F: Future<Output = Option<SendMessage>> + Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::spawn(
+            async move {
+                if let Some(msg) = command.await {
+                    deliver(&client, msg).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    async fn handle_service_response(&mut self, from_srv: BotResponses) {
+        let client = &self.client;
+        let feeds_base_url = &self.settings.feeds_base_url;
+        match from_srv {
+            BotResponses::ListChannels(channels) => {
+                deliver(
+                    client,
+                    make_list_channels(channels.chat_id, channels.channels),
+                )
+                .await;
+            }
+            BotResponses::ExportChannels(channels) => {
+                match write_export(feeds_base_url, &channels).await {
+                    Ok(path) => match path.to_str() {
+                        Some(p) => {
+                            let msg = make_document_resp(channels.chat_id, p.to_string());
+                            if let Ok(message) = try_deliver(client, msg).await {
+                                self.pending.insert(message.id(), PendingSend::Export(path));
                             }
                         }
+                        None => log::error!("invalid export path: {path:?}"),
+                    },
+                    Err(err) => {
+                        log::error!("cannot export channels: {err}");
+                        deliver(
+                            client,
+                            make_text_resp(channels.chat_id, "cannot export channels"),
+                        )
+                        .await;
                     }
                 }
             }
-        }))
+            BotResponses::ChannelsAdded(added) => {
+                deliver(client, make_channels_added_resp(added)).await;
+            }
+            BotResponses::ChannelsRemoved(removed) => {
+                deliver(client, make_channels_removed_resp(removed)).await;
+            }
+            BotResponses::Stats(stats) => {
+                deliver(client, make_stats_resp(stats)).await;
+            }
+            BotResponses::Text(text) => {
+                deliver(client, make_text_resp(text.chat_id, text.text)).await;
+            }
+            BotResponses::Post(post) => self.send_post(post).await,
+            BotResponses::Collections(collections) => {
+                deliver(client, make_collections_resp(feeds_base_url, collections)).await;
+            }
+        }
+    }
+
+    async fn report_blocked(&self, user_id: i64, chat_id: i64) {
+        let blocked = BotRequests::Blocked(UserChat { user_id, chat_id });
+        if let Err(err) = self.to_service.send(blocked).await {
+            log::error!("cannot report blocked user: {err}");
+        }
+    }
+
+    /// Unlike replies, posts are retried by the service, so the outcome is reported back:
+    /// at once if TDLib rejects the message, otherwise once TDLib reports it is sent.
+    #[tracing::instrument(skip_all, fields(chat_id = post.chat_id, delivery_id = post.delivery_id))]
+    async fn send_post(&mut self, post: BotResponsePost) {
+        match self
+            .client
+            .send_message(make_text_resp(post.chat_id, post.text))
+            .await
+        {
+            Ok(message) => {
+                let pending = PendingSend::Post {
+                    delivery_id: post.delivery_id,
+                    chat_id: post.chat_id,
+                };
+                self.pending.insert(message.id(), pending);
+            }
+            Err(err) => {
+                metrics::DELIVERIES.with_label_values(&["failed"]).inc();
+                let outcome = DeliveryOutcome::from_error(&err);
+                self.report_delivered(post.delivery_id, post.chat_id, outcome);
+            }
+        }
+    }
+
+    /// Users who have blocked the bot are reported to the service, TDLib accepts messages
+    /// to them and only fails to send them later.
+    async fn handle_send_outcome(&mut self, chat_id: i64, message_id: i64, error: Option<String>) {
+        match self.pending.remove(&message_id) {
+            Some(PendingSend::Post {
+                delivery_id,
+                chat_id,
+            }) => {
+                let outcome = match error {
+                    None => {
+                        metrics::DELIVERIES.with_label_values(&["ok"]).inc();
+                        DeliveryOutcome::Sent
+                    }
+                    Some(error) => {
+                        log::warn!("cannot send delivery {delivery_id}: {error}");
+                        metrics::DELIVERIES.with_label_values(&["failed"]).inc();
+                        DeliveryOutcome::from_error(&anyhow!(error))
+                    }
+                };
+                self.report_delivered(delivery_id, chat_id, outcome);
+            }
+            // an exported file is removed once its path is dropped
+            pending => {
+                let error = match error {
+                    Some(error) => anyhow!(error),
+                    None => return,
+                };
+                match pending {
+                    Some(_) => log::error!("cannot send exported channels: {error}"),
+                    None => log::warn!("cannot send message: {error}"),
+                }
+                if is_blocked(&error) {
+                    // users chat with the bot privately, so the chat id is the user id
+                    self.report_blocked(chat_id, chat_id).await;
+                }
+            }
+        }
     }
+
+    fn report_delivered(&self, delivery_id: i64, chat_id: i64, outcome: DeliveryOutcome) {
+        let delivered = Delivered {
+            delivery_id,
+            chat_id,
+            outcome,
+        };
+        if let Err(err) = self.delivered.send(delivered) {
+            log::error!("cannot report delivery: {err}");
+        }
+    }
+}
+
+/// Sends the message, failures are only logged since there is no one to report them to.
+/// Returns whether the message is sent.
+async fn deliver(client: &impl TdApi, msg: SendMessage) -> bool {
+    try_deliver(client, msg).await.is_ok()
 }
 
-pub fn init_bot_updates_reader(mut receiver: Receiver<Box<Update>>) -> TgUpdate {
-    let (sx, rx) = mpsc::channel(2000);
+/// Sends the message, counting and logging a failure. Returns the message TDLib is sending.
+#[tracing::instrument(skip_all, fields(chat_id = msg.chat_id()))]
+async fn try_deliver(client: &impl TdApi, msg: SendMessage) -> Result<Message> {
+    let chat_id = msg.chat_id();
+    match client.send_message(msg).await {
+        Ok(message) => {
+            metrics::DELIVERIES.with_label_values(&["ok"]).inc();
+            Ok(message)
+        }
+        Err(err) => {
+            log::warn!("cannot send message to {chat_id}: {err}");
+            metrics::DELIVERIES.with_label_values(&["failed"]).inc();
+            Err(err)
+        }
+    }
+}
+
+pub fn init_bot_updates_reader(
+    mut receiver: Receiver<Box<Update>>,
+    settings: &IngestionSettings,
+) -> TgUpdate {
+    let (sx, rx) = mpsc::channel(settings.updates_queue_size);
+    metrics::watch_channel("bot_updates", &sx);
+    let send_timeout = settings.send_update_timeout();
 
     tokio::spawn(async move {
         while let Some(update) = receiver.recv().await {
+            metrics::UPDATES_RECEIVED.with_label_values(&["bot"]).inc();
             let new_update = match update.as_ref() {
                 Update::NewMessage(new_message) => handle_message_to_bot(new_message),
+                Update::NewCallbackQuery(callback_query) => handle_callback_query(callback_query),
+                Update::ChatMember(chat_member) => handle_chat_member(chat_member),
+                Update::MessageSendSucceeded(succeeded) => Some(send_outcome(
+                    succeeded.message(),
+                    BotCommand::Sent(succeeded.old_message_id()),
+                )),
+                Update::MessageSendFailed(failed) => Some(send_outcome(
+                    failed.message(),
+                    BotCommand::SendFailed(
+                        failed.old_message_id(),
+                        format!("[{}] {}", failed.error_code(), failed.error_message()),
+                    ),
+                )),
                 _ => None,
             };
             if let Some(new_update) = new_update {
-                if let Err(err) = sx.send_timeout(new_update, SEND_UPDATE_TIMEOUT).await {
+                if let Err(err) = sx.send_timeout(new_update, send_timeout).await {
                     log::error!("cannot send new update: {}", err);
                 }
             }
@@ -216,60 +1110,476 @@ pub fn init_bot_updates_reader(mut receiver: Receiver<Box<Update>>) -> TgUpdate
 }
 
 fn handle_message_to_bot(new_message: &UpdateNewMessage) -> Option<BotUpdate> {
-    log::debug!("{new_message:?}");
-    match new_message.message().content() {
-        MessageContent::MessageText(message_text) => {
-            let text = message_text.text();
-            let is_bot_command = text.entities().iter().any(|te| match te.type_() {
-                TextEntityType::BotCommand(_) => true,
-                _ => false,
-            });
-
-            match new_message.message().sender_id() {
-                MessageSender::_Default => {
-                    todo!()
-                }
-                MessageSender::Chat(_) => {
-                    todo!()
+    let forward_origin = new_message
+        .message()
+        .forward_info()
+        .as_ref()
+        .map(|fi| fi.origin());
+    let message = match (forward_origin, new_message.message().content()) {
+        (Some(MessageForwardOrigin::Channel(origin)), _) => BotCommand::Forwarded(origin.chat_id()),
+        (_, MessageContent::MessageText(message_text)) => parse_bot_command(message_text.text()),
+        (_, MessageContent::MessageDocument(message_document)) => {
+            let document = message_document.document();
+            let file = document.document();
+            BotCommand::Import(Some(ImportFile {
+                id: file.id(),
+                name: document.file_name().clone(),
+                mime_type: document.mime_type().clone(),
+                size: i64::from(file.size())
+                    .max(i64::from(file.expected_size()))
+                    .try_into()
+                    .unwrap_or_default(),
+            }))
+        }
+        _ => return None,
+    };
+
+    match new_message.message().sender_id() {
+        // posts of channels and anonymous admins of groups, there is no user to reply to
+        MessageSender::_Default | MessageSender::Chat(_) => None,
+        MessageSender::User(user) => Some(BotUpdate {
+            chat_id: new_message.message().chat_id(),
+            user_id: user.user_id(),
+            span: update_span(
+                new_message.message().chat_id(),
+                user.user_id(),
+                new_message.message().id(),
+                &message,
+            ),
+            message,
+        }),
+    }
+}
+
+fn handle_callback_query(callback_query: &UpdateNewCallbackQuery) -> Option<BotUpdate> {
+    let data = match callback_query.payload() {
+        CallbackQueryPayload::Data(payload) => BASE64.decode(payload.data()).ok()?,
+        _ => return None,
+    };
+    let channel_name = String::from_utf8(data)
+        .ok()?
+        .strip_prefix(ADD_CHANNEL_CALLBACK_PREFIX)?
+        .to_string();
+    let message = BotCommand::ConfirmAdd(callback_query.id(), channel_name);
+    Some(BotUpdate {
+        chat_id: callback_query.chat_id(),
+        user_id: callback_query.sender_user_id(),
+        span: update_span(
+            callback_query.chat_id(),
+            callback_query.sender_user_id(),
+            callback_query.message_id(),
+            &message,
+        ),
+        message,
+    })
+}
+
+/// The bot gets banned in the private chat once the user blocks it.
+fn handle_chat_member(chat_member: &UpdateChatMember) -> Option<BotUpdate> {
+    let chat_id = chat_member.chat_id();
+    let user_id = chat_member.actor_user_id();
+    // users chat with the bot privately, so the chat id is the user id
+    if chat_id != user_id {
+        return None;
+    }
+    match chat_member.new_chat_member().status() {
+        ChatMemberStatus::Banned(_) => {}
+        _ => return None,
+    }
+    let message = BotCommand::Blocked;
+    Some(BotUpdate {
+        chat_id,
+        user_id,
+        span: update_span(chat_id, user_id, 0, &message),
+        message,
+    })
+}
+
+/// Outcome of sending a message of the bot, it is sent to a private chat with the user.
+fn send_outcome(message: &Message, outcome: BotCommand) -> BotUpdate {
+    let chat_id = message.chat_id();
+    BotUpdate {
+        chat_id,
+        user_id: chat_id,
+        span: update_span(chat_id, chat_id, message.id(), &outcome),
+        message: outcome,
+    }
+}
+
+/// Only the kind of the command is recorded, its text may be private.
+fn update_span(chat_id: i64, user_id: i64, message_id: i64, message: &BotCommand) -> Span {
+    tracing::info_span!("bot_update", chat_id, user_id, message_id, command = %message)
+}
+
+fn parse_bot_command(text: &FormattedText) -> BotCommand {
+    let is_bot_command = text.entities().iter().any(|te| match te.type_() {
+        TextEntityType::BotCommand(_) => true,
+        _ => false,
+    });
+    if !is_bot_command {
+        return BotCommand::Text(text.text().clone());
+    }
+    match text.text() {
+        x if x.starts_with("/add") => {
+            BotCommand::Add(text.text().clone().chars().skip("/add".len()).collect())
+        }
+        x if x.starts_with("/remove") => {
+            BotCommand::Remove(text.text().clone().chars().skip("/remove".len()).collect())
+        }
+        x if x.starts_with("/list") => BotCommand::List,
+        x if x.starts_with("/start") => BotCommand::Start(
+            x.chars()
+                .skip("/start".len())
+                .collect::<String>()
+                .trim()
+                .to_string(),
+        ),
+        x if x.starts_with("/stop") => BotCommand::Stop,
+        x if x.starts_with("/export") => BotCommand::Export,
+        x if x.starts_with("/import") => BotCommand::Import(None),
+        x if x.starts_with("/stats") => BotCommand::Stats,
+        x if x.starts_with("/broadcast") => BotCommand::Broadcast(
+            text.text()
+                .clone()
+                .chars()
+                .skip("/broadcast".len())
+                .collect(),
+        ),
+        x if x.starts_with("/ban") => {
+            BotCommand::Ban(text.text().clone().chars().skip("/ban".len()).collect())
+        }
+        x if x.starts_with("/refetch") => {
+            BotCommand::Refetch(text.text().clone().chars().skip("/refetch".len()).collect())
+        }
+        x if x.starts_with("/health") => BotCommand::Health,
+        x if x.starts_with("/collection") => BotCommand::Collection(
+            text.text()
+                .clone()
+                .chars()
+                .skip("/collection".len())
+                .collect(),
+        ),
+        x if x.starts_with("/webhook") => {
+            BotCommand::Webhook(text.text().clone().chars().skip("/webhook".len()).collect())
+        }
+        _ => BotCommand::Invalid,
+    }
+}
+
+/// Parses `[list]`, `create <name>`, `delete <name>`, `add <name> <channels>`
+/// or `remove <name> <channels>`. Returns `None` if the arguments make no sense.
+fn parse_collection_action(feeds_base_url: &str, args: &str) -> Option<CollectionAction> {
+    let mut words = args.split_whitespace();
+    let verb = match words.next() {
+        None => return Some(CollectionAction::List),
+        Some(verb) => verb.to_lowercase(),
+    };
+    if verb == "list" {
+        return words.next().is_none().then_some(CollectionAction::List);
+    }
+    let name = parse_collection_name(words.next()?)?;
+    let rest = words.collect::<Vec<_>>().join(" ");
+    match verb.as_str() {
+        "create" if rest.is_empty() => Some(CollectionAction::Create(name)),
+        "delete" if rest.is_empty() => Some(CollectionAction::Delete(name)),
+        "add" | "remove" => {
+            let (channel_names, invalid) = opml::parse_links(feeds_base_url, &rest);
+            if channel_names.is_empty() && invalid.is_empty() {
+                return None;
+            }
+            Some(if verb == "add" {
+                CollectionAction::Add {
+                    name,
+                    channel_names,
+                    invalid,
                 }
-                MessageSender::User(user) => {
-                    let message: BotCommand;
-                    if !is_bot_command {
-                        message = BotCommand::Invalid
-                    } else {
-                        message = match text.text() {
-                            x if x.starts_with("/add") => BotCommand::Add(
-                                text.text().clone().chars().skip("/add".len()).collect(),
-                            ),
-                            x if x.starts_with("/remove") => BotCommand::Remove(
-                                text.text().clone().chars().skip("/remove".len()).collect(),
-                            ),
-                            x if x.starts_with("/list") => BotCommand::List,
-                            x if x.starts_with("/start") => BotCommand::Start,
-                            x if x.starts_with("/stop") => BotCommand::Stop,
-                            _ => BotCommand::Invalid,
-                        };
-                    }
-                    return Some(BotUpdate {
-                        chat_id: new_message.message().chat_id(),
-                        user_id: user.user_id(),
-                        message,
-                    });
+            } else {
+                CollectionAction::Remove {
+                    name,
+                    channel_names,
+                    invalid,
                 }
-            }
+            })
         }
         _ => None,
     }
 }
 
-fn make_channel_not_found_resp(chat_id: i64, channel_name: &str) -> SendMessage {
+/// Parses `[list]`, `add <url>` or `remove <url>`. Returns `None` if the arguments make no sense
+/// or the URL to add points to the host tgfeed runs on or to its network.
+fn parse_webhook_action(args: &str) -> Option<WebhookAction> {
+    let words: Vec<_> = args.split_whitespace().collect();
+    match words.as_slice() {
+        [] => Some(WebhookAction::List),
+        [verb] if verb.eq_ignore_ascii_case("list") => Some(WebhookAction::List),
+        [verb, url] if webhooks::is_valid_url(url) => match verb.to_lowercase().as_str() {
+            "add" if webhooks::is_public_url(url) => Some(WebhookAction::Add(url.to_string())),
+            "remove" => Some(WebhookAction::Remove(url.to_string())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Collection names are short and case insensitive, so they are easy to type.
+fn parse_collection_name(name: &str) -> Option<String> {
+    let name = name.to_lowercase();
+    let valid = (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then_some(name)
+}
+
+/// Commands shown in the bot menu, admin commands are shown only if `with_admin` is set.
+fn bot_commands(with_admin: bool) -> Vec<TdLibBotCommand> {
+    BotCommand::iter()
+        .filter(|cmd| with_admin || !cmd.is_admin())
+        .filter_map(|cmd| {
+            let message = cmd.get_message();
+            let det_message = cmd.get_detailed_message();
+
+            match (message, det_message) {
+                (Some(message), Some(det_message)) => Some(
+                    TdLibBotCommand::builder()
+                        .command(message)
+                        .description(det_message)
+                        .build(),
+                ),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Downloads an uploaded file and resolves the channels mentioned in it, up to `max_channels`.
+/// Returns resolved channels along with the entries which cannot be resolved
+/// and the ones over the limit. Files over `max_size` bytes are not read.
+async fn import_channels(
+    client: &impl TdApi,
+    feeds_base_url: &str,
+    file_id: i32,
+    max_size: u64,
+    concurrency: usize,
+    max_channels: usize,
+) -> Result<(Vec<models::NewChannel>, Vec<String>, Vec<String>)> {
+    let file = client
+        .download_file(
+            DownloadFile::builder()
+                .file_id(file_id)
+                .priority(1)
+                .synchronous(true)
+                .build(),
+        )
+        .await?;
+    // the size telegram reports beforehand may be unknown
+    let path = file.local().path();
+    let size = tokio::fs::metadata(path).await?.len();
+    if size > max_size {
+        return Err(anyhow!("file {file_id} is too big: {size} bytes"));
+    }
+    let content = tokio::fs::read_to_string(path).await?;
+    let (channel_names, invalid) = opml::parse(feeds_base_url, &content)?;
+    let (channel_names, over_limit) = split_over_limit(channel_names, max_channels);
+    let (channels, mut unresolved) = resolve_channels(client, channel_names, concurrency).await;
+    unresolved.extend(invalid);
+    Ok((channels, unresolved, over_limit))
+}
+
+/// Splits off the names over `max`, no one can subscribe to them anyway; 0 means unlimited.
+fn split_over_limit(mut channel_names: Vec<String>, max: usize) -> (Vec<String>, Vec<String>) {
+    let over_limit = if max > 0 && channel_names.len() > max {
+        channel_names.split_off(max)
+    } else {
+        vec![]
+    };
+    (channel_names, over_limit)
+}
+
+/// Searches channels concurrently, at most `concurrency` requests at a time.
+/// Returns found channels along with the names which cannot be found.
+async fn resolve_channels(
+    client: &impl TdApi,
+    channel_names: Vec<String>,
+    concurrency: usize,
+) -> (Vec<models::NewChannel>, Vec<String>) {
+    let results: Vec<_> = futures::stream::iter(channel_names)
+        .map(|channel_name| async move {
+            let res = client
+                .search_public_chat(SearchPublicChat::builder().username(&channel_name).build())
+                .await;
+            (channel_name, res)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut channels = Vec::with_capacity(results.len());
+    let mut unresolved = vec![];
+    for (channel_name, res) in results {
+        match res {
+            Ok(ch) => channels.push(models::NewChannel {
+                title: ch.title().trim().to_string(),
+                telegram_id: ch.id(),
+                username: channel_name,
+            }),
+            Err(err) => {
+                log::debug!("cannot find channel {channel_name}: {err}");
+                unresolved.push(channel_name)
+            }
+        }
+    }
+    (channels, unresolved)
+}
+
+/// Returns the channel if it has a public username, private channels cannot be searched by name.
+async fn get_public_channel(
+    client: &impl TdApi,
+    chat_id: i64,
+) -> Result<Option<models::NewChannel>> {
+    let chat = client
+        .get_chat(GetChat::builder().chat_id(chat_id).build())
+        .await?;
+    let supergroup_id = match chat.type_() {
+        ChatType::Supergroup(sg) if sg.is_channel() => sg.supergroup_id(),
+        _ => return Ok(None),
+    };
+    let sg = client
+        .get_supergroup(
+            GetSupergroup::builder()
+                .supergroup_id(supergroup_id)
+                .build(),
+        )
+        .await?;
+    if sg.username().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(models::NewChannel {
+        title: chat.title().trim().to_string(),
+        telegram_id: chat.id(),
+        username: sg.username().clone(),
+    }))
+}
+
+/// Writes the channels to a file with a random name readable only by the owner,
+/// the file is removed once the returned path is dropped.
+async fn write_export(
+    feeds_base_url: &str,
+    channels: &BotResponseListChannels,
+) -> Result<TempPath> {
+    let path = tempfile::Builder::new()
+        .prefix("tgfeed-export-")
+        .suffix(".opml")
+        .tempfile()?
+        .into_temp_path();
+    tokio::fs::write(&path, opml::render(feeds_base_url, &channels.channels)).await?;
+    Ok(path)
+}
+
+fn make_channels_added_resp(resp: BotResponseChannelsAdded) -> SendMessage {
+    let mut s = "".to_string();
+    append_channels(&mut s, "added", &resp.added);
+    append_channels(&mut s, "already added", &resp.already_present);
+    append_channels(&mut s, "not found", &resp.unresolved);
+    append_channels(&mut s, "not added, limit reached", &resp.over_limit);
+    make_text_resp(resp.chat_id, s)
+}
+
+fn make_channels_removed_resp(resp: BotResponseChannelsRemoved) -> SendMessage {
+    let mut s = "".to_string();
+    append_channels(&mut s, "removed", &resp.removed);
+    append_channels(&mut s, "not found", &resp.not_found);
+    make_text_resp(resp.chat_id, s)
+}
+
+fn append_channels(s: &mut String, header: &str, channels: &[String]) {
+    if channels.is_empty() {
+        return;
+    }
+    *s += format!("{}:\n{}\n", header, channels.join("\n")).as_str();
+}
+
+fn make_collections_resp(feeds_base_url: &str, resp: BotResponseCollections) -> SendMessage {
+    let mut s = resp.note.map(|note| note + "\n\n").unwrap_or_default();
+    if resp.collections.is_empty() {
+        s += "no collections, create one with /collection create <name>";
+    }
+    for c in resp.collections {
+        let feed_url = opml::collection_feed_url(feeds_base_url, &c.token);
+        s += format!(
+            "{} ({} channels)\nfeed: {feed_url}\ndigest: {feed_url}/digest\n",
+            c.name, c.channels
+        )
+        .as_str();
+    }
+    make_text_resp(resp.chat_id, s)
+}
+
+fn make_collection_usage_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(
+        chat_id,
+        "usage:\n/collection list\n/collection create <name>\n/collection delete <name>\n\
+        /collection add <name> <channels>\n/collection remove <name> <channels>",
+    )
+}
+
+fn make_webhook_usage_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(
+        chat_id,
+        "usage:\n/webhook list\n/webhook add <url>\n/webhook remove <url>",
+    )
+}
+
+fn make_no_channels_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(chat_id, "no channels specified")
+}
+
+fn make_invalid_request_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(chat_id, "invalid request")
+}
+
+fn make_rate_limited_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(chat_id, "too many requests, try again in a minute")
+}
+
+fn make_private_bot_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(
+        chat_id,
+        "this bot is private, ask the admin for an invite link",
+    )
+}
+
+fn make_private_channel_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(
+        chat_id,
+        "the channel has no public username and cannot be added",
+    )
+}
+
+fn make_confirm_add_resp(chat_id: i64, channel: &models::NewChannel) -> SendMessage {
+    let data = format!("{}{}", ADD_CHANNEL_CALLBACK_PREFIX, channel.username);
     SendMessage::builder()
         .chat_id(chat_id)
+        .reply_markup(ReplyMarkup::InlineKeyboard(
+            ReplyMarkupInlineKeyboard::builder()
+                .rows(vec![vec![InlineKeyboardButton::builder()
+                    .text("subscribe")
+                    .type_(InlineKeyboardButtonType::Callback(
+                        InlineKeyboardButtonTypeCallback::builder()
+                            .data(BASE64.encode(data))
+                            .build(),
+                    ))
+                    .build()]])
+                .build(),
+        ))
         .input_message_content(InputMessageContent::InputMessageText(
             InputMessageText::builder()
                 .text(
                     FormattedText::builder()
-                        .text(format!("channel {} not found", channel_name))
+                        .text(format!(
+                            "subscribe to {} ({})?",
+                            channel.title, channel.username
+                        ))
                         .build(),
                 )
                 .build(),
@@ -277,16 +1587,31 @@ fn make_channel_not_found_resp(chat_id: i64, channel_name: &str) -> SendMessage
         .build()
 }
 
-fn make_channel_added_resp(chat_id: i64, channel_name: &str) -> SendMessage {
-    make_text_resp(chat_id, format!("channel {} added", channel_name))
-}
-
-fn make_channel_removed_resp(chat_id: i64, channel_name: &str) -> SendMessage {
-    make_text_resp(chat_id, format!("channel {} removed", channel_name))
+fn make_stats_resp(resp: BotResponseStats) -> SendMessage {
+    let stats = resp.stats;
+    make_text_resp(
+        resp.chat_id,
+        format!(
+            "users: {} ({} enabled)\nchannels: {}\nsubscriptions: {}\nposts: {} ({} in the last 24 hours)",
+            stats.users,
+            stats.enabled_users,
+            stats.channels,
+            stats.subscriptions,
+            stats.posts,
+            stats.posts_last_day
+        ),
+    )
 }
 
-fn make_invalid_request_resp(chat_id: i64) -> SendMessage {
-    make_text_resp(chat_id, "invalid request")
+fn make_health_resp(chat_id: i64, health: &Health) -> SendMessage {
+    let mut s = "".to_string();
+    for (component, state) in health.states() {
+        s += format!("{component}: {state}\n").as_str();
+    }
+    if s.is_empty() {
+        s = "no telegram components started".to_string();
+    }
+    make_text_resp(chat_id, s)
 }
 
 fn make_start_resp(chat_id: i64) -> SendMessage {
@@ -308,6 +1633,26 @@ fn make_text_resp<T: AsRef<str>>(chat_id: i64, text: T) -> SendMessage {
         .build()
 }
 
+fn make_import_usage_resp(chat_id: i64) -> SendMessage {
+    make_text_resp(
+        chat_id,
+        "send an OPML file or a text file with t.me links to import channels",
+    )
+}
+
+fn make_document_resp(chat_id: i64, path: String) -> SendMessage {
+    SendMessage::builder()
+        .chat_id(chat_id)
+        .input_message_content(InputMessageContent::InputMessageDocument(
+            InputMessageDocument::builder()
+                .document(InputFile::Local(
+                    InputFileLocal::builder().path(path).build(),
+                ))
+                .build(),
+        ))
+        .build()
+}
+
 fn make_list_channels(chat_id: i64, channels: Vec<models::Channel>) -> SendMessage {
     let mut s = "".to_string();
     for ch in channels {
@@ -322,3 +1667,979 @@ fn make_list_channels(chat_id: i64, channels: Vec<models::Channel>) -> SendMessa
         ))
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::LimitsSettings;
+    use crate::telegram::fake::FakeTdApi;
+    use crate::telegram::{Component, ComponentState};
+    use rust_tdlib::types::{
+        CallbackQueryPayloadData, ChatMember, ChatMemberStatusBanned, Document, File, Message,
+        MessageDocument, MessageForwardInfo, MessageForwardOriginChannel, MessageSenderChat,
+        MessageSenderUser, MessageText, TextEntity, TextEntityTypeBotCommand,
+        UpdateMessageSendFailed, UpdateMessageSendSucceeded,
+    };
+
+    const BOT_ID: i64 = 1;
+    const USER_ID: i64 = 100;
+    const ADMIN_ID: i64 = 101;
+    const CHAT_ID: i64 = 200;
+    const CHANNEL_ID: i64 = -1001;
+    const BASE_URL: &str = "https://feeds.example.com";
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Harness {
+        api: FakeTdApi,
+        sent: mpsc::UnboundedReceiver<SendMessage>,
+        updates: Sender<Box<Update>>,
+        requests: Receiver<BotRequests>,
+        delivered: mpsc::UnboundedReceiver<Delivered>,
+        responses: Sender<BotResponses>,
+        shutdown: watch::Sender<bool>,
+        health: Health,
+        handle: JoinHandle<()>,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            Self::with_limits(LimitsSettings::default()).await
+        }
+
+        async fn with_limits(limits: LimitsSettings) -> Self {
+            let (api, sent) = FakeTdApi::new(BOT_ID);
+            api.add_channel(CHANNEL_ID, "rustlang", "Rust");
+            let (updates, updates_rx) = mpsc::channel(10);
+            let (to_service, requests) = mpsc::channel(10);
+            let (delivered_tx, delivered) = mpsc::unbounded_channel();
+            let (responses, from_service) = mpsc::channel(10);
+            let (shutdown, shutdown_rx) = watch::channel(false);
+            let tg_update = init_bot_updates_reader(updates_rx, &IngestionSettings::default());
+            let health = Health::default();
+            let settings = BotSettings {
+                feeds_base_url: BASE_URL.to_string(),
+                resolve_concurrency: 2,
+                admins: vec![ADMIN_ID],
+                max_channels_per_user: limits.max_channels_per_user,
+                commands_per_minute: limits.commands_per_minute,
+                max_import_size: limits.max_import_kb * 1024,
+                max_imports: limits.max_imports,
+            };
+            let access = Access::new(&limits, &settings.admins);
+            let handle = BotClient::new(api.clone(), settings, None, health.clone(), access)
+                .start(
+                    tg_update,
+                    Arc::new(Mutex::new(from_service)),
+                    to_service,
+                    delivered_tx,
+                    shutdown_rx,
+                )
+                .await
+                .unwrap();
+            Self {
+                api,
+                sent,
+                updates,
+                requests,
+                delivered,
+                responses,
+                shutdown,
+                health,
+                handle,
+            }
+        }
+
+        async fn send(&self, update: Update) {
+            self.updates.send(Box::new(update)).await.unwrap();
+        }
+
+        /// Reports the message with the given id as sent to the chat or failed with the error,
+        /// like TDLib does once it is done with the message.
+        async fn send_outcome(&self, message_id: i64, chat_id: i64, error: Option<(i32, &str)>) {
+            let message = Message::builder().id(message_id).chat_id(chat_id).build();
+            let update = match error {
+                None => Update::MessageSendSucceeded(
+                    UpdateMessageSendSucceeded::builder()
+                        .message(message)
+                        .old_message_id(message_id)
+                        .build(),
+                ),
+                Some((code, error)) => Update::MessageSendFailed(
+                    UpdateMessageSendFailed::builder()
+                        .message(message)
+                        .old_message_id(message_id)
+                        .error_code(code)
+                        .error_message(error)
+                        .build(),
+                ),
+            };
+            self.send(update).await;
+        }
+
+        async fn next_sent(&mut self) -> SendMessage {
+            tokio::time::timeout(TIMEOUT, self.sent.recv())
+                .await
+                .expect("no message sent")
+                .unwrap()
+        }
+
+        async fn next_request(&mut self) -> BotRequests {
+            tokio::time::timeout(TIMEOUT, self.requests.recv())
+                .await
+                .expect("no request sent")
+                .unwrap()
+        }
+
+        async fn next_delivered(&mut self) -> Delivered {
+            tokio::time::timeout(TIMEOUT, self.delivered.recv())
+                .await
+                .expect("no outcome reported")
+                .unwrap()
+        }
+    }
+
+    fn message(user_id: i64, content: MessageContent) -> Message {
+        Message::builder()
+            .chat_id(CHAT_ID)
+            .sender_id(MessageSender::User(
+                MessageSenderUser::builder().user_id(user_id).build(),
+            ))
+            .content(content)
+            .build()
+    }
+
+    fn text_update(user_id: i64, text: &str) -> Update {
+        let mut formatted = FormattedText::builder();
+        formatted.text(text);
+        if text.starts_with('/') {
+            let command_len = text.split_whitespace().next().unwrap().len();
+            formatted.entities(vec![TextEntity::builder()
+                .offset(0)
+                .length(command_len as i32)
+                .type_(TextEntityType::BotCommand(
+                    TextEntityTypeBotCommand::builder().build(),
+                ))
+                .build()]);
+        }
+        let content =
+            MessageContent::MessageText(MessageText::builder().text(formatted.build()).build());
+        Update::NewMessage(
+            UpdateNewMessage::builder()
+                .message(message(user_id, content))
+                .build(),
+        )
+    }
+
+    fn forwarded_update(channel_id: i64) -> Update {
+        let msg = Message::builder()
+            .chat_id(CHAT_ID)
+            .sender_id(MessageSender::User(
+                MessageSenderUser::builder().user_id(USER_ID).build(),
+            ))
+            .content(MessageContent::MessageText(
+                MessageText::builder()
+                    .text(FormattedText::builder().text("a post").build())
+                    .build(),
+            ))
+            .forward_info(
+                MessageForwardInfo::builder()
+                    .origin(MessageForwardOrigin::Channel(
+                        MessageForwardOriginChannel::builder()
+                            .chat_id(channel_id)
+                            .build(),
+                    ))
+                    .build(),
+            )
+            .build();
+        Update::NewMessage(UpdateNewMessage::builder().message(msg).build())
+    }
+
+    fn document_update(file_id: i32, file_name: &str, size: i32) -> Update {
+        let content = MessageContent::MessageDocument(
+            MessageDocument::builder()
+                .document(
+                    Document::builder()
+                        .file_name(file_name)
+                        .document(File::builder().id(file_id).size(size).build())
+                        .build(),
+                )
+                .build(),
+        );
+        Update::NewMessage(
+            UpdateNewMessage::builder()
+                .message(message(USER_ID, content))
+                .build(),
+        )
+    }
+
+    fn callback_update(query_id: i64, data: &str) -> Update {
+        Update::NewCallbackQuery(
+            UpdateNewCallbackQuery::builder()
+                .id(query_id)
+                .chat_id(CHAT_ID)
+                .sender_user_id(USER_ID)
+                .payload(CallbackQueryPayload::Data(
+                    CallbackQueryPayloadData::builder().data(data).build(),
+                ))
+                .build(),
+        )
+    }
+
+    fn text_of(msg: &SendMessage) -> &str {
+        match msg.input_message_content() {
+            InputMessageContent::InputMessageText(text) => text.text().text(),
+            content => panic!("unexpected message content: {content:?}"),
+        }
+    }
+
+    fn callback_data_of(msg: &SendMessage) -> String {
+        let keyboard = match msg.reply_markup() {
+            Some(ReplyMarkup::InlineKeyboard(keyboard)) => keyboard,
+            markup => panic!("unexpected reply markup: {markup:?}"),
+        };
+        match keyboard.rows()[0][0].type_() {
+            InlineKeyboardButtonType::Callback(callback) => callback.data().clone(),
+            button => panic!("unexpected button: {button:?}"),
+        }
+    }
+
+    fn expect_add(request: BotRequests) -> AddUserChannels {
+        match request {
+            BotRequests::AddUserChannels(add) => add,
+            request => panic!("unexpected request: {request:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn start_registers_commands_and_user() {
+        let mut h = Harness::start().await;
+        let commands: Vec<_> = h
+            .api
+            .commands(None)
+            .iter()
+            .map(|c| c.command().clone())
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "/start",
+                "/stop",
+                "/add",
+                "/list",
+                "/remove",
+                "/export",
+                "/import",
+                "/collection",
+                "/webhook"
+            ]
+        );
+        let admin_commands = h.api.commands(Some(ADMIN_ID));
+        assert_eq!(admin_commands.len(), commands.len() + 5);
+        assert!(admin_commands.iter().any(|c| c.command() == "/broadcast"));
+
+        h.send(text_update(USER_ID, "/start")).await;
+        match h.next_request().await {
+            BotRequests::AddUser(user) => {
+                assert_eq!(user.user_id, USER_ID);
+                assert_eq!(user.chat_id, CHAT_ID);
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+        let msg = h.next_sent().await;
+        assert_eq!(msg.chat_id(), CHAT_ID);
+        assert_eq!(text_of(&msg), "started");
+
+        h.send(text_update(USER_ID, "/stop")).await;
+        assert!(matches!(h.next_request().await, BotRequests::RemoveUser(_)));
+        assert_eq!(text_of(&h.next_sent().await), "stopped");
+    }
+
+    #[tokio::test]
+    async fn add_resolves_channels_and_replies_with_summary() {
+        let mut h = Harness::start().await;
+        h.send(text_update(
+            USER_ID,
+            "/add @rustlang https://t.me/missingchan bad!",
+        ))
+        .await;
+
+        let add = expect_add(h.next_request().await);
+        assert_eq!(add.user_id, USER_ID);
+        assert_eq!(add.chat_id, CHAT_ID);
+        assert_eq!(add.channels.len(), 1);
+        assert_eq!(add.channels[0].username, "rustlang");
+        assert_eq!(add.channels[0].title, "Rust");
+        assert_eq!(add.channels[0].telegram_id, CHANNEL_ID);
+        assert_eq!(add.unresolved, vec!["missingchan", "bad!"]);
+
+        h.responses
+            .send(BotResponses::ChannelsAdded(BotResponseChannelsAdded {
+                chat_id: CHAT_ID,
+                added: vec!["rustlang".to_string()],
+                already_present: vec![],
+                unresolved: add.unresolved,
+                over_limit: vec![],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "added:\nrustlang\nnot found:\nmissingchan\nbad!\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn add_without_channels_is_rejected() {
+        let mut h = Harness::start().await;
+        h.send(text_update(USER_ID, "/add")).await;
+        assert_eq!(text_of(&h.next_sent().await), "no channels specified");
+        assert!(h.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_and_list_go_to_the_service() {
+        let mut h = Harness::start().await;
+        h.send(text_update(USER_ID, "/remove t.me/rustlang")).await;
+        match h.next_request().await {
+            BotRequests::RemoveUserChannels(remove) => {
+                assert_eq!(remove.channel_names, vec!["rustlang"]);
+                assert!(remove.invalid.is_empty());
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+
+        h.send(text_update(USER_ID, "/list")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::ListChannels(USER_ID)
+        ));
+        h.responses
+            .send(BotResponses::ListChannels(BotResponseListChannels {
+                chat_id: CHAT_ID,
+                channels: vec![models::Channel {
+                    id: CHANNEL_ID,
+                    title: "Rust".to_string(),
+                    username: "rustlang".to_string(),
+                }],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(text_of(&h.next_sent().await), "rustlang: Rust\n");
+    }
+
+    #[tokio::test]
+    async fn collection_commands_go_to_the_service() {
+        let mut h = Harness::start().await;
+        h.send(text_update(USER_ID, "/collection add Tech @rustlang bad!"))
+            .await;
+        match h.next_request().await {
+            BotRequests::Collection(request) => {
+                assert_eq!(request.user_id, USER_ID);
+                assert_eq!(
+                    request.action,
+                    CollectionAction::Add {
+                        name: "tech".to_string(),
+                        channel_names: vec!["rustlang".to_string()],
+                        invalid: vec!["bad!".to_string()],
+                    }
+                );
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+
+        h.send(text_update(USER_ID, "/collection create")).await;
+        assert!(text_of(&h.next_sent().await).starts_with("usage:"));
+        h.send(text_update(USER_ID, "/collection create no/slashes"))
+            .await;
+        assert!(text_of(&h.next_sent().await).starts_with("usage:"));
+        assert!(h.requests.try_recv().is_err());
+
+        h.responses
+            .send(BotResponses::Collections(BotResponseCollections {
+                chat_id: CHAT_ID,
+                note: Some("collection tech is created".to_string()),
+                collections: vec![models::Collection {
+                    id: 1,
+                    name: "tech".to_string(),
+                    token: "abc".to_string(),
+                    channels: 0,
+                }],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "collection tech is created\n\ntech (0 channels)\n\
+            feed: https://feeds.example.com/collections/abc\n\
+            digest: https://feeds.example.com/collections/abc/digest\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_commands_go_to_the_service() {
+        let mut h = Harness::start().await;
+        h.send(text_update(
+            USER_ID,
+            "/webhook add https://example.com/hook",
+        ))
+        .await;
+        match h.next_request().await {
+            BotRequests::Webhook(request) => {
+                assert_eq!(request.user_id, USER_ID);
+                assert_eq!(
+                    request.action,
+                    WebhookAction::Add("https://example.com/hook".to_string())
+                );
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+        h.send(text_update(USER_ID, "/webhook")).await;
+        match h.next_request().await {
+            BotRequests::Webhook(request) => assert_eq!(request.action, WebhookAction::List),
+            request => panic!("unexpected request: {request:?}"),
+        }
+
+        for url in [
+            "example.com",
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+        ] {
+            h.send(text_update(USER_ID, &format!("/webhook add {url}")))
+                .await;
+            assert!(text_of(&h.next_sent().await).starts_with("usage:"));
+        }
+        h.send(text_update(
+            USER_ID,
+            "/webhook drop https://example.com/hook",
+        ))
+        .await;
+        assert!(text_of(&h.next_sent().await).starts_with("usage:"));
+        assert!(h.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn forwarded_post_subscribes_after_confirmation() {
+        let mut h = Harness::start().await;
+        h.send(forwarded_update(CHANNEL_ID)).await;
+        let confirm = h.next_sent().await;
+        assert_eq!(text_of(&confirm), "subscribe to Rust (rustlang)?");
+
+        h.send(callback_update(42, &callback_data_of(&confirm)))
+            .await;
+        let add = expect_add(h.next_request().await);
+        assert_eq!(add.channels.len(), 1);
+        assert_eq!(add.channels[0].username, "rustlang");
+        assert!(add.unresolved.is_empty());
+        assert_eq!(h.api.answered_callbacks(), vec![42]);
+    }
+
+    #[tokio::test]
+    async fn slow_searches_do_not_hold_up_other_commands() {
+        let mut h = Harness::start().await;
+        let held = h.api.hold("search_public_chat");
+        h.send(text_update(USER_ID, "/add @rustlang")).await;
+        h.send(text_update(ADMIN_ID, "/refetch @rustlang")).await;
+        h.send(text_update(USER_ID + 1, "/list")).await;
+        match h.next_request().await {
+            BotRequests::ListChannels(user_id) => assert_eq!(user_id, USER_ID + 1),
+            request => panic!("unexpected request: {request:?}"),
+        }
+
+        drop(held);
+        let mut resolved = vec![];
+        for _ in 0..2 {
+            match h.next_request().await {
+                BotRequests::AddUserChannels(add) => {
+                    resolved.push(add.channels[0].username.clone())
+                }
+                BotRequests::RefetchChannel(refetch) => resolved.push(refetch.channel.username),
+                request => panic!("unexpected request: {request:?}"),
+            }
+        }
+        assert_eq!(resolved, vec!["rustlang", "rustlang"]);
+    }
+
+    #[tokio::test]
+    async fn forwarded_post_from_private_channel_is_rejected() {
+        let mut h = Harness::start().await;
+        h.api.add_channel(-1002, "", "Private");
+        h.send(forwarded_update(-1002)).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "the channel has no public username and cannot be added"
+        );
+    }
+
+    #[tokio::test]
+    async fn import_resolves_channels_from_file() {
+        let mut h = Harness::start().await;
+        let path = std::env::temp_dir().join("tgfeed-bot-test-import.txt");
+        tokio::fs::write(&path, "t.me/rustlang\n@missingchan\n")
+            .await
+            .unwrap();
+        h.api.add_file(7, path.to_str().unwrap());
+
+        h.send(text_update(USER_ID, "/import")).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "send an OPML file or a text file with t.me links to import channels"
+        );
+
+        h.send(document_update(7, "channels.txt", 27)).await;
+        let add = expect_add(h.next_request().await);
+        assert_eq!(add.channels.len(), 1);
+        assert_eq!(add.channels[0].username, "rustlang");
+        assert_eq!(add.unresolved, vec!["missingchan"]);
+        tokio::fs::remove_file(path).await.ok();
+    }
+
+    #[tokio::test]
+    async fn import_rejects_other_and_big_files() {
+        let mut h = Harness::with_limits(LimitsSettings {
+            max_import_kb: 1,
+            ..LimitsSettings::default()
+        })
+        .await;
+        h.send(document_update(7, "photo.jpg", 100)).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "only OPML files and text files with t.me links are imported"
+        );
+        h.send(document_update(7, "channels.opml", 2048)).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "the file is too big, at most 1 KB"
+        );
+
+        // the size telegram reports may be unknown, the downloaded file is checked as well
+        let path = std::env::temp_dir().join("tgfeed-bot-test-big-import.txt");
+        tokio::fs::write(&path, "t.me/rustlang\n".repeat(100))
+            .await
+            .unwrap();
+        h.api.add_file(8, path.to_str().unwrap());
+        h.send(document_update(8, "channels.txt", 0)).await;
+        assert_eq!(text_of(&h.next_sent().await), "cannot read the file");
+        tokio::fs::remove_file(path).await.ok();
+    }
+
+    #[test]
+    fn imports_are_limited_overall_and_by_user() {
+        let imports = Imports::new(2);
+        let first = imports.start(USER_ID).unwrap();
+        assert!(imports.start(USER_ID).is_err());
+        let _second = imports.start(USER_ID + 1).unwrap();
+        assert!(imports.start(USER_ID + 2).is_err());
+        drop(first);
+        assert!(imports.start(USER_ID).is_ok());
+    }
+
+    #[tokio::test]
+    async fn exported_file_is_removed_after_sending() {
+        let mut h = Harness::start().await;
+        h.responses
+            .send(BotResponses::ExportChannels(BotResponseListChannels {
+                chat_id: CHAT_ID,
+                channels: vec![models::Channel {
+                    id: CHANNEL_ID,
+                    title: "Rust".to_string(),
+                    username: "rustlang".to_string(),
+                }],
+            }))
+            .await
+            .unwrap();
+        let path = match h.next_sent().await.input_message_content() {
+            InputMessageContent::InputMessageDocument(doc) => match doc.document() {
+                InputFile::Local(local) => local.path().clone(),
+                file => panic!("unexpected file: {file:?}"),
+            },
+            content => panic!("unexpected message content: {content:?}"),
+        };
+        assert!(path.ends_with(".opml"));
+        assert!(std::path::Path::new(&path).exists());
+
+        h.send_outcome(1, CHAT_ID, None).await;
+        // updates are handled one at a time, so the outcome is handled once the next one is
+        h.send(text_update(USER_ID, "hello")).await;
+        assert_eq!(text_of(&h.next_sent().await), "invalid request");
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn own_messages_are_ignored_and_text_is_invalid() {
+        let mut h = Harness::start().await;
+        h.send(text_update(BOT_ID, "/start")).await;
+        h.send(text_update(USER_ID, "hello")).await;
+        assert_eq!(text_of(&h.next_sent().await), "invalid request");
+        assert!(h.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_not_from_users_are_ignored() {
+        let mut h = Harness::start().await;
+        let msg = Message::builder()
+            .chat_id(CHAT_ID)
+            .sender_id(MessageSender::Chat(
+                MessageSenderChat::builder().chat_id(CHANNEL_ID).build(),
+            ))
+            .content(MessageContent::MessageText(
+                MessageText::builder()
+                    .text(FormattedText::builder().text("hello").build())
+                    .build(),
+            ))
+            .build();
+        h.send(Update::NewMessage(
+            UpdateNewMessage::builder().message(msg).build(),
+        ))
+        .await;
+        // updates are handled in order, a reply to the chat would be sent before the request
+        h.send(text_update(USER_ID, "/list")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::ListChannels(USER_ID)
+        ));
+        assert!(h.sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_responses_are_sent_on_shutdown() {
+        let mut h = Harness::start().await;
+        h.responses
+            .send(BotResponses::ChannelsRemoved(BotResponseChannelsRemoved {
+                chat_id: CHAT_ID,
+                removed: vec!["rustlang".to_string()],
+                not_found: vec![],
+            }))
+            .await
+            .unwrap();
+        h.shutdown.send(true).unwrap();
+        tokio::time::timeout(TIMEOUT, h.handle)
+            .await
+            .expect("bot is not stopped")
+            .unwrap();
+        assert_eq!(text_of(&h.next_sent().await), "removed:\nrustlang\n");
+    }
+
+    #[tokio::test]
+    async fn outcomes_of_posts_are_reported() {
+        let mut h = Harness::start().await;
+        h.api
+            .add_error("send_message", "[429] Too Many Requests: retry after 3");
+        h.api.add_error("send_message", "[400] Chat not found");
+        for delivery_id in 1..=4 {
+            h.responses
+                .send(BotResponses::Post(BotResponsePost {
+                    delivery_id,
+                    chat_id: 300,
+                    text: "Rust\nhttps://t.me/rustlang/1".to_string(),
+                }))
+                .await
+                .unwrap();
+        }
+        for _ in 3..=4 {
+            assert_eq!(
+                text_of(&h.next_sent().await),
+                "Rust\nhttps://t.me/rustlang/1"
+            );
+        }
+        let mut outcomes = vec![];
+        for i in 1..=4 {
+            // the first two are rejected by TDLib at once, the others are reported once sent
+            if i == 3 {
+                assert!(h.delivered.try_recv().is_err());
+                h.send_outcome(1, 300, None).await;
+                h.send_outcome(2, 300, Some((400, "Chat not found"))).await;
+            }
+            let delivered = h.next_delivered().await;
+            assert_eq!(delivered.chat_id, 300);
+            outcomes.push((delivered.delivery_id, delivered.outcome));
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                (
+                    1,
+                    DeliveryOutcome::Failed {
+                        error: "[429] Too Many Requests: retry after 3".to_string(),
+                        retry_after: Some(Duration::from_secs(3)),
+                    }
+                ),
+                (
+                    2,
+                    DeliveryOutcome::ChatGone("[400] Chat not found".to_string())
+                ),
+                (3, DeliveryOutcome::Sent),
+                (
+                    4,
+                    DeliveryOutcome::ChatGone("[400] Chat not found".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_users_are_reported() {
+        let mut h = Harness::start().await;
+        let member = ChatMember::builder()
+            .member_id(MessageSender::User(
+                MessageSenderUser::builder().user_id(BOT_ID).build(),
+            ))
+            .status(ChatMemberStatus::Banned(
+                ChatMemberStatusBanned::builder().build(),
+            ))
+            .build();
+        h.send(Update::ChatMember(
+            UpdateChatMember::builder()
+                .chat_id(USER_ID)
+                .actor_user_id(USER_ID)
+                .new_chat_member(member)
+                .build(),
+        ))
+        .await;
+        match h.next_request().await {
+            BotRequests::Blocked(user) => {
+                assert_eq!((user.user_id, user.chat_id), (USER_ID, USER_ID))
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_users_are_detected_from_failed_sends() {
+        let mut h = Harness::start().await;
+        for (chat_id, text) in [(300, "news"), (CHAT_ID, "a reply")] {
+            h.responses
+                .send(BotResponses::Text(BotResponseText {
+                    chat_id,
+                    text: text.to_string(),
+                }))
+                .await
+                .unwrap();
+        }
+        assert_eq!(text_of(&h.next_sent().await), "news");
+        h.next_sent().await;
+        h.send_outcome(
+            1,
+            300,
+            Some((403, "Forbidden: bot was blocked by the user")),
+        )
+        .await;
+        match h.next_request().await {
+            BotRequests::Blocked(user) => assert_eq!((user.user_id, user.chat_id), (300, 300)),
+            request => panic!("unexpected request: {request:?}"),
+        }
+
+        h.responses
+            .send(BotResponses::Post(BotResponsePost {
+                delivery_id: 1,
+                chat_id: 301,
+                text: "Rust\nhttps://t.me/rustlang/1".to_string(),
+            }))
+            .await
+            .unwrap();
+        h.next_sent().await;
+        h.send_outcome(
+            3,
+            301,
+            Some((403, "Forbidden: bot was blocked by the user")),
+        )
+        .await;
+        assert_eq!(
+            h.next_delivered().await.outcome,
+            DeliveryOutcome::Blocked("[403] Forbidden: bot was blocked by the user".to_string())
+        );
+
+        // other failures are only logged
+        h.send_outcome(2, CHAT_ID, Some((400, "Bad Request: message is too long")))
+            .await;
+        h.send(text_update(USER_ID, "hello")).await;
+        assert_eq!(text_of(&h.next_sent().await), "invalid request");
+        assert!(h.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_commands_are_rejected_for_users() {
+        let mut h = Harness::start().await;
+        for command in [
+            "/stats",
+            "/broadcast hi",
+            "/ban 5",
+            "/refetch @rustlang",
+            "/health",
+        ] {
+            h.send(text_update(USER_ID, command)).await;
+            assert_eq!(text_of(&h.next_sent().await), "invalid request");
+        }
+        assert!(h.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_gets_stats_and_health() {
+        let mut h = Harness::start().await;
+        h.send(text_update(ADMIN_ID, "/stats")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::Stats(CHAT_ID)
+        ));
+        h.responses
+            .send(BotResponses::Stats(BotResponseStats {
+                chat_id: CHAT_ID,
+                stats: models::Stats {
+                    users: 3,
+                    enabled_users: 2,
+                    channels: 4,
+                    subscriptions: 5,
+                    posts: 6,
+                    posts_last_day: 1,
+                },
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "users: 3 (2 enabled)\nchannels: 4\nsubscriptions: 5\nposts: 6 (1 in the last 24 hours)"
+        );
+
+        h.health.set(Component::Bot, ComponentState::Running);
+        h.health.set(Component::User, ComponentState::Restarting);
+        h.send(text_update(ADMIN_ID, "/health")).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "bot: running\nuser: restarting\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_broadcasts_go_to_the_service() {
+        let mut h = Harness::start().await;
+        h.send(text_update(ADMIN_ID, "/broadcast maintenance at 10:00"))
+            .await;
+        let text = match h.next_request().await {
+            BotRequests::Broadcast(broadcast) => {
+                assert_eq!(broadcast.chat_id, CHAT_ID);
+                broadcast.text
+            }
+            request => panic!("unexpected request: {request:?}"),
+        };
+        assert_eq!(text, "maintenance at 10:00");
+        // the messages are queued and sent by the outbox like posts
+        assert!(h.sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_bans_users_and_refetches_channels() {
+        let mut h = Harness::start().await;
+        h.send(text_update(ADMIN_ID, "/ban 100")).await;
+        match h.next_request().await {
+            BotRequests::BanUser(ban) => assert_eq!(ban.user_id, USER_ID),
+            request => panic!("unexpected request: {request:?}"),
+        }
+        h.send(text_update(ADMIN_ID, "/ban 101")).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "specify the id of a user to ban"
+        );
+
+        h.send(text_update(ADMIN_ID, "/refetch t.me/rustlang"))
+            .await;
+        match h.next_request().await {
+            BotRequests::RefetchChannel(refetch) => {
+                assert_eq!(refetch.channel.username, "rustlang");
+                assert_eq!(refetch.channel.telegram_id, CHANNEL_ID);
+            }
+            request => panic!("unexpected request: {request:?}"),
+        }
+        h.send(text_update(ADMIN_ID, "/refetch @missingchan")).await;
+        assert_eq!(text_of(&h.next_sent().await), "channel not found");
+    }
+
+    #[tokio::test]
+    async fn add_skips_channels_over_the_user_limit() {
+        let mut h = Harness::with_limits(LimitsSettings {
+            max_channels_per_user: 1,
+            ..Default::default()
+        })
+        .await;
+        h.send(text_update(USER_ID, "/add @rustlang @golang")).await;
+        let add = expect_add(h.next_request().await);
+        assert_eq!(add.channels.len(), 1);
+        assert!(add.unresolved.is_empty());
+        assert_eq!(add.over_limit, vec!["golang"]);
+
+        h.responses
+            .send(BotResponses::ChannelsAdded(BotResponseChannelsAdded {
+                chat_id: CHAT_ID,
+                added: vec![],
+                already_present: vec![],
+                unresolved: vec![],
+                over_limit: vec!["rustlang".to_string(), "golang".to_string()],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "not added, limit reached:\nrustlang\ngolang\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_over_the_rate_limit_are_dropped() {
+        let mut h = Harness::with_limits(LimitsSettings {
+            commands_per_minute: 2,
+            ..Default::default()
+        })
+        .await;
+        for _ in 0..2 {
+            h.send(text_update(USER_ID, "/list")).await;
+            assert!(matches!(
+                h.next_request().await,
+                BotRequests::ListChannels(USER_ID)
+            ));
+        }
+        h.send(text_update(USER_ID, "/list")).await;
+        assert_eq!(
+            text_of(&h.next_sent().await),
+            "too many requests, try again in a minute"
+        );
+        h.send(text_update(USER_ID, "/list")).await;
+        // admins are not limited
+        h.send(text_update(ADMIN_ID, "/list")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::ListChannels(ADMIN_ID)
+        ));
+        assert!(h.sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn private_bot_requires_an_invite_code() {
+        let mut h = Harness::with_limits(LimitsSettings {
+            private: true,
+            invite_codes: vec!["friends".to_string()],
+            ..Default::default()
+        })
+        .await;
+        for command in ["/list", "/start", "/start strangers"] {
+            h.send(text_update(USER_ID, command)).await;
+            assert_eq!(
+                text_of(&h.next_sent().await),
+                "this bot is private, ask the admin for an invite link"
+            );
+        }
+        assert!(h.requests.try_recv().is_err());
+
+        h.send(text_update(ADMIN_ID, "/list")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::ListChannels(ADMIN_ID)
+        ));
+
+        h.send(text_update(USER_ID, "/start friends")).await;
+        assert!(matches!(h.next_request().await, BotRequests::AddUser(_)));
+        assert_eq!(text_of(&h.next_sent().await), "started");
+        h.send(text_update(USER_ID, "/list")).await;
+        assert!(matches!(
+            h.next_request().await,
+            BotRequests::ListChannels(USER_ID)
+        ));
+    }
+}
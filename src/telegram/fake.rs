@@ -0,0 +1,280 @@
+use super::api::TdApi;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rust_tdlib::types::{
+    AnswerCallbackQuery, BotCommand, BotCommandScope, Chat, ChatType, ChatTypeSupergroup, Chats,
+    DownloadFile, File, GetChat, GetChatHistory, GetChats, GetMe, GetSupergroup, LocalFile,
+    Message, Messages, Ok as TdOk, SearchPublicChat, SendMessage, SetCommands, Supergroup, User,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, OwnedRwLockWriteGuard, RwLock};
+
+/// Scripted in-memory TDLib: answers from the chats, files and history added beforehand
+/// and records everything the code under test sends.
+#[derive(Clone)]
+pub struct FakeTdApi {
+    inner: Arc<Mutex<State>>,
+    sent: mpsc::UnboundedSender<SendMessage>,
+}
+
+#[derive(Default)]
+struct State {
+    me_id: i64,
+    chats: HashMap<i64, Chat>,
+    usernames: HashMap<String, i64>,
+    supergroups: HashMap<i64, Supergroup>,
+    history: HashMap<i64, Vec<Message>>,
+    files: HashMap<i32, String>,
+    /// Commands by the chat they are set for, `None` for the default ones.
+    commands: HashMap<Option<i64>, Vec<BotCommand>>,
+    answered_callbacks: Vec<i64>,
+    /// Id of the last sent message.
+    last_message_id: i64,
+    /// Errors returned by the next calls of the method instead of the answers.
+    errors: HashMap<&'static str, VecDeque<String>>,
+    /// Calls of the method wait while the write lock is held.
+    holds: HashMap<&'static str, Arc<RwLock<()>>>,
+}
+
+impl FakeTdApi {
+    /// Returns the fake along with the receiver of messages sent through it. Sent messages
+    /// get ids 1, 2 and so on in the order they are sent.
+    pub fn new(me_id: i64) -> (Self, mpsc::UnboundedReceiver<SendMessage>) {
+        let (sent, sent_rx) = mpsc::unbounded_channel();
+        let api = Self {
+            inner: Arc::new(Mutex::new(State {
+                me_id,
+                ..Default::default()
+            })),
+            sent,
+        };
+        (api, sent_rx)
+    }
+
+    /// Adds a channel; it can be searched by the username unless the username is empty.
+    pub fn add_channel(&self, chat_id: i64, username: &str, title: &str) {
+        let supergroup_id = chat_id.abs();
+        let chat = Chat::builder()
+            .id(chat_id)
+            .title(title)
+            .type_(ChatType::Supergroup(
+                ChatTypeSupergroup::builder()
+                    .supergroup_id(supergroup_id)
+                    .is_channel(true)
+                    .build(),
+            ))
+            .build();
+        let supergroup = Supergroup::builder()
+            .id(supergroup_id)
+            .username(username)
+            .is_channel(true)
+            .build();
+        let mut state = self.inner.lock().unwrap();
+        if !username.is_empty() {
+            state.usernames.insert(username.to_string(), chat_id);
+        }
+        state.chats.insert(chat_id, chat);
+        state.supergroups.insert(supergroup_id, supergroup);
+    }
+
+    pub fn add_history(&self, chat_id: i64, messages: Vec<Message>) {
+        self.inner.lock().unwrap().history.insert(chat_id, messages);
+    }
+
+    /// Makes the file with the given id downloadable, `path` must exist.
+    pub fn add_file(&self, file_id: i32, path: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .insert(file_id, path.to_string());
+    }
+
+    pub fn commands(&self, chat_id: Option<i64>) -> Vec<BotCommand> {
+        self.inner
+            .lock()
+            .unwrap()
+            .commands
+            .get(&chat_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn answered_callbacks(&self) -> Vec<i64> {
+        self.inner.lock().unwrap().answered_callbacks.clone()
+    }
+
+    /// Makes the next call of the method fail with the error, e.g. `FLOOD_WAIT_5`.
+    pub fn add_error(&self, method: &'static str, error: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .errors
+            .entry(method)
+            .or_default()
+            .push_back(error.to_string());
+    }
+
+    /// Makes the calls of the method wait until the returned guard is dropped, like TDLib
+    /// waiting out a flood wait.
+    pub fn hold(&self, method: &'static str) -> OwnedRwLockWriteGuard<()> {
+        let hold = Arc::new(RwLock::new(()));
+        let guard = hold.clone().try_write_owned().unwrap();
+        self.inner.lock().unwrap().holds.insert(method, hold);
+        guard
+    }
+
+    async fn wait_released(&self, method: &'static str) {
+        let hold = self.inner.lock().unwrap().holds.get(method).cloned();
+        if let Some(hold) = hold {
+            drop(hold.read().await);
+        }
+    }
+
+    fn check_error(&self, method: &'static str) -> Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        match state.errors.get_mut(method).and_then(VecDeque::pop_front) {
+            Some(error) => Err(anyhow!(error)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl TdApi for FakeTdApi {
+    async fn send_message(&self, request: SendMessage) -> Result<Message> {
+        self.check_error("send_message")?;
+        // like the temporary ids TDLib gives to messages until they are actually sent
+        let message_id = {
+            let mut state = self.inner.lock().unwrap();
+            state.last_message_id += 1;
+            state.last_message_id
+        };
+        let message = Message::builder()
+            .id(message_id)
+            .chat_id(request.chat_id())
+            .build();
+        self.sent
+            .send(request)
+            .map_err(|_| anyhow!("sent messages are not received anymore"))?;
+        Ok(message)
+    }
+
+    async fn search_public_chat(&self, request: SearchPublicChat) -> Result<Chat> {
+        self.wait_released("search_public_chat").await;
+        self.check_error("search_public_chat")?;
+        let state = self.inner.lock().unwrap();
+        state
+            .usernames
+            .get(request.username())
+            .and_then(|chat_id| state.chats.get(chat_id))
+            .cloned()
+            .ok_or_else(|| anyhow!("USERNAME_NOT_OCCUPIED"))
+    }
+
+    async fn get_chat_history(&self, request: GetChatHistory) -> Result<Messages> {
+        self.check_error("get_chat_history")?;
+        // TDLib rejects offsets which skip all the messages asked for
+        if request.offset() > 0 || request.offset() <= -request.limit() {
+            return Err(anyhow!(
+                "[400] Parameter offset must be greater than -limit"
+            ));
+        }
+        let state = self.inner.lock().unwrap();
+        let messages: Vec<_> = state
+            .history
+            .get(&request.chat_id())
+            .into_iter()
+            .flatten()
+            .take(request.limit() as usize)
+            .cloned()
+            .map(Some)
+            .collect();
+        Ok(Messages::builder()
+            .total_count(messages.len() as i32)
+            .messages(messages)
+            .build())
+    }
+
+    async fn get_chats(&self, request: GetChats) -> Result<Chats> {
+        self.check_error("get_chats")?;
+        let state = self.inner.lock().unwrap();
+        let mut chat_ids: Vec<_> = state.chats.keys().copied().collect();
+        chat_ids.sort_unstable();
+        chat_ids.truncate(request.limit() as usize);
+        Ok(Chats::builder()
+            .total_count(chat_ids.len() as i32)
+            .chat_ids(chat_ids)
+            .build())
+    }
+
+    async fn get_chat(&self, request: GetChat) -> Result<Chat> {
+        self.check_error("get_chat")?;
+        self.inner
+            .lock()
+            .unwrap()
+            .chats
+            .get(&request.chat_id())
+            .cloned()
+            .ok_or_else(|| anyhow!("Chat not found"))
+    }
+
+    async fn get_supergroup(&self, request: GetSupergroup) -> Result<Supergroup> {
+        self.check_error("get_supergroup")?;
+        self.inner
+            .lock()
+            .unwrap()
+            .supergroups
+            .get(&request.supergroup_id())
+            .cloned()
+            .ok_or_else(|| anyhow!("Supergroup not found"))
+    }
+
+    async fn set_commands(&self, request: SetCommands) -> Result<TdOk> {
+        self.check_error("set_commands")?;
+        let chat_id = match request.scope() {
+            Some(BotCommandScope::Chat(scope)) => Some(scope.chat_id()),
+            _ => None,
+        };
+        self.inner
+            .lock()
+            .unwrap()
+            .commands
+            .insert(chat_id, request.commands().clone());
+        Ok(TdOk::default())
+    }
+
+    async fn get_me(&self, _request: GetMe) -> Result<User> {
+        self.check_error("get_me")?;
+        Ok(User::builder().id(self.inner.lock().unwrap().me_id).build())
+    }
+
+    async fn answer_callback_query(&self, request: AnswerCallbackQuery) -> Result<TdOk> {
+        self.check_error("answer_callback_query")?;
+        self.inner
+            .lock()
+            .unwrap()
+            .answered_callbacks
+            .push(request.callback_query_id());
+        Ok(TdOk::default())
+    }
+
+    async fn download_file(&self, request: DownloadFile) -> Result<File> {
+        self.check_error("download_file")?;
+        let state = self.inner.lock().unwrap();
+        let path = state
+            .files
+            .get(&request.file_id())
+            .ok_or_else(|| anyhow!("File not found"))?;
+        Ok(File::builder()
+            .id(request.file_id())
+            .local(
+                LocalFile::builder()
+                    .path(path)
+                    .is_downloading_completed(true)
+                    .build(),
+            )
+            .build())
+    }
+}
@@ -1,18 +1,29 @@
 use crate::models::{NewChannel, Post};
 use rust_tdlib::client::tdlib_client::TdJson;
 use rust_tdlib::client::{AuthStateHandlerProxy, Client, Worker};
-use std::time::Duration;
 use tokio::sync::mpsc;
 
+mod access;
+mod api;
+mod auth;
 mod bot;
+#[cfg(test)]
+mod fake;
 mod parsers;
+mod scheduler;
 mod service;
+mod supervisor;
 mod user;
 
-pub use bot::{AddUserChannel, BotRequests, BotResponseListChannels, BotResponses};
+pub use access::{Access, RateLimit, RateLimiter};
+pub use bot::{
+    BotRequests, BotResponseChannelsAdded, BotResponseChannelsRemoved, BotResponseCollections,
+    BotResponseListChannels, BotResponsePost, BotResponseStats, BotResponseText, BotResponses,
+    CollectionAction, CollectionRequest, Delivered, DeliveryOutcome, WebhookAction, WebhookRequest,
+};
 pub use service::{ServiceRequests, ServiceResponses, TelegramService};
-
-const SEND_UPDATE_TIMEOUT: Duration = Duration::from_secs(15);
+pub use supervisor::{Component, ComponentState, Health};
+pub use user::UserClient;
 
 #[derive(Debug)]
 pub enum NewUpdate {
@@ -62,6 +62,14 @@ pub fn parse_message_content(message: &MessageContent) -> Option<String> {
     }
 }
 
+/// Whether the content of a saved post has media, only their captions are saved.
+pub fn has_media(message: &MessageContent) -> bool {
+    matches!(
+        message,
+        MessageContent::MessageAnimation(_) | MessageContent::MessagePhoto(_)
+    )
+}
+
 pub fn parse_formatted_text(formatted_text: &FormattedText) -> String {
     let mut entities_by_index = make_entities_stack(formatted_text.entities());
     let mut result_text = String::new();
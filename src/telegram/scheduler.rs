@@ -0,0 +1,319 @@
+use super::api::TdApi;
+use super::supervisor::Component;
+use crate::settings::RequestsSettings;
+use anyhow::Result;
+use async_trait::async_trait;
+use rust_tdlib::types::{
+    AnswerCallbackQuery, Chat, Chats, DownloadFile, File, GetChat, GetChatHistory, GetChats, GetMe,
+    GetSupergroup, Message, Messages, Ok as TdOk, SearchPublicChat, SendMessage, SetCommands,
+    Supergroup, User,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Schedules TDLib requests of a single client. On `FLOOD_WAIT` the method is paused for
+/// the time telegram asks for, and idempotent requests are retried once the pause is over.
+/// Kept across client restarts, so a restarted client does not hit the limit again right away.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    inner: Arc<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    component: Component,
+    settings: RequestsSettings,
+    /// Methods paused until the given time.
+    paused: Mutex<HashMap<&'static str, Instant>>,
+    /// Requests waiting for a pause to end or being executed.
+    pending: AtomicUsize,
+}
+
+impl Scheduler {
+    pub fn new(component: Component, settings: RequestsSettings) -> Self {
+        Self {
+            inner: Arc::new(State {
+                component,
+                settings,
+                paused: Mutex::new(HashMap::new()),
+                pending: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Makes all requests of the client go through the scheduler.
+    pub fn wrap<C: TdApi>(&self, client: C) -> Scheduled<C> {
+        Scheduled {
+            client,
+            scheduler: self.clone(),
+        }
+    }
+
+    pub fn component(&self) -> Component {
+        self.inner.component
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.inner.pending.load(Ordering::Relaxed)
+    }
+
+    async fn call<T, F, Fut>(&self, method: &'static str, idempotent: bool, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let _pending = Pending::new(&self.inner.pending);
+        let mut retries = 0;
+        loop {
+            self.wait(method).await;
+            let err = match request().await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            let wait = match flood_wait(&err) {
+                None => return Err(err),
+                Some(wait) => wait,
+            };
+            log::warn!(
+                "{} got flood wait on {method}, pausing it for {}s",
+                self.inner.component,
+                wait.as_secs()
+            );
+            self.pause(method, wait);
+            let settings = &self.inner.settings;
+            if !idempotent
+                || retries >= settings.flood_wait_retries
+                || wait > settings.max_flood_wait()
+            {
+                return Err(err);
+            }
+            retries += 1;
+        }
+    }
+
+    async fn wait(&self, method: &'static str) {
+        loop {
+            let until = self.inner.paused.lock().unwrap().get(method).copied();
+            match until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until).await,
+                _ => return,
+            }
+        }
+    }
+
+    fn pause(&self, method: &'static str, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut paused = self.inner.paused.lock().unwrap();
+        let entry = paused.entry(method).or_insert(until);
+        *entry = (*entry).max(until);
+    }
+}
+
+/// Counts the request as pending until it is dropped, even if the caller gives up on it.
+struct Pending<'a>(&'a AtomicUsize);
+
+impl<'a> Pending<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Parses the time to wait out of `FLOOD_WAIT_X` and `Too Many Requests: retry after X` errors.
+pub(super) fn flood_wait(err: &anyhow::Error) -> Option<Duration> {
+    let message = format!("{err:#}");
+    ["FLOOD_WAIT_", "retry after "].iter().find_map(|prefix| {
+        let (_, rest) = message.split_once(prefix)?;
+        let secs: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        secs.parse().ok().map(Duration::from_secs)
+    })
+}
+
+/// A client whose requests go through the scheduler.
+#[derive(Debug, Clone)]
+pub struct Scheduled<C> {
+    client: C,
+    scheduler: Scheduler,
+}
+
+/// Sent messages and answered callbacks are not retried: they are not idempotent.
+#[async_trait]
+impl<C: TdApi> TdApi for Scheduled<C> {
+    async fn send_message(&self, request: SendMessage) -> Result<Message> {
+        self.scheduler
+            .call("send_message", false, || {
+                self.client.send_message(request.clone())
+            })
+            .await
+    }
+
+    async fn search_public_chat(&self, request: SearchPublicChat) -> Result<Chat> {
+        self.scheduler
+            .call("search_public_chat", true, || {
+                self.client.search_public_chat(request.clone())
+            })
+            .await
+    }
+
+    async fn get_chat_history(&self, request: GetChatHistory) -> Result<Messages> {
+        self.scheduler
+            .call("get_chat_history", true, || {
+                self.client.get_chat_history(request.clone())
+            })
+            .await
+    }
+
+    async fn get_chats(&self, request: GetChats) -> Result<Chats> {
+        self.scheduler
+            .call("get_chats", true, || self.client.get_chats(request.clone()))
+            .await
+    }
+
+    async fn get_chat(&self, request: GetChat) -> Result<Chat> {
+        self.scheduler
+            .call("get_chat", true, || self.client.get_chat(request.clone()))
+            .await
+    }
+
+    async fn get_supergroup(&self, request: GetSupergroup) -> Result<Supergroup> {
+        self.scheduler
+            .call("get_supergroup", true, || {
+                self.client.get_supergroup(request.clone())
+            })
+            .await
+    }
+
+    async fn set_commands(&self, request: SetCommands) -> Result<TdOk> {
+        self.scheduler
+            .call("set_commands", true, || {
+                self.client.set_commands(request.clone())
+            })
+            .await
+    }
+
+    async fn get_me(&self, request: GetMe) -> Result<User> {
+        self.scheduler
+            .call("get_me", true, || self.client.get_me(request.clone()))
+            .await
+    }
+
+    async fn answer_callback_query(&self, request: AnswerCallbackQuery) -> Result<TdOk> {
+        self.scheduler
+            .call("answer_callback_query", false, || {
+                self.client.answer_callback_query(request.clone())
+            })
+            .await
+    }
+
+    async fn download_file(&self, request: DownloadFile) -> Result<File> {
+        self.scheduler
+            .call("download_file", true, || {
+                self.client.download_file(request.clone())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::fake::FakeTdApi;
+    use anyhow::anyhow;
+
+    fn scheduler(flood_wait_retries: u32) -> Scheduler {
+        Scheduler::new(
+            Component::User,
+            RequestsSettings {
+                flood_wait_retries,
+                max_flood_wait_secs: 5,
+            },
+        )
+    }
+
+    #[test]
+    fn parses_flood_wait() {
+        assert_eq!(
+            flood_wait(&anyhow!("FLOOD_WAIT_17")),
+            Some(Duration::from_secs(17))
+        );
+        assert_eq!(
+            flood_wait(&anyhow!("[429] Too Many Requests: retry after 3")),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(flood_wait(&anyhow!("USERNAME_NOT_OCCUPIED")), None);
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_are_retried_after_the_pause() {
+        let (api, _sent) = FakeTdApi::new(1);
+        api.add_channel(-1001, "rustlang", "Rust");
+        api.add_error("get_chat", "FLOOD_WAIT_1");
+        api.add_error("get_chat", "FLOOD_WAIT_0");
+        let scheduler = scheduler(2);
+        let client = scheduler.wrap(api);
+
+        let started = Instant::now();
+        let chat = client
+            .get_chat(GetChat::builder().chat_id(-1001).build())
+            .await
+            .unwrap();
+        assert_eq!(chat.title(), "Rust");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(scheduler.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn retries_are_limited() {
+        let (api, _sent) = FakeTdApi::new(1);
+        api.add_channel(-1001, "rustlang", "Rust");
+        for _ in 0..2 {
+            api.add_error("get_chat", "FLOOD_WAIT_0");
+        }
+        let client = scheduler(1).wrap(api);
+        let err = client
+            .get_chat(GetChat::builder().chat_id(-1001).build())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "FLOOD_WAIT_0");
+        // the error is consumed, so the next request goes through
+        client
+            .get_chat(GetChat::builder().chat_id(-1001).build())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sent_messages_are_not_retried() {
+        let (api, mut sent) = FakeTdApi::new(1);
+        api.add_error("send_message", "FLOOD_WAIT_0");
+        let client = scheduler(3).wrap(api);
+        let request = SendMessage::builder().chat_id(200).build();
+        assert!(client.send_message(request.clone()).await.is_err());
+        assert!(sent.try_recv().is_err());
+        client.send_message(request).await.unwrap();
+        assert_eq!(sent.try_recv().unwrap().chat_id(), 200);
+    }
+
+    #[tokio::test]
+    async fn long_flood_waits_are_not_retried() {
+        let (api, _sent) = FakeTdApi::new(1);
+        api.add_error("get_me", "FLOOD_WAIT_60");
+        let scheduler = scheduler(3);
+        let client = scheduler.wrap(api);
+        let started = Instant::now();
+        assert!(client.get_me(GetMe::builder().build()).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // the method stays paused for other requests
+        assert!(scheduler.inner.paused.lock().unwrap()["get_me"] > Instant::now());
+    }
+}
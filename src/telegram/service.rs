@@ -1,27 +1,59 @@
-use super::bot::{init_bot_updates_reader, BotClient, BotRequests, BotResponses};
+use super::access::Access;
+use super::auth::{AdminPrompt, UserAuthStateHandler};
+use super::bot::{
+    init_bot_updates_reader, BotClient, BotRequests, BotResponses, BotSettings, DeliveredSender,
+};
+use super::scheduler::{Scheduled, Scheduler};
+use super::supervisor::{supervise, Component, ComponentState, Health};
 use super::user::UserClient;
 use super::{TgClient, TgWorker};
+use crate::db::DbService;
+use crate::metrics;
+use crate::settings::{
+    AuthCodeSource, DeliverySettings, IngestionSettings, LimitsSettings, ProxySettings,
+    TelegramSettings,
+};
 use crate::telegram::user::init_client_updates_reader;
 use anyhow::{bail, Result};
+use fs2::FileExt;
 use rust_tdlib::client::tdlib_client::TdLibClient;
 use rust_tdlib::client::{
-    AuthStateHandlerProxy, Client, ClientIdentifier, ClientState,
+    AuthStateHandlerProxy, Client, ClientAuthStateHandler, ClientIdentifier, ClientState,
     ConsoleClientStateHandlerIdentified, Worker,
 };
 use rust_tdlib::tdjson::set_log_verbosity_level;
 use rust_tdlib::types::Update::User;
-use rust_tdlib::types::{AuthorizationState, TdlibParameters, Update};
+use rust_tdlib::types::{
+    AddProxy, AuthorizationState, Close, ProxyType, ProxyTypeMtproto, ProxyTypeSocks5,
+    TdlibParameters, Update,
+};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
 use tokio::task::JoinHandle;
 
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Locked in the TDLib database directory of a client while a process uses it,
+/// TDLib cannot share the database between processes.
+const LOCK_FILE: &str = "tgfeed.lock";
+
 #[derive(Debug)]
 pub enum ServiceRequests {
     Bot(BotRequests),
 }
 
+impl ServiceRequests {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServiceRequests::Bot(request) => request.into(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ServiceResponses {
     Bot(BotResponses),
@@ -32,74 +64,176 @@ type ToApp = Sender<ServiceRequests>;
 
 #[derive(Clone)]
 pub struct TelegramService {
-    api_hash: String,
-    app_id: i32,
-    user_phone: String,
-    bot_token: String,
+    settings: TelegramSettings,
+    ingestion: IngestionSettings,
+    delivery: DeliverySettings,
+    feeds_base_url: String,
+    limits: LimitsSettings,
+    admin_prompt: Option<AdminPrompt>,
+    health: Health,
+    access: Access,
+    bot_requests: Scheduler,
+    user_requests: Scheduler,
     inner: Arc<RwLock<Option<Inner>>>,
 }
 
 struct Inner {
-    pub bot_client: BotClient,
-    pub user_client: UserClient,
-    pub worker: TgWorker,
+    pub shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl TelegramService {
-    pub fn new(api_hash: String, app_id: i32, user_phone: String, bot_token: String) -> Self {
+    pub fn new(
+        settings: TelegramSettings,
+        ingestion: IngestionSettings,
+        delivery: DeliverySettings,
+        feeds_base_url: String,
+        limits: LimitsSettings,
+    ) -> Self {
+        let access = Access::new(&limits, &settings.admins);
+        let bot_requests = Scheduler::new(Component::Bot, settings.requests.clone());
+        let user_requests = Scheduler::new(Component::User, settings.requests.clone());
+        let admin_prompt = match settings.auth.source {
+            AuthCodeSource::Bot { admin_id } => Some(AdminPrompt::new(admin_id)),
+            _ => None,
+        };
         Self {
-            api_hash,
-            user_phone,
-            bot_token,
-            app_id,
+            settings,
+            ingestion,
+            delivery,
+            feeds_base_url,
+            limits,
+            admin_prompt,
+            health: Health::default(),
+            access,
+            bot_requests,
+            user_requests,
             inner: Arc::new(RwLock::new(None)),
         }
     }
 
-    pub async fn start(&self, mut from_app: FromApp, to_app: ToApp) -> Result<JoinHandle<()>> {
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    pub fn access(&self) -> Access {
+        self.access.clone()
+    }
+
+    /// Starts both clients under supervision, new posts are saved to `db` and delivery
+    /// outcomes are reported to `delivered`. The returned handle completes once the service is stopped: with an error if TDLib worker exited
+    /// unexpectedly.
+    pub async fn start(
+        &self,
+        db: DbService,
+        mut from_app: FromApp,
+        to_app: ToApp,
+        delivered: DeliveredSender,
+    ) -> Result<JoinHandle<Result<()>>> {
         if self.inner.read().await.is_some() {
             bail!("service already started");
         }
+        // one-off admin commands use the reader account as well
+        let user_lock = self.lock_database("user")?;
 
-        set_log_verbosity_level(
-            std::env::var("TDLIB_LOG_VERBOSITY")
-                .unwrap_or("1".to_string())
-                .parse()?,
-        );
-        let auth_handler = AuthStateHandlerProxy::new_with_encryption_key("".to_string());
+        let mut worker = self.build_worker()?;
+        let mut worker_waiter = worker.start();
+        let health = self.health.clone();
+        health.set(Component::Worker, ComponentState::Running);
 
-        let mut worker = Worker::builder()
-            .with_auth_state_handler(auth_handler)
-            .build()?;
+        let (shutdown, mut shutdown_recv) = watch::channel(false);
+        let shutdown = Arc::new(shutdown);
 
-        let mut worker_waiter = worker.start();
-        let (bcl, brecv) = self
-            .build_client(
+        for scheduler in [&self.bot_requests, &self.user_requests] {
+            let scheduler = scheduler.clone();
+            metrics::watch_queue(
+                &format!("{}_tdlib_requests", scheduler.component()),
+                move || Some(scheduler.queue_depth()),
+            );
+        }
+
+        let queue_size = self.delivery.queue_size;
+        let (bss, bsr) = mpsc::channel(queue_size);
+        let bsr = Arc::new(Mutex::new(bsr));
+        let (sbs, mut sbr) = mpsc::channel(queue_size);
+        metrics::watch_channel("bot_responses", &bss);
+        metrics::watch_channel("bot_requests", &sbs);
+        health.set(Component::Bot, ComponentState::Starting);
+        let bot = self
+            .start_bot(
                 &mut worker,
-                ClientIdentifier::BotToken(self.bot_token.clone()),
-                init_bot_updates_reader,
+                bsr.clone(),
+                sbs.clone(),
+                delivered.clone(),
+                shutdown_recv.clone(),
             )
             .await?;
 
-        let (ucl, urecv) = self
-            .build_client(
+        let (uss, usr) = mpsc::channel(queue_size);
+        let usr = Arc::new(Mutex::new(usr));
+        let (sus, sur) = mpsc::channel(queue_size);
+        metrics::watch_channel("user_responses", &uss);
+        metrics::watch_channel("user_requests", &sus);
+        health.set(Component::User, ComponentState::Starting);
+        let user = self
+            .start_user(
                 &mut worker,
-                ClientIdentifier::PhoneNumber(self.user_phone.clone()),
-                init_client_updates_reader,
+                db.clone(),
+                usr.clone(),
+                sus.clone(),
+                shutdown_recv.clone(),
             )
             .await?;
 
-        let mut bot_client = BotClient::new(bcl);
-        let (bss, bsr) = mpsc::channel(10);
-        let (sbs, mut sbr) = mpsc::channel(10);
-        let mut bot_handle = bot_client.start(brecv, bsr, sbs).await?;
-
-        let mut user_client = UserClient::new(ucl);
-        let (uss, usr) = mpsc::channel(10);
-        let (sus, sur) = mpsc::channel(10);
-        let mut user_handle = user_client.start(urecv, usr, sus).await?;
+        let bot_supervisor = tokio::spawn(supervise(
+            Component::Bot,
+            health.clone(),
+            worker.clone(),
+            shutdown_recv.clone(),
+            bot,
+            {
+                let service = self.clone();
+                let worker = worker.clone();
+                let shutdown = shutdown_recv.clone();
+                move || {
+                    let service = service.clone();
+                    let mut worker = worker.clone();
+                    let (bsr, sbs, shutdown) = (bsr.clone(), sbs.clone(), shutdown.clone());
+                    let delivered = delivered.clone();
+                    async move {
+                        service
+                            .start_bot(&mut worker, bsr, sbs, delivered, shutdown)
+                            .await
+                    }
+                }
+            },
+        ));
+        let user_supervisor = tokio::spawn(supervise(
+            Component::User,
+            health.clone(),
+            worker.clone(),
+            shutdown_recv.clone(),
+            user,
+            {
+                let service = self.clone();
+                let worker = worker.clone();
+                let shutdown = shutdown_recv.clone();
+                move || {
+                    let service = service.clone();
+                    let mut worker = worker.clone();
+                    let (db, usr, sus) = (db.clone(), usr.clone(), sus.clone());
+                    let shutdown = shutdown.clone();
+                    async move {
+                        service
+                            .start_user(&mut worker, db, usr, sus, shutdown)
+                            .await
+                    }
+                }
+            },
+        ));
 
+        let router_shutdown = shutdown.clone();
         let join = tokio::spawn(async move {
+            let mut requested = true;
             loop {
                 tokio::select! {
                     Some(app_resp) = from_app.recv() => {
@@ -113,56 +247,209 @@ impl TelegramService {
                         to_app.send(ServiceRequests::Bot(bot_req)).await;
                     }
                     _ = &mut worker_waiter => {
-                        log::info!("worker exited");
-                    }
-                    _ = &mut bot_handle => {
-                        log::info!("bot exited");
+                        log::error!("worker exited");
+                        requested = false;
+                        break;
                     }
-                    _ = &mut user_handle => {
-                        log::info!("user exited");
+                    _ = shutdown_recv.changed() => {
+                        break;
                     }
                 }
             }
-        });
 
-        self.inner.write().await.insert(Inner {
-            bot_client,
-            user_client,
-            worker,
+            router_shutdown.send(true).ok();
+            // supervisors return as soon as their components have stopped
+            let mut clients = vec![];
+            for supervisor in [bot_supervisor, user_supervisor] {
+                if let Ok(Some(client)) = supervisor.await {
+                    clients.push(client);
+                }
+            }
+            // the bot has exited, so it is safe to wait for the rest of its requests
+            while let Some(bot_req) = sbr.recv().await {
+                to_app.send(ServiceRequests::Bot(bot_req)).await;
+            }
+            drop(to_app);
+
+            for client in clients.iter() {
+                if let Err(err) = close_client(&worker, client).await {
+                    log::error!("cannot close client: {err}");
+                }
+            }
+            worker.stop();
+            drop(user_lock);
+            health.set(Component::Worker, ComponentState::Stopped);
+
+            if requested {
+                Ok(())
+            } else {
+                bail!("telegram worker exited unexpectedly")
+            }
         });
+
+        self.inner.write().await.insert(Inner { shutdown });
         Ok(join)
     }
 
-    async fn build_client<F, A>(
+    /// Authorizes the reader account alone, without the bot, and passes it to `f`.
+    /// Used by one-off admin commands; the client is closed once `f` completes.
+    /// Fails if a running service uses the account.
+    pub async fn with_user_client<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(UserClient<Scheduled<TgClient>>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let _lock = self.lock_database("user")?;
+        let mut worker = self.build_worker()?;
+        worker.start();
+        let (client, _) = self
+            .build_client(
+                &mut worker,
+                "user",
+                UserAuthStateHandler::new(
+                    self.settings.phone.clone(),
+                    self.settings.auth.clone(),
+                    self.admin_prompt.clone(),
+                ),
+                |mut receiver| {
+                    // updates are not needed for one-off commands
+                    tokio::spawn(async move { while receiver.recv().await.is_some() {} })
+                },
+            )
+            .await?;
+        let result = f(UserClient::new(self.user_requests.wrap(client.clone()))).await;
+        if let Err(err) = close_client(&worker, &client).await {
+            log::error!("cannot close client: {err}");
+        }
+        worker.stop();
+        result
+    }
+
+    /// Locks the TDLib database of the client for this process, the lock is released
+    /// once the returned file is dropped.
+    fn lock_database(&self, client_name: &str) -> Result<File> {
+        let dir = PathBuf::from(self.settings.tdlib.database_directory(client_name));
+        std::fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            bail!(
+                "TDLib database {} is used by another tgfeed process, stop `tgfeed serve` first",
+                dir.display()
+            );
+        }
+        Ok(file)
+    }
+
+    fn build_worker(&self) -> Result<TgWorker> {
+        set_log_verbosity_level(self.settings.tdlib.log_verbosity);
+        let auth_handler =
+            AuthStateHandlerProxy::new_with_encryption_key(self.settings.tdlib.encryption_key()?);
+        Ok(Worker::builder()
+            .with_auth_state_handler(auth_handler)
+            .build()?)
+    }
+
+    async fn start_bot(
         &self,
         worker: &mut TgWorker,
-        ident: ClientIdentifier,
+        from_service: Arc<Mutex<Receiver<BotResponses>>>,
+        to_service: Sender<BotRequests>,
+        delivered: DeliveredSender,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<(TgClient, JoinHandle<()>)> {
+        let ident = ClientIdentifier::BotToken(self.settings.bot_token.clone());
+        let (client, updates) = self
+            .build_client(
+                worker,
+                "bot",
+                ConsoleClientStateHandlerIdentified::new(ident),
+                |receiver| init_bot_updates_reader(receiver, &self.ingestion),
+            )
+            .await?;
+        if let Some(admin_prompt) = &self.admin_prompt {
+            admin_prompt.set_client(client.clone());
+        }
+        let settings = BotSettings {
+            feeds_base_url: self.feeds_base_url.clone(),
+            resolve_concurrency: self.ingestion.resolve_concurrency,
+            admins: self.settings.admins.clone(),
+            max_channels_per_user: self.limits.max_channels_per_user,
+            commands_per_minute: self.limits.commands_per_minute,
+            max_import_size: self.limits.max_import_kb * 1024,
+            max_imports: self.limits.max_imports,
+        };
+        let handle = BotClient::new(
+            self.bot_requests.wrap(client.clone()),
+            settings,
+            self.admin_prompt.clone(),
+            self.health.clone(),
+            self.access.clone(),
+        )
+        .start(updates, from_service, to_service, delivered, shutdown)
+        .await?;
+        Ok((client, handle))
+    }
+
+    async fn start_user(
+        &self,
+        worker: &mut TgWorker,
+        db: DbService,
+        from_service: Arc<Mutex<Receiver<String>>>,
+        to_service: Sender<String>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<(TgClient, JoinHandle<()>)> {
+        let (client, updates) = self
+            .build_client(
+                worker,
+                "user",
+                UserAuthStateHandler::new(
+                    self.settings.phone.clone(),
+                    self.settings.auth.clone(),
+                    self.admin_prompt.clone(),
+                ),
+                |receiver| init_client_updates_reader(receiver, &self.ingestion),
+            )
+            .await?;
+        let handle = UserClient::new(self.user_requests.wrap(client.clone()))
+            .start(db, updates, from_service, to_service, shutdown)
+            .await?;
+        Ok((client, handle))
+    }
+
+    async fn build_client<F, A, H>(
+        &self,
+        worker: &mut TgWorker,
+        client_name: &str,
+        auth_handler: H,
         updates_handler: F,
     ) -> Result<(TgClient, A)>
     where
         F: FnOnce(Receiver<Box<Update>>) -> A,
+        H: ClientAuthStateHandler + 'static,
     {
-        let (sender, receiver) = mpsc::channel::<Box<Update>>(1000);
-        let db_dir = match ident {
-            ClientIdentifier::PhoneNumber(_) => "user",
-            ClientIdentifier::BotToken(_) => "bot",
-        };
+        let (sender, receiver) = mpsc::channel::<Box<Update>>(self.ingestion.updates_queue_size);
+        let tdlib = &self.settings.tdlib;
+        let mut parameters = TdlibParameters::builder();
+        parameters
+            .database_directory(tdlib.database_directory(client_name))
+            .use_test_dc(tdlib.use_test_dc)
+            .api_id(self.settings.api_id)
+            .api_hash(self.settings.api_hash.clone())
+            .system_language_code(tdlib.system_language_code.clone())
+            .device_model(tdlib.device_model.clone())
+            .system_version(tdlib.system_version.clone())
+            .application_version(tdlib.application_version.clone())
+            .enable_storage_optimizer(true);
+        if let Some(files_dir) = tdlib.files_directory(client_name) {
+            parameters.files_directory(files_dir);
+        }
         let client = Client::builder()
-            .with_tdlib_parameters(
-                TdlibParameters::builder()
-                    .database_directory(db_dir)
-                    .use_test_dc(false)
-                    .api_id(self.app_id)
-                    .api_hash(self.api_hash.clone())
-                    .system_language_code("en")
-                    .device_model("Unknown")
-                    .system_version("Unknown")
-                    .application_version("0.0.1")
-                    .enable_storage_optimizer(true)
-                    .build(),
-            )
+            .with_tdlib_parameters(parameters.build())
             .with_auth_state_channel(10)
-            .with_client_auth_state_handler(ConsoleClientStateHandlerIdentified::new(ident))
+            .with_client_auth_state_handler(auth_handler)
             .with_updates_sender(sender)
             .build()?;
 
@@ -170,10 +457,14 @@ impl TelegramService {
 
         let client = worker.bind_client(client).await?;
 
+        let mut proxy_added = false;
         loop {
             match worker.wait_auth_state_change(&client).await? {
                 Ok(state) => match state {
                     ClientState::Opened => {
+                        if !proxy_added {
+                            self.add_proxy(&client).await?;
+                        }
                         log::debug!("client authorized; can start interaction");
                         break;
                     }
@@ -181,6 +472,11 @@ impl TelegramService {
                         bail!("client closed, need to reauthorize it");
                     }
                     ClientState::Authorizing => {
+                        // the proxy is needed before the client reaches the network to authorize
+                        if !proxy_added {
+                            self.add_proxy(&client).await?;
+                            proxy_added = true;
+                        }
                         log::debug!("client not authorized yet")
                     }
                 },
@@ -193,10 +489,69 @@ impl TelegramService {
         Ok((client, recv))
     }
 
+    async fn add_proxy(&self, client: &TgClient) -> Result<()> {
+        let (server, port, type_) = match &self.settings.tdlib.proxy {
+            None => return Ok(()),
+            Some(ProxySettings::Socks5 {
+                server,
+                port,
+                username,
+                password,
+            }) => (
+                server,
+                port,
+                ProxyType::Socks5(
+                    ProxyTypeSocks5::builder()
+                        .username(username)
+                        .password(password)
+                        .build(),
+                ),
+            ),
+            Some(ProxySettings::Mtproto {
+                server,
+                port,
+                secret,
+            }) => (
+                server,
+                port,
+                ProxyType::Mtproto(ProxyTypeMtproto::builder().secret(secret).build()),
+            ),
+        };
+        client
+            .add_proxy(
+                AddProxy::builder()
+                    .server(server)
+                    .port(*port)
+                    .enable(true)
+                    .type_(type_)
+                    .build(),
+            )
+            .await?;
+        log::info!("using proxy {server}:{port}");
+        Ok(())
+    }
+
+    /// Asks the clients to stop; the handle returned by `start` completes once
+    /// pending requests are passed to the app and TDLib clients are closed.
     pub async fn stop(&self) {
         let mut guard = self.inner.write().await;
         if let Some(inner) = guard.take() {
-            inner.worker.stop();
+            inner.shutdown.send(true).ok();
         }
     }
 }
+
+/// Asks TDLib to close the client and waits until it is closed.
+pub(super) async fn close_client(worker: &TgWorker, client: &TgClient) -> Result<()> {
+    client.close(Close::builder().build()).await?;
+    tokio::time::timeout(CLOSE_TIMEOUT, async {
+        loop {
+            match worker.wait_auth_state_change(client).await? {
+                Ok(ClientState::Closed) => return Ok(()),
+                Ok(_) => {}
+                Err((err, _)) => bail!(err),
+            }
+        }
+    })
+    .await?
+}
@@ -0,0 +1,143 @@
+use super::service::close_client;
+use super::{TgClient, TgWorker};
+use anyhow::Result;
+use rust_tdlib::client::ClientState;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use strum::Display;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A component which worked for that long is considered healthy again, so the backoff is reset.
+const STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Component {
+    Worker,
+    Bot,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum ComponentState {
+    Starting,
+    Running,
+    Restarting,
+    Stopped,
+}
+
+/// Current state of every telegram component.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    inner: Arc<RwLock<BTreeMap<Component, ComponentState>>>,
+}
+
+impl Health {
+    pub fn set(&self, component: Component, state: ComponentState) {
+        log::info!("{component} is {state}");
+        self.inner.write().unwrap().insert(component, state);
+    }
+
+    pub fn get(&self, component: Component) -> Option<ComponentState> {
+        self.inner.read().unwrap().get(&component).copied()
+    }
+
+    pub fn states(&self) -> Vec<(Component, ComponentState)> {
+        self.inner
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(c, s)| (*c, *s))
+            .collect()
+    }
+
+    pub fn is_running(&self) -> bool {
+        let states = self.inner.read().unwrap();
+        !states.is_empty() && states.values().all(|s| *s == ComponentState::Running)
+    }
+}
+
+/// Watches a started component and restarts it with exponential backoff when its task exits
+/// or its TDLib client gets closed. Returns the running client once shutdown is requested,
+/// the clients of exited components are closed before they are restarted.
+pub async fn supervise<F, Fut>(
+    component: Component,
+    health: Health,
+    worker: TgWorker,
+    mut shutdown: watch::Receiver<bool>,
+    started: (TgClient, JoinHandle<()>),
+    mut restart: F,
+) -> Option<TgClient>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(TgClient, JoinHandle<()>)>>,
+{
+    let mut running = Some(started);
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        if let Some((client, mut handle)) = running.take() {
+            health.set(component, ComponentState::Running);
+            let started_at = Instant::now();
+            tokio::select! {
+                _ = &mut handle => {
+                    log::error!("{component} exited");
+                    // the client is still open, a new one for the same account would conflict with it
+                    if let Err(err) = close_client(&worker, &client).await {
+                        log::error!("cannot close {component} client: {err}");
+                    }
+                }
+                _ = wait_closed(&worker, &client) => {
+                    log::error!("{component} client closed");
+                    handle.abort();
+                }
+                _ = shutdown.changed() => {
+                    // the component stops by itself on shutdown
+                    handle.await.ok();
+                    health.set(component, ComponentState::Stopped);
+                    return Some(client);
+                }
+            }
+            if started_at.elapsed() > STABLE_PERIOD {
+                backoff = INITIAL_BACKOFF;
+            }
+        }
+        if *shutdown.borrow() {
+            break;
+        }
+
+        health.set(component, ComponentState::Restarting);
+        log::info!("restarting {component} in {backoff:?}");
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.changed() => break,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        match restart().await {
+            Ok(started) => running = Some(started),
+            Err(err) => log::error!("cannot restart {component}: {err}"),
+        }
+    }
+    health.set(component, ComponentState::Stopped);
+    None
+}
+
+async fn wait_closed(worker: &TgWorker, client: &TgClient) {
+    loop {
+        match worker.wait_auth_state_change(client).await {
+            Ok(Ok(ClientState::Closed)) => return,
+            Ok(Ok(state)) => log::debug!("client state changed: {state:?}"),
+            Ok(Err((err, _))) => log::error!("client authorization error: {err}"),
+            Err(err) => {
+                log::error!("cannot get client state: {err}");
+                return;
+            }
+        }
+    }
+}
@@ -1,47 +1,79 @@
+use crate::db::DbService;
 use crate::models::{NewChannel, Post};
-use crate::telegram::{parsers, TgClient, TgWorker, SEND_UPDATE_TIMEOUT};
+use crate::settings::IngestionSettings;
+use crate::telegram::api::TdApi;
+use crate::telegram::{parsers, TgClient, TgWorker};
+use crate::{feed, metrics};
 use anyhow::{anyhow, Result};
-use rust_tdlib::client::tdlib_client::TdJson;
-use rust_tdlib::client::{Client, ClientIdentifier};
+use rust_tdlib::client::ClientIdentifier;
 use rust_tdlib::types::{
     Chat, ChatType, GetChat, GetChatHistory, GetChats, GetSupergroup, MessageContent,
     SearchPublicChat, TdlibParameters, TextEntityType, Update, UpdateNewMessage,
 };
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, watch, Mutex};
 use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
 
-type TgUpdate = Receiver<String>;
+type TgUpdate = Receiver<ChannelUpdate>;
 type FromService = Receiver<String>;
 type ToService = Sender<String>;
 
+/// Parsed content of a new or edited post along with the span of the TDLib update it came with.
+#[derive(Debug)]
+pub struct ChannelUpdate {
+    chat_id: i64,
+    message_id: i64,
+    /// Zero for edited posts, their date stays the same.
+    date: i32,
+    content: String,
+    has_media: bool,
+    edited: bool,
+    span: Span,
+}
+
 #[derive(Clone)]
-pub struct UserClient {
-    client: Client<TdJson>,
+pub struct UserClient<C = TgClient> {
+    client: C,
 }
 
-impl UserClient {
-    pub fn new(client: TgClient) -> Self {
+impl<C: TdApi> UserClient<C> {
+    pub fn new(client: C) -> Self {
         Self { client }
     }
 
+    /// Saves new posts of the channels known to `db` and queues them for delivery, edited ones
+    /// are updated and queued for webhooks. Posts of other chats the account is a member of
+    /// are skipped.
     pub async fn start(
         &self,
+        db: DbService,
         mut tg_update: TgUpdate,
-        mut from_service: FromService,
+        from_service: Arc<Mutex<FromService>>,
         to_service: ToService,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<JoinHandle<()>> {
         Ok(tokio::spawn(async move {
+            let mut from_service = from_service.lock().await;
             loop {
                 tokio::select! {
-                    Some(tg_update) = tg_update.recv() => {
-                        log::debug!("new update: {tg_update}");
-                    },
+                    Some(tg_update) = tg_update.recv() => save_update(&db, tg_update).await,
 
                     Some(from_service) = from_service.recv() => {
                         log::debug!("new service request: {from_service}");
                     }
+
+                    _ = shutdown.changed() => {
+                        // new posts are not accepted anymore, but the ones which are already queued are saved
+                        tg_update.close();
+                        while let Some(tg_update) = tg_update.recv().await {
+                            save_update(&db, tg_update).await;
+                        }
+                        log::info!("user client stopped");
+                        break;
+                    }
                 }
             }
         }))
@@ -54,7 +86,8 @@ impl UserClient {
                 GetChatHistory::builder()
                     .chat_id(chat_id)
                     .limit(limit)
-                    .offset(-50)
+                    // the latest messages, TDLib rejects offsets of -limit and below
+                    .offset(0)
                     .from_message_id(0)
                     .build(),
             )
@@ -75,6 +108,7 @@ impl UserClient {
                     pub_date: msg.date(),
                     content: content,
                     chat_id,
+                    has_media: parsers::has_media(msg.content()),
                 })
             }
         }
@@ -124,24 +158,107 @@ impl UserClient {
     }
 }
 
-pub fn init_client_updates_reader(mut receiver: Receiver<Box<Update>>) -> TgUpdate {
-    let (sx, rx) = mpsc::channel(2000);
+/// Saves the post, failures are only logged since TDLib does not send the update again.
+async fn save_update(db: &DbService, update: ChannelUpdate) {
+    let span = update.span.clone();
+    if let Err(err) = save_post(db, update).instrument(span.clone()).await {
+        span.in_scope(|| log::error!("cannot save new post: {err:#}"));
+    }
+}
+
+async fn save_post(db: &DbService, update: ChannelUpdate) -> Result<()> {
+    if update.edited {
+        let ChannelUpdate {
+            chat_id,
+            message_id,
+            content,
+            has_media,
+            ..
+        } = update;
+        if !db
+            .edit_channel_post(chat_id, message_id, &content, has_media)
+            .await?
+        {
+            log::trace!("skipped an edit of an unknown post");
+        }
+        return Ok(());
+    }
+    let channel = match db.get_channel_by_id(update.chat_id).await? {
+        Some(channel) => channel,
+        None => {
+            log::trace!("skipped a post of an untracked chat");
+            return Ok(());
+        }
+    };
+    log::debug!("new post of {} chars", update.content.len());
+    let post = Post {
+        title: None,
+        link: feed::post_link(&channel.username, update.message_id),
+        telegram_id: update.message_id,
+        pub_date: update.date,
+        content: update.content,
+        chat_id: update.chat_id,
+        has_media: update.has_media,
+    };
+    db.save_channel_posts(&[post], true).await
+}
+
+pub fn init_client_updates_reader(
+    mut receiver: Receiver<Box<Update>>,
+    settings: &IngestionSettings,
+) -> TgUpdate {
+    let (sx, rx) = mpsc::channel(settings.updates_queue_size);
+    metrics::watch_channel("user_updates", &sx);
+    let send_timeout = settings.send_update_timeout();
 
     tokio::spawn(async move {
         while let Some(update) = receiver.recv().await {
+            metrics::UPDATES_RECEIVED.with_label_values(&["user"]).inc();
             let new_update = match update.as_ref() {
-                Update::MessageContent(content) => None,
+                // updateMessageEdited which follows it has no content
+                Update::MessageContent(edited) => {
+                    let span = tracing::info_span!(
+                        "channel_update",
+                        chat_id = edited.chat_id(),
+                        message_id = edited.message_id(),
+                        edited = true
+                    );
+                    let content = edited.new_content();
+                    span.in_scope(|| parsers::parse_message_content(content))
+                        .map(|text| ChannelUpdate {
+                            chat_id: edited.chat_id(),
+                            message_id: edited.message_id(),
+                            date: 0,
+                            content: text,
+                            has_media: parsers::has_media(content),
+                            edited: true,
+                            span,
+                        })
+                }
                 Update::NewMessage(new_message) => {
-                    parsers::parse_message_content(new_message.message().content())
+                    let message = new_message.message();
+                    let span = tracing::info_span!(
+                        "channel_update",
+                        chat_id = message.chat_id(),
+                        message_id = message.id()
+                    );
+                    span.in_scope(|| parsers::parse_message_content(message.content()))
+                        .map(|content| ChannelUpdate {
+                            chat_id: message.chat_id(),
+                            message_id: message.id(),
+                            date: message.date(),
+                            content,
+                            has_media: parsers::has_media(message.content()),
+                            edited: false,
+                            span,
+                        })
                 }
                 _ => None,
             };
             if let Some(new_update) = new_update {
-                if let Err(err) = sx
-                    .send_timeout(new_update.to_string(), SEND_UPDATE_TIMEOUT)
-                    .await
-                {
-                    log::error!("cannot send new update");
+                let span = new_update.span.clone();
+                if let Err(err) = sx.send_timeout(new_update, send_timeout).await {
+                    span.in_scope(|| log::error!("cannot send new update"));
                 }
             }
         }
@@ -167,3 +284,308 @@ fn is_channel(chat: &Chat) -> bool {
         ChatType::Supergroup(sg) => sg.is_channel(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed_cache::FeedCache;
+    use crate::models;
+    use crate::settings::{DbSettings, StreamSettings};
+    use crate::stream::Streams;
+    use crate::telegram::fake::FakeTdApi;
+    use axum::body::HttpBody;
+    use axum::response::sse::Sse;
+    use axum::response::IntoResponse;
+    use rust_tdlib::types::{
+        FormattedText, Message, MessagePhoto, MessageText, UpdateMessageContent,
+    };
+    use tokio::sync::broadcast;
+
+    const USER_ID: i64 = 100;
+    const CHAT_ID: i64 = 200;
+    const CHANNEL_ID: i64 = -1001;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The user client saving to `db`, along with the sender of TDLib updates to it
+    /// and the receiver of the channels with new or edited posts.
+    struct Harness {
+        db: DbService,
+        tdlib: Sender<Box<Update>>,
+        updates: broadcast::Sender<models::TelegramChatId>,
+        channel_updates: broadcast::Receiver<models::TelegramChatId>,
+        _shutdown: watch::Sender<bool>,
+    }
+
+    impl Harness {
+        /// A user is subscribed to the channel, which has a webhook as well.
+        async fn start() -> Self {
+            let (updates, channel_updates) = broadcast::channel(10);
+            let db = DbService::new(&DbSettings {
+                path: "sqlite::memory:".to_string(),
+                max_connections: 1,
+                run_migrations: true,
+            })
+            .await
+            .unwrap()
+            .with_feed_cache(FeedCache::new(0, Duration::ZERO), updates.clone());
+            db.save_user(models::NewUser {
+                user_id: USER_ID,
+                chat_id: CHAT_ID,
+                enabled: true,
+                reason: models::StateReason::Started,
+            })
+            .await
+            .unwrap();
+            let channel = NewChannel {
+                telegram_id: CHANNEL_ID,
+                title: "Rust".to_string(),
+                username: "rustlang".to_string(),
+            };
+            db.add_user_channels(USER_ID, &[channel], models::SubscriptionLimits::default())
+                .await
+                .unwrap();
+            db.save_webhook(
+                models::WebhookTarget::Channel(CHANNEL_ID),
+                "https://hooks.example.com",
+                "secret",
+            )
+            .await
+            .unwrap();
+
+            let (api, _sent) = FakeTdApi::new(USER_ID);
+            let (tdlib, receiver) = mpsc::channel(10);
+            let tg_update = init_client_updates_reader(receiver, &IngestionSettings::default());
+            let (_requests, from_service) = mpsc::channel(1);
+            let (to_service, _responses) = mpsc::channel(1);
+            let (shutdown, shutdown_recv) = watch::channel(false);
+            UserClient::new(api)
+                .start(
+                    db.clone(),
+                    tg_update,
+                    Arc::new(Mutex::new(from_service)),
+                    to_service,
+                    shutdown_recv,
+                )
+                .await
+                .unwrap();
+            Self {
+                db,
+                tdlib,
+                updates,
+                channel_updates,
+                _shutdown: shutdown,
+            }
+        }
+
+        async fn send(&self, update: Update) {
+            self.tdlib.send(Box::new(update)).await.unwrap();
+        }
+
+        /// Waits until a post of the channel is saved or edited.
+        async fn next_channel_update(&mut self) -> models::TelegramChatId {
+            tokio::time::timeout(TIMEOUT, self.channel_updates.recv())
+                .await
+                .expect("no post saved")
+                .unwrap()
+        }
+
+        /// Events of the webhook deliveries queued so far.
+        async fn webhook_events(&self) -> Vec<String> {
+            self.db
+                .get_due_webhook_deliveries(i64::MAX, 10)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|d| d.event)
+                .collect()
+        }
+    }
+
+    fn text(text: &str) -> MessageContent {
+        MessageContent::MessageText(
+            MessageText::builder()
+                .text(FormattedText::builder().text(text).build())
+                .build(),
+        )
+    }
+
+    fn post_update(chat_id: i64, message_id: i64, text: &str) -> Update {
+        let message = Message::builder()
+            .id(message_id)
+            .chat_id(chat_id)
+            .date(1_700_000_000)
+            .content(self::text(text))
+            .build();
+        Update::NewMessage(UpdateNewMessage::builder().message(message).build())
+    }
+
+    fn edit_update(chat_id: i64, message_id: i64, content: MessageContent) -> Update {
+        Update::MessageContent(
+            UpdateMessageContent::builder()
+                .chat_id(chat_id)
+                .message_id(message_id)
+                .new_content(content)
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn new_posts_of_tracked_channels_are_queued_for_delivery() {
+        let mut h = Harness::start().await;
+        h.send(post_update(CHANNEL_ID - 1, 1 << 20, "not tracked"))
+            .await;
+        h.send(post_update(CHANNEL_ID, 2 << 20, "a new release"))
+            .await;
+
+        assert_eq!(h.next_channel_update().await, CHANNEL_ID);
+        let deliveries = h.db.get_due_deliveries(0, i64::MAX).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].chat_id, CHAT_ID);
+        assert_eq!(deliveries[0].link, "https://t.me/rustlang/2");
+        assert_eq!(h.webhook_events().await, vec!["post.created"]);
+        assert!(h
+            .db
+            .get_channel_by_id(CHANNEL_ID - 1)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn edited_posts_are_updated_and_queued_for_webhooks() {
+        let mut h = Harness::start().await;
+        h.send(post_update(CHANNEL_ID, 2 << 20, "a new release"))
+            .await;
+        h.next_channel_update().await;
+        let photo = MessagePhoto::builder()
+            .caption(FormattedText::builder().text("an edited release").build())
+            .build();
+        // edits of posts which are not saved are skipped
+        h.send(edit_update(CHANNEL_ID, 3 << 20, text("unknown")))
+            .await;
+        h.send(edit_update(
+            CHANNEL_ID,
+            2 << 20,
+            MessageContent::MessagePhoto(photo),
+        ))
+        .await;
+
+        assert_eq!(h.next_channel_update().await, CHANNEL_ID);
+        let (_, posts) =
+            h.db.get_channel_posts("rustlang", models::PostsPage::Latest, 10)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].content, "an edited release");
+        assert!(posts[0].has_media);
+        assert_eq!(posts[0].pub_date, 1_700_000_000);
+        assert_eq!(
+            h.webhook_events().await,
+            vec!["post.created", "post.edited"]
+        );
+        // the subscribers are not sent the post again
+        assert_eq!(h.db.get_due_deliveries(0, i64::MAX).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn new_posts_are_streamed_as_they_are_saved() {
+        let h = Harness::start().await;
+        let streams = Streams::new(
+            h.db.clone(),
+            StreamSettings {
+                // only posts saved by this process show up in time
+                poll_interval_ms: 60_000,
+                ..StreamSettings::default()
+            },
+            "https://feeds.example.com".to_string(),
+            h.updates.clone(),
+        );
+        let stream = streams.open(vec![CHANNEL_ID], None).await.unwrap();
+        let mut body = Sse::new(stream).into_response().into_body();
+
+        h.send(post_update(CHANNEL_ID, 2 << 20, "a new release"))
+            .await;
+
+        let chunk = tokio::time::timeout(TIMEOUT, body.data())
+            .await
+            .expect("no post streamed")
+            .unwrap()
+            .unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(event.contains("event: post\n"), "{event}");
+        let data: serde_json::Value = event
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .unwrap();
+        assert_eq!(data["channel"]["username"], "rustlang");
+        assert_eq!(data["post"]["url"], "https://t.me/rustlang/2");
+        assert_eq!(data["post"]["content"], "a new release");
+    }
+
+    #[tokio::test]
+    async fn queued_posts_are_saved_on_shutdown() {
+        let h = Harness::start().await;
+        let (updates, tg_update) = mpsc::channel(10);
+        for id in 1..=3 {
+            let update = ChannelUpdate {
+                chat_id: CHANNEL_ID,
+                message_id: id << 20,
+                date: 1_700_000_000,
+                content: format!("post {id}"),
+                has_media: false,
+                edited: false,
+                span: Span::none(),
+            };
+            updates.send(update).await.unwrap();
+        }
+        let (shutdown, shutdown_recv) = watch::channel(false);
+        shutdown.send(true).unwrap();
+
+        let (api, _sent) = FakeTdApi::new(USER_ID);
+        let (_requests, from_service) = mpsc::channel(1);
+        let (to_service, _responses) = mpsc::channel(1);
+        let handle = UserClient::new(api)
+            .start(
+                h.db.clone(),
+                tg_update,
+                Arc::new(Mutex::new(from_service)),
+                to_service,
+                shutdown_recv,
+            )
+            .await
+            .unwrap();
+        tokio::time::timeout(TIMEOUT, handle)
+            .await
+            .expect("user client is not stopped")
+            .unwrap();
+        let (_, posts) =
+            h.db.get_channel_posts("rustlang", models::PostsPage::Latest, 10)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(posts.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn history_is_fetched_with_any_limit() {
+        let (api, _sent) = FakeTdApi::new(USER_ID);
+        let messages = (1..=3)
+            .map(|id| {
+                Message::builder()
+                    .id(id << 20)
+                    .chat_id(CHANNEL_ID)
+                    .date(1_700_000_000)
+                    .content(text(&format!("post {id}")))
+                    .build()
+            })
+            .collect();
+        api.add_history(CHANNEL_ID, messages);
+        let user = UserClient::new(api);
+        for limit in [1, 2, 100] {
+            let posts = user.get_channel_history(CHANNEL_ID, limit).await.unwrap();
+            assert_eq!(posts.len(), (limit as usize).min(3), "{limit}");
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use crate::db::DbService;
+use crate::settings::WebhookSettings;
+use crate::{feed, metrics, models, websub};
+use futures::StreamExt;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::{header, Url};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+/// Queued posts taken per poll, the rest waits for the next one.
+const BATCH_SIZE: i64 = 100;
+
+/// Length of the generated secrets payloads are signed with.
+pub const SECRET_LEN: usize = 32;
+
+/// Whether the URL can be a webhook, i.e. it is an absolute http(s) URL.
+pub fn is_valid_url(url: &str) -> bool {
+    Url::parse(url).map_or(false, |url| {
+        (url.scheme() == "http" || url.scheme() == "https") && url.has_host()
+    })
+}
+
+/// Whether the URL points neither to the host tgfeed runs on nor to its network by itself.
+/// Host names are checked once resolved, when posts are sent.
+pub fn is_public_url(url: &str) -> bool {
+    let host = match Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+    {
+        Some(host) => host,
+        None => return false,
+    };
+    // IPv6 addresses are in brackets
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public_ip(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.');
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    }
+}
+
+/// Whether the address is not a loopback, private, link-local or otherwise local one.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = first & 0xfe00 == 0xfc00;
+                let link_local = first & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+/// Client sending to public addresses only, redirects are not followed since they could
+/// lead anywhere. Addresses written in URLs are not resolved, check them with [`is_public_url`].
+pub fn public_client(timeout: Duration) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+}
+
+/// Resolves host names to public addresses only, so that a webhook cannot reach the host
+/// tgfeed runs on or its network, even by a name changed to resolve there after it is added.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+                let error = format!(
+                    "{} resolves to a private address {}",
+                    name.as_str(),
+                    addr.ip()
+                );
+                return Err(error.into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    /// `post.created` or `post.edited`.
+    event: &'a str,
+    /// Stays the same across retries, so receivers can skip duplicates.
+    delivery_id: i64,
+    channel: feed::JsonChannel<'a>,
+    post: feed::JsonPost<'a>,
+}
+
+fn payload(base_url: &str, delivery: &models::WebhookDelivery) -> String {
+    let channel = models::Channel {
+        id: delivery.channel_id,
+        title: delivery.channel_title.clone(),
+        username: delivery.channel.clone(),
+    };
+    let post = models::Post {
+        title: delivery.title.clone(),
+        link: delivery.link.clone(),
+        telegram_id: delivery.telegram_id,
+        pub_date: delivery.pub_date,
+        content: delivery.content.clone(),
+        chat_id: delivery.channel_id,
+        has_media: delivery.has_media,
+    };
+    let payload = Payload {
+        event: &delivery.event,
+        delivery_id: delivery.id,
+        channel: feed::JsonChannel::new(base_url, &channel),
+        post: feed::JsonPost::new(&channel, &post),
+    };
+    serde_json::to_string(&payload).expect("payload is serializable")
+}
+
+/// Sends posts queued for webhooks as signed JSON. Posts of a webhook may arrive out of order
+/// when some of them are retried. Failed posts are retried with a backoff and dropped after
+/// `max_attempts`, webhooks failing `disable_after_failures` times in a row are disabled.
+/// Webhooks of users are sent to public addresses only, those of channels are added by admins.
+pub struct Webhooks {
+    db: DbService,
+    client: reqwest::Client,
+    /// Resolves host names to public addresses only.
+    users_client: reqwest::Client,
+    /// Whether webhooks of users are checked to be public, tests send them to the loopback.
+    public_only: bool,
+    settings: WebhookSettings,
+    base_url: String,
+}
+
+impl Webhooks {
+    pub fn new(db: DbService, settings: WebhookSettings, base_url: String) -> anyhow::Result<Self> {
+        // a redirect could lead anywhere
+        let client = reqwest::Client::builder()
+            .timeout(settings.request_timeout())
+            .redirect(Policy::none())
+            .build()?;
+        let users_client = public_client(settings.request_timeout())?;
+        Ok(Self {
+            db,
+            client,
+            users_client,
+            public_only: true,
+            settings,
+            base_url,
+        })
+    }
+
+    /// Runs until aborted, posts being sent meanwhile stay queued and are sent again.
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut poll = tokio::time::interval(self.settings.poll_interval());
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                poll.tick().await;
+                self.dispatch().await;
+            }
+        })
+    }
+
+    async fn dispatch(&self) {
+        let due = match self
+            .db
+            .get_due_webhook_deliveries(unix_now(), BATCH_SIZE)
+            .await
+        {
+            Ok(due) => due,
+            Err(err) => {
+                log::error!("cannot get due webhook deliveries: {err:#}");
+                return;
+            }
+        };
+        futures::stream::iter(due)
+            .for_each_concurrent(self.settings.concurrency, |delivery| self.deliver(delivery))
+            .await;
+    }
+
+    async fn deliver(&self, delivery: models::WebhookDelivery) {
+        let result = match self.send(&delivery).await {
+            Ok(()) => self
+                .db
+                .complete_webhook_delivery(delivery.id, delivery.webhook_id)
+                .await
+                .map(|()| "ok"),
+            Err(error) => self.fail(&delivery, &error).await,
+        };
+        match result {
+            Ok(result) => metrics::WEBHOOK_DELIVERIES
+                .with_label_values(&[result])
+                .inc(),
+            Err(err) => log::error!("cannot record webhook delivery {}: {err:#}", delivery.id),
+        }
+    }
+
+    /// Returns the error to record if the post is not accepted.
+    async fn send(&self, delivery: &models::WebhookDelivery) -> Result<(), String> {
+        let client = match delivery.user_id {
+            Some(_) if self.public_only => {
+                // addresses in URLs are not resolved
+                if !is_public_url(&delivery.url) {
+                    return Err("private address".to_string());
+                }
+                &self.users_client
+            }
+            _ => &self.client,
+        };
+        let body = payload(&self.base_url, delivery);
+        let resp = client
+            .post(&delivery.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Tgfeed-Event", &delivery.event)
+            .header("X-Tgfeed-Delivery", delivery.id)
+            .header(
+                "X-Tgfeed-Signature",
+                websub::signature(&delivery.secret, body.as_bytes()),
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("status {}", resp.status()))
+        }
+    }
+
+    /// Schedules a retry or gives up on the post, disables the webhook if it keeps failing.
+    async fn fail(
+        &self,
+        delivery: &models::WebhookDelivery,
+        error: &str,
+    ) -> anyhow::Result<&'static str> {
+        let attempts = delivery.attempts + 1;
+        let next_attempt_at = (attempts < self.settings.max_attempts)
+            .then(|| unix_now() + self.settings.retry_delay(attempts).as_secs() as i64);
+        log::info!(
+            "webhook {} failed on delivery {} (attempt {attempts}): {error}",
+            delivery.webhook_id,
+            delivery.id
+        );
+        let failures = self
+            .db
+            .fail_webhook_delivery(delivery.id, delivery.webhook_id, next_attempt_at, error)
+            .await?;
+        if failures >= self.settings.disable_after_failures {
+            log::warn!(
+                "webhook {} is disabled after {failures} failures in a row",
+                delivery.webhook_id
+            );
+            self.db.disable_webhook(delivery.webhook_id).await?;
+            return Ok("disabled");
+        }
+        Ok(if next_attempt_at.is_some() {
+            "retried"
+        } else {
+            "dropped"
+        })
+    }
+}
+
+fn unix_now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DbSettings;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::{Arc, Mutex};
+
+    const CHANNEL_ID: i64 = -1001;
+
+    /// Payloads received by an endpoint along with their signatures, which answers
+    /// with the given statuses in turn and with 200 after them.
+    #[derive(Clone, Default)]
+    struct Endpoint {
+        received: Arc<Mutex<Vec<(String, String)>>>,
+        statuses: Arc<Mutex<Vec<StatusCode>>>,
+    }
+
+    async fn receive(
+        State(endpoint): State<Endpoint>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let signature = headers["X-Tgfeed-Signature"].to_str().unwrap().to_string();
+        endpoint.received.lock().unwrap().push((signature, body));
+        endpoint
+            .statuses
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or(StatusCode::OK)
+    }
+
+    fn endpoint(statuses: Vec<StatusCode>) -> (String, Endpoint) {
+        let endpoint = Endpoint {
+            statuses: Arc::new(Mutex::new(statuses)),
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(endpoint.clone());
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, endpoint)
+    }
+
+    async fn webhooks(settings: WebhookSettings) -> Webhooks {
+        let db = DbService::new(&DbSettings {
+            path: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            run_migrations: true,
+        })
+        .await
+        .unwrap();
+        db.save_user(models::NewUser {
+            user_id: 1,
+            chat_id: 1,
+            enabled: true,
+            reason: models::StateReason::Started,
+        })
+        .await
+        .unwrap();
+        let channel = models::NewChannel {
+            telegram_id: CHANNEL_ID,
+            title: "Rust".to_string(),
+            username: "rustlang".to_string(),
+        };
+        db.add_user_channels(1, &[channel], models::SubscriptionLimits::default())
+            .await
+            .unwrap();
+        let mut webhooks =
+            Webhooks::new(db, settings, "https://feeds.example.com".to_string()).unwrap();
+        // the endpoints are served on the loopback
+        webhooks.public_only = false;
+        webhooks
+    }
+
+    async fn save_post(db: &DbService, telegram_id: i64) {
+        let post = models::Post {
+            title: Some("Release".to_string()),
+            link: "".to_string(),
+            telegram_id: telegram_id << 20,
+            pub_date: 1_700_000_000,
+            content: "a <b>new</b> release".to_string(),
+            chat_id: CHANNEL_ID,
+            has_media: true,
+        };
+        db.save_channel_posts(&[post], true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn posts_are_sent_signed_to_users_and_channels_webhooks() {
+        let w = webhooks(WebhookSettings::default()).await;
+        let (url, endpoint) = endpoint(vec![]);
+        w.db.save_webhook(models::WebhookTarget::User(1), &url, "user secret")
+            .await
+            .unwrap();
+        w.db.save_webhook(
+            models::WebhookTarget::Channel(CHANNEL_ID),
+            &url,
+            "channel secret",
+        )
+        .await
+        .unwrap();
+
+        save_post(&w.db, 7).await;
+        w.dispatch().await;
+        let received = endpoint.received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        for (signature, body) in received.iter() {
+            let valid = [
+                websub::signature("user secret", body.as_bytes()),
+                websub::signature("channel secret", body.as_bytes()),
+            ];
+            assert!(valid.contains(signature));
+            let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(payload["event"], "post.created");
+            assert_eq!(payload["channel"]["username"], "rustlang");
+            assert_eq!(
+                payload["channel"]["feed_url"],
+                "https://feeds.example.com/rustlang"
+            );
+            assert_eq!(payload["post"]["id"], 7);
+            assert_eq!(payload["post"]["url"], "https://t.me/rustlang/7");
+            assert_eq!(payload["post"]["content"], "a <b>new</b> release");
+            assert_eq!(
+                payload["post"]["media"],
+                serde_json::json!(["https://t.me/rustlang/7?single"])
+            );
+        }
+        assert!(w
+            .db
+            .get_due_webhook_deliveries(unix_now(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn edited_posts_are_sent_as_edited() {
+        // one at a time, so that they arrive in order
+        let settings = WebhookSettings {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let w = webhooks(settings).await;
+        let (url, endpoint) = endpoint(vec![]);
+        w.db.save_webhook(models::WebhookTarget::User(1), &url, "secret")
+            .await
+            .unwrap();
+        save_post(&w.db, 7).await;
+        assert!(w
+            .db
+            .edit_channel_post(CHANNEL_ID, 7 << 20, "an edited release", false)
+            .await
+            .unwrap());
+        assert!(!w
+            .db
+            .edit_channel_post(CHANNEL_ID, 8 << 20, "a missing post", false)
+            .await
+            .unwrap());
+
+        w.dispatch().await;
+        let received = endpoint.received.lock().unwrap().clone();
+        let events: Vec<_> = received
+            .iter()
+            .map(|(_, body)| {
+                let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+                (
+                    payload["event"].as_str().unwrap().to_string(),
+                    payload["post"]["content"].as_str().unwrap().to_string(),
+                    payload["post"]["media"].as_array().unwrap().len(),
+                )
+            })
+            .collect();
+        // the content is read when the post is sent, so both have the edited one
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "post.created".to_string(),
+                    "an edited release".to_string(),
+                    0
+                ),
+                (
+                    "post.edited".to_string(),
+                    "an edited release".to_string(),
+                    0
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_posts_are_retried_and_failing_webhooks_disabled() {
+        let settings = WebhookSettings {
+            max_attempts: 2,
+            retry_base_secs: 0,
+            disable_after_failures: 3,
+            ..Default::default()
+        };
+        let w = webhooks(settings).await;
+        let failing = vec![StatusCode::INTERNAL_SERVER_ERROR; 3];
+        let (url, endpoint) = endpoint(failing);
+        w.db.save_webhook(models::WebhookTarget::User(1), &url, "secret")
+            .await
+            .unwrap();
+
+        save_post(&w.db, 1).await;
+        w.dispatch().await;
+        let due =
+            w.db.get_due_webhook_deliveries(unix_now(), 10)
+                .await
+                .unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+        // the second attempt is the last one
+        w.dispatch().await;
+        assert!(w
+            .db
+            .get_due_webhook_deliveries(unix_now(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        save_post(&w.db, 2).await;
+        w.dispatch().await;
+        let webhook = w.db.get_webhooks(None).await.unwrap().pop().unwrap();
+        assert!(!webhook.enabled);
+        assert_eq!(webhook.failures, 3);
+        assert_eq!(
+            webhook.last_error.as_deref(),
+            Some("status 500 Internal Server Error")
+        );
+        assert_eq!(endpoint.received.lock().unwrap().len(), 3);
+        // posts are not queued for disabled webhooks
+        save_post(&w.db, 3).await;
+        assert!(w
+            .db
+            .get_due_webhook_deliveries(unix_now(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // adding it again enables it
+        let webhook =
+            w.db.save_webhook(models::WebhookTarget::User(1), &url, "secret")
+                .await
+                .unwrap();
+        assert!(webhook.enabled);
+        assert_eq!(webhook.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn webhooks_of_users_are_sent_to_public_addresses_only() {
+        let mut w = webhooks(WebhookSettings::default()).await;
+        w.public_only = true;
+        let (url, endpoint) = endpoint(vec![]);
+        let local_name = url.replace("127.0.0.1", "localhost");
+        for url in [&url, &local_name] {
+            w.db.save_webhook(models::WebhookTarget::User(1), url, "secret")
+                .await
+                .unwrap();
+        }
+        w.db.save_webhook(models::WebhookTarget::Channel(CHANNEL_ID), &url, "secret")
+            .await
+            .unwrap();
+
+        save_post(&w.db, 1).await;
+        w.dispatch().await;
+        assert_eq!(endpoint.received.lock().unwrap().len(), 1);
+        let due = w.db.get_due_webhook_deliveries(i64::MAX, 10).await.unwrap();
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|d| d.user_id == Some(1)));
+    }
+
+    #[tokio::test]
+    async fn redirects_are_not_followed() {
+        let w = webhooks(WebhookSettings::default()).await;
+        let (target, endpoint) = endpoint(vec![]);
+        let app = Router::new().route(
+            "/hook",
+            post(move || async move { axum::response::Redirect::temporary(&target) }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        w.db.save_webhook(models::WebhookTarget::User(1), &url, "secret")
+            .await
+            .unwrap();
+
+        save_post(&w.db, 1).await;
+        w.dispatch().await;
+        assert!(endpoint.received.lock().unwrap().is_empty());
+        let webhook = w.db.get_webhooks(None).await.unwrap().pop().unwrap();
+        assert_eq!(
+            webhook.last_error.as_deref(),
+            Some("status 307 Temporary Redirect")
+        );
+    }
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        assert!(is_public_url("https://example.com/hook"));
+        assert!(is_public_url("http://93.184.216.34/hook"));
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://api.localhost./hook",
+            "http://10.0.0.1/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(!is_public_url(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn only_http_urls_are_webhooks() {
+        assert!(is_valid_url("https://example.com/hook"));
+        assert!(is_valid_url("http://127.0.0.1:8080/hook?token=1"));
+        assert!(!is_valid_url("example.com/hook"));
+        assert!(!is_valid_url("ftp://example.com/hook"));
+        assert!(!is_valid_url("mailto:admin@example.com"));
+    }
+}
@@ -0,0 +1,612 @@
+use crate::db::DbService;
+use crate::models::PostsPage;
+use crate::settings::{FeedsSettings, WebSubSettings};
+use crate::telegram::{RateLimit, RateLimiter};
+use crate::{http, metrics, models, opml, webhooks};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use rand::distributions::{Alphanumeric, DistString};
+use reqwest::{header, StatusCode, Url};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinHandle;
+
+/// How often expired subscriptions are removed, they are not pushed to meanwhile anyway.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Subscribers must send shorter secrets (WebSub, section 5.1).
+const MAX_SECRET_LEN: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, strum::IntoStaticStr)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Mode {
+    Subscribe,
+    Unsubscribe,
+}
+
+/// A form encoded (un)subscription request of a subscriber (WebSub, section 5.1).
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubscriptionRequest {
+    #[serde(rename = "hub.mode")]
+    pub mode: Mode,
+    /// URL of the channel feed.
+    #[serde(rename = "hub.topic")]
+    pub topic: String,
+    #[serde(rename = "hub.callback")]
+    pub callback: String,
+    #[serde(rename = "hub.lease_seconds")]
+    pub lease_seconds: Option<u64>,
+    /// Pushed feeds are signed with it if set.
+    #[serde(rename = "hub.secret")]
+    pub secret: Option<String>,
+}
+
+/// The WebSub hub of the channel feeds: verifies the intent of subscribers and pushes
+/// the latest page of the channel feed to them on new posts of the channel. Failed pushes
+/// are not retried, subscribers get the posts with the next push. Anyone may subscribe,
+/// so callbacks are requested at public addresses only.
+#[derive(Clone)]
+pub struct Hub {
+    db: DbService,
+    client: reqwest::Client,
+    /// Whether callbacks are checked to be public, tests serve them on the loopback.
+    public_only: bool,
+    /// Permits of verifications in progress.
+    verifications: Arc<Semaphore>,
+    /// Requests by the host of the callback.
+    requests: Arc<Mutex<RateLimiter<String>>>,
+    settings: WebSubSettings,
+    feeds: FeedsSettings,
+}
+
+impl Hub {
+    pub fn new(
+        db: DbService,
+        settings: WebSubSettings,
+        feeds: FeedsSettings,
+    ) -> anyhow::Result<Self> {
+        let client = webhooks::public_client(settings.request_timeout())?;
+        Ok(Self {
+            db,
+            client,
+            public_only: true,
+            verifications: Arc::new(Semaphore::new(settings.max_verifications)),
+            requests: Arc::new(Mutex::new(RateLimiter::new(
+                settings.requests_per_host_per_minute,
+            ))),
+            settings,
+            feeds,
+        })
+    }
+
+    /// Validates the request and verifies it with the subscriber in the background.
+    /// Returns the reason if the request is invalid.
+    pub fn accept(&self, request: SubscriptionRequest) -> Result<(), &'static str> {
+        let channel_name = self
+            .topic_channel(&request.topic)
+            .ok_or("hub.topic must be a channel feed served by this hub")?
+            .to_string();
+        let host = match Url::parse(&request.callback) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url.host_str(),
+            _ => None,
+        }
+        .ok_or("hub.callback must be an absolute http(s) url")?
+        .to_lowercase();
+        if self.public_only && !webhooks::is_public_url(&request.callback) {
+            return Err("hub.callback must not be a private address");
+        }
+        if request
+            .secret
+            .as_ref()
+            .map_or(false, |s| s.len() >= MAX_SECRET_LEN)
+        {
+            return Err("hub.secret must be shorter than 200 bytes");
+        }
+        if self.requests.lock().unwrap().check(host) != RateLimit::Allowed {
+            return Err("too many requests for the hub.callback host, retry later");
+        }
+        let permit = self
+            .verifications
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| "too many requests being verified, retry later")?;
+        let hub = self.clone();
+        tokio::spawn(async move {
+            hub.verify(request, &channel_name).await;
+            drop(permit);
+        });
+        Ok(())
+    }
+
+    /// Pushes channel feeds as `updates` announce new posts, runs until they are closed.
+    pub fn start(self, mut updates: broadcast::Receiver<models::TelegramChatId>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = cleanup.tick() => self.remove_expired().await,
+                    update = updates.recv() => match update {
+                        // a slow subscriber of one channel does not hold up the others
+                        Ok(channel_id) => {
+                            let hub = self.clone();
+                            tokio::spawn(async move { hub.publish(channel_id).await });
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("websub hub skipped {skipped} channel updates")
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                }
+            }
+            log::info!("websub hub stopped");
+        })
+    }
+
+    /// Name of the channel whose feed is the topic, `None` if it is not a channel feed.
+    fn topic_channel<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let name = topic.strip_prefix(&opml::feed_url(&self.feeds.base_url, ""))?;
+        let valid = !name.is_empty() && !name.contains(['/', '?', '#']);
+        valid.then_some(name)
+    }
+
+    /// Verifies the intent of the subscriber (WebSub, section 5.3): the callback must echo
+    /// the challenge. Subscribers to unknown channels are told the request is denied.
+    async fn verify(&self, request: SubscriptionRequest, channel_name: &str) {
+        let SubscriptionRequest {
+            mode,
+            topic,
+            callback,
+            lease_seconds,
+            secret,
+        } = request;
+        let channel = match self.db.get_channel(channel_name).await {
+            Ok(Some(channel)) => channel,
+            Ok(None) => {
+                let query = [
+                    ("hub.mode", "denied"),
+                    ("hub.topic", topic.as_str()),
+                    ("hub.reason", "no such channel"),
+                ];
+                if let Err(err) = self.client.get(&callback).query(&query).send().await {
+                    log::info!("cannot deny {callback} subscription to {topic}: {err}");
+                }
+                return;
+            }
+            Err(err) => {
+                log::error!("cannot get channel {channel_name}: {err:#}");
+                return;
+            }
+        };
+
+        let mode_name: &str = mode.into();
+        let challenge = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let lease = self.settings.lease_secs(lease_seconds);
+        let mut query = vec![
+            ("hub.mode", mode_name.to_string()),
+            ("hub.topic", topic.clone()),
+            ("hub.challenge", challenge.clone()),
+        ];
+        if mode == Mode::Subscribe {
+            query.push(("hub.lease_seconds", lease.to_string()));
+        }
+        let confirmed = match self.client.get(&callback).query(&query).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                resp.text().await.map_or(false, |body| body == challenge)
+            }
+            Ok(resp) => {
+                log::info!(
+                    "{callback} refused to {mode_name} to {topic}: {}",
+                    resp.status()
+                );
+                false
+            }
+            Err(err) => {
+                log::info!("cannot verify {callback} subscription to {topic}: {err}");
+                false
+            }
+        };
+        if !confirmed {
+            return;
+        }
+
+        let result = match mode {
+            Mode::Subscribe => {
+                let secret = secret.as_deref().filter(|s| !s.is_empty());
+                self.db
+                    .save_websub_subscription(
+                        channel.id,
+                        &callback,
+                        secret,
+                        unix_now() + lease as i64,
+                    )
+                    .await
+            }
+            Mode::Unsubscribe => self
+                .db
+                .remove_websub_subscription(channel.id, &callback)
+                .await
+                .map(|_| ()),
+        };
+        match result {
+            Ok(()) => log::info!("{callback} {mode_name}d to {topic}"),
+            Err(err) => log::error!("cannot {mode_name} {callback} to {topic}: {err:#}"),
+        }
+    }
+
+    /// Pushes the latest page of the channel feed to its subscribers (WebSub, section 7).
+    async fn publish(&self, channel_id: models::TelegramChatId) {
+        let subscriptions = match self
+            .db
+            .get_websub_subscriptions(channel_id, unix_now())
+            .await
+        {
+            Ok(subscriptions) => subscriptions,
+            Err(err) => {
+                log::error!("cannot get websub subscriptions of {channel_id}: {err:#}");
+                return;
+            }
+        };
+        let channel_name = match subscriptions.first() {
+            Some(subscription) => subscription.channel.clone(),
+            None => return,
+        };
+        let feed = match http::render_channel_feed(
+            &self.db,
+            &self.feeds,
+            true,
+            &channel_name,
+            PostsPage::Latest,
+            None,
+        )
+        .await
+        {
+            Ok(Some(feed)) => feed,
+            Ok(None) => return,
+            Err(err) => {
+                log::error!("cannot render feed of {channel_name}: {err:#}");
+                return;
+            }
+        };
+        let topic = opml::feed_url(&self.feeds.base_url, &channel_name);
+        let links = format!(
+            r#"<{}>; rel="hub", <{topic}>; rel="self""#,
+            opml::websub_hub_url(&self.feeds.base_url)
+        );
+        futures::stream::iter(subscriptions)
+            .for_each_concurrent(self.settings.push_concurrency, |subscription| {
+                self.push(channel_id, subscription, &links, &feed.body)
+            })
+            .await;
+    }
+
+    async fn push(
+        &self,
+        channel_id: models::TelegramChatId,
+        subscription: models::WebSubSubscription,
+        links: &str,
+        body: &str,
+    ) {
+        let callback = subscription.callback;
+        let mut req = self
+            .client
+            .post(&callback)
+            .header(header::CONTENT_TYPE, "application/rss+xml")
+            .header(header::LINK, links);
+        if let Some(secret) = &subscription.secret {
+            req = req.header("X-Hub-Signature", signature(secret, body.as_bytes()));
+        }
+        let result = match req.body(body.to_string()).send().await {
+            Ok(resp) if resp.status().is_success() => "ok",
+            // the subscriber is gone for good (WebSub, section 7)
+            Ok(resp) if resp.status() == StatusCode::GONE => {
+                if let Err(err) = self
+                    .db
+                    .remove_websub_subscription(channel_id, &callback)
+                    .await
+                {
+                    log::error!("cannot remove websub subscription of {callback}: {err:#}");
+                }
+                "gone"
+            }
+            Ok(resp) => {
+                log::info!(
+                    "{callback} refused the feed of {channel_id}: {}",
+                    resp.status()
+                );
+                "failed"
+            }
+            Err(err) => {
+                log::info!("cannot push the feed of {channel_id} to {callback}: {err}");
+                "failed"
+            }
+        };
+        metrics::WEBSUB_PUSHES.with_label_values(&[result]).inc();
+    }
+
+    async fn remove_expired(&self) {
+        match self
+            .db
+            .remove_expired_websub_subscriptions(unix_now())
+            .await
+        {
+            Ok(0) => {}
+            Ok(removed) => log::info!("{removed} websub subscriptions expired"),
+            Err(err) => log::error!("cannot remove expired websub subscriptions: {err:#}"),
+        }
+    }
+}
+
+/// `X-Hub-Signature` of the pushed content (WebSub, section 8), webhook payloads are
+/// signed the same way.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn unix_now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed_cache::FeedCache;
+    use crate::settings::DbSettings;
+    use axum::extract::{Query, State};
+    use axum::http::HeaderMap;
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const BASE_URL: &str = "https://feeds.example.com";
+
+    /// Requests received by a subscriber: query of verification ones, signature and body
+    /// of pushed ones.
+    #[derive(Clone, Default)]
+    struct Subscriber {
+        verified: Arc<Mutex<Vec<HashMap<String, String>>>>,
+        pushed: Arc<Mutex<Vec<(Option<String>, String)>>>,
+    }
+
+    async fn confirm(
+        State(subscriber): State<Subscriber>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> String {
+        let challenge = query.get("hub.challenge").cloned().unwrap_or_default();
+        subscriber.verified.lock().unwrap().push(query);
+        challenge
+    }
+
+    async fn receive(State(subscriber): State<Subscriber>, headers: HeaderMap, body: String) {
+        let signature = headers
+            .get("X-Hub-Signature")
+            .map(|v| v.to_str().unwrap().to_string());
+        subscriber.pushed.lock().unwrap().push((signature, body));
+    }
+
+    /// Serves a subscriber confirming every request, returns its callback.
+    fn subscriber() -> (String, Subscriber) {
+        let subscriber = Subscriber::default();
+        let app = Router::new()
+            .route("/callback", get(confirm).post(receive))
+            .with_state(subscriber.clone());
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let callback = format!("http://{}/callback", server.local_addr());
+        tokio::spawn(server);
+        (callback, subscriber)
+    }
+
+    async fn hub() -> (Hub, broadcast::Sender<models::TelegramChatId>) {
+        hub_with(WebSubSettings::default()).await
+    }
+
+    async fn hub_with(
+        settings: WebSubSettings,
+    ) -> (Hub, broadcast::Sender<models::TelegramChatId>) {
+        let (updates, _) = broadcast::channel(10);
+        let db = DbService::new(&DbSettings {
+            path: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            run_migrations: true,
+        })
+        .await
+        .unwrap()
+        .with_feed_cache(FeedCache::new(0, Duration::ZERO), updates.clone());
+        let feeds = FeedsSettings {
+            base_url: BASE_URL.to_string(),
+            default_items: 2,
+            max_items: 3,
+        };
+        let mut hub = Hub::new(db, settings, feeds).unwrap();
+        // the subscribers are served on the loopback
+        hub.public_only = false;
+        (hub, updates)
+    }
+
+    async fn save_post(db: &DbService, telegram_id: i64) {
+        db.save_user(models::NewUser {
+            user_id: 1,
+            chat_id: 1,
+            enabled: true,
+            reason: models::StateReason::Started,
+        })
+        .await
+        .unwrap();
+        let channel = models::NewChannel {
+            telegram_id: -1001,
+            title: "Rust".to_string(),
+            username: "rustlang".to_string(),
+        };
+        db.add_user_channels(1, &[channel], models::SubscriptionLimits::default())
+            .await
+            .unwrap();
+        db.save_channel_posts(
+            &[models::Post {
+                title: Some("Release".to_string()),
+                link: "".to_string(),
+                telegram_id: telegram_id << 20,
+                pub_date: 1_700_000_000,
+                content: "a new release".to_string(),
+                chat_id: -1001,
+                has_media: false,
+            }],
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn request(mode: Mode, topic: &str, callback: &str) -> SubscriptionRequest {
+        SubscriptionRequest {
+            mode,
+            topic: topic.to_string(),
+            callback: callback.to_string(),
+            lease_seconds: Some(3600),
+            secret: Some("secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_rejected() {
+        let (hub, _) = hub().await;
+        let topic = format!("{BASE_URL}/rustlang");
+        // nothing listens there, the verification fails
+        let callback = "http://127.0.0.1:9/callback";
+        assert!(hub
+            .accept(request(Mode::Subscribe, &topic, callback))
+            .is_ok());
+        for topic in [
+            "https://other.example.com/rustlang".to_string(),
+            format!("{BASE_URL}/"),
+            format!("{BASE_URL}/rustlang/archive"),
+            format!("{BASE_URL}/rustlang?limit=10"),
+        ] {
+            assert!(hub
+                .accept(request(Mode::Subscribe, &topic, callback))
+                .is_err());
+        }
+        for callback in ["/callback", "ftp://subscriber.example.com/callback"] {
+            assert!(hub
+                .accept(request(Mode::Subscribe, &topic, callback))
+                .is_err());
+        }
+        let mut long_secret = request(Mode::Subscribe, &topic, callback);
+        long_secret.secret = Some("s".repeat(200));
+        assert!(hub.accept(long_secret).is_err());
+    }
+
+    #[tokio::test]
+    async fn callbacks_are_limited() {
+        let settings = WebSubSettings {
+            max_verifications: 1,
+            requests_per_host_per_minute: 2,
+            ..Default::default()
+        };
+        let (mut hub, _) = hub_with(settings).await;
+        let topic = format!("{BASE_URL}/rustlang");
+        hub.public_only = true;
+        for callback in [
+            "http://127.0.0.1:8080/callback",
+            "http://localhost/callback",
+            "http://10.0.0.1/callback",
+        ] {
+            assert_eq!(
+                hub.accept(request(Mode::Subscribe, &topic, callback)),
+                Err("hub.callback must not be a private address")
+            );
+        }
+
+        hub.public_only = false;
+        // nothing answers there, so the verification stays in progress
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent = format!("http://{}/callback", listener.local_addr().unwrap());
+        assert!(hub
+            .accept(request(Mode::Subscribe, &topic, &silent))
+            .is_ok());
+        let busy = Err("too many requests being verified, retry later");
+        assert_eq!(
+            hub.accept(request(
+                Mode::Subscribe,
+                &topic,
+                "http://localhost:9/callback"
+            )),
+            busy
+        );
+        assert_eq!(hub.accept(request(Mode::Subscribe, &topic, &silent)), busy);
+        assert_eq!(
+            hub.accept(request(Mode::Subscribe, &topic, &silent)),
+            Err("too many requests for the hub.callback host, retry later")
+        );
+    }
+
+    #[tokio::test]
+    async fn verified_subscribers_get_new_posts() {
+        let (hub, updates) = hub().await;
+        let db = hub.db.clone();
+        save_post(&db, 1).await;
+        let (callback, subscriber) = subscriber();
+        let topic = format!("{BASE_URL}/rustlang");
+
+        hub.verify(request(Mode::Subscribe, &topic, &callback), "rustlang")
+            .await;
+        let query = subscriber.verified.lock().unwrap().pop().unwrap();
+        assert_eq!(query["hub.mode"], "subscribe");
+        assert_eq!(query["hub.topic"], topic);
+        assert_eq!(query["hub.lease_seconds"], "3600");
+        let subscriptions = db
+            .get_websub_subscriptions(-1001, unix_now())
+            .await
+            .unwrap();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].callback, callback);
+
+        let task = hub.clone().start(updates.subscribe());
+        save_post(&db, 2).await;
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(pushed) = subscriber.pushed.lock().unwrap().pop() {
+                    return pushed;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        task.abort();
+        assert!(body.contains("https://t.me/rustlang/2"));
+        assert!(body.contains(r#"rel="hub""#));
+        assert!(body.contains(&format!(r#"href="{BASE_URL}/websub""#)));
+        assert_eq!(signature, Some(signature("secret", body.as_bytes())));
+
+        hub.verify(request(Mode::Unsubscribe, &topic, &callback), "rustlang")
+            .await;
+        let query = subscriber.verified.lock().unwrap().pop().unwrap();
+        assert_eq!(query["hub.mode"], "unsubscribe");
+        assert!(db
+            .get_websub_subscriptions(-1001, unix_now())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscriptions_to_unknown_channels_are_denied() {
+        let (hub, _) = hub().await;
+        let (callback, subscriber) = subscriber();
+        let topic = format!("{BASE_URL}/missing");
+        hub.verify(request(Mode::Subscribe, &topic, &callback), "missing")
+            .await;
+        let query = subscriber.verified.lock().unwrap().pop().unwrap();
+        assert_eq!(query["hub.mode"], "denied");
+        assert!(!query.contains_key("hub.challenge"));
+    }
+}